@@ -0,0 +1,207 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, LitInt};
+
+// Documentation:
+// #[derive(BitPack)] writes the pack and unpack functions for a struct whose fields each sit
+// in a fixed place in a word. Every field is marked with #[bits(width = .., lsb = ..)], plus
+// `signed` for two's complement fields, and the struct may be marked #[bitpack(word_bits = ..)]
+// when the word is narrower than 64 bits. The generated code calls bitpack::bitpack, so the
+// crate using the derive must depend on bitpack.
+//
+//     #[derive(BitPack)]
+//     #[bitpack(word_bits = 32)]
+//     struct Word {
+//         #[bits(width = 9, lsb = 23)]
+//         a: u64,
+//         #[bits(width = 5, lsb = 18, signed)]
+//         b: i64,
+//     }
+//
+// gives `Word::to_word(&self) -> Option<u64>`, which is None iff a value does not fit in its
+// field, and `Word::from_word(word: u64) -> Word`. Fields are converted with `as`, so any
+// numeric field type works.
+
+/// Where one field of the struct sits in the word
+#[derive(Clone, Debug, PartialEq)]
+struct FieldBits {
+    name: String,
+    width: u64,
+    lsb: u64,
+    signed: bool,
+}
+
+#[proc_macro_derive(BitPack, attributes(bits, bitpack))]
+pub fn derive_bitpack(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(&input.ident, "BitPack needs a struct with named fields")),
+        },
+        _ => return Err(Error::new_spanned(&input.ident, "BitPack can only be derived for structs")),
+    };
+
+    let word_bits = word_bits(input)?;
+    let mut layout = Vec::new();
+    let mut idents = Vec::new();
+    let mut types = Vec::new();
+    for field in fields {
+        let ident = field.ident.clone().expect("named fields have names");
+        let attr = field.attrs.iter().find(|attr| attr.path().is_ident("bits"))
+            .ok_or_else(|| Error::new_spanned(&ident, "every field needs a #[bits(width = .., lsb = ..)] attribute"))?;
+        let bits = field_bits(&ident, attr)?;
+        check_field(&bits, &layout, word_bits).map_err(|reason| Error::new_spanned(&ident, reason))?;
+        layout.push(bits);
+        idents.push(ident);
+        types.push(field.ty.clone());
+    }
+
+    let pack = layout.iter().zip(&idents).map(|(bits, ident)| {
+        let (width, lsb) = (bits.width, bits.lsb);
+        if bits.signed {
+            quote! { let word = ::bitpack::bitpack::news(word, #width, #lsb, self.#ident as i64)?; }
+        } else {
+            quote! { let word = ::bitpack::bitpack::newu(word, #width, #lsb, self.#ident as u64)?; }
+        }
+    });
+    let unpack = layout.iter().zip(&idents).zip(&types).map(|((bits, ident), ty)| {
+        let (width, lsb) = (bits.width, bits.lsb);
+        if bits.signed {
+            quote! { #ident: ::bitpack::bitpack::gets(word, #width, #lsb) as #ty }
+        } else {
+            quote! { #ident: ::bitpack::bitpack::getu(word, #width, #lsb) as #ty }
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Packs every field into a word, or returns None if a value does not fit in its field
+            pub fn to_word(&self) -> Option<u64> {
+                let word = 0_u64;
+                #(#pack)*
+                Some(word)
+            }
+
+            /// Unpacks every field from a word made by `to_word`
+            pub fn from_word(word: u64) -> Self {
+                #name { #(#unpack),* }
+            }
+        }
+    })
+}
+
+/// Reads `word_bits` from a #[bitpack(..)] attribute on the struct, defaulting to 64
+fn word_bits(input: &DeriveInput) -> Result<u64, Error> {
+    let mut word_bits = 64;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("bitpack")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("word_bits") {
+                word_bits = meta.value()?.parse::<LitInt>()?.base10_parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("expected `word_bits`"))
+            }
+        })?;
+    }
+    if word_bits == 0 || word_bits > 64 {
+        return Err(Error::new(Span::call_site(), format!("a {} bit word is not 1 to 64 bits", word_bits)));
+    }
+    Ok(word_bits)
+}
+
+/// Reads the width, lsb and signedness out of a field's #[bits(..)] attribute
+fn field_bits(ident: &Ident, attr: &syn::Attribute) -> Result<FieldBits, Error> {
+    let (mut width, mut lsb, mut signed) = (None, None, false);
+    attr.parse_nested_meta(|meta| {
+        if meta.path.is_ident("width") {
+            width = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+        } else if meta.path.is_ident("lsb") {
+            lsb = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+        } else if meta.path.is_ident("signed") {
+            signed = true;
+        } else {
+            return Err(meta.error("expected `width`, `lsb` or `signed`"));
+        }
+        Ok(())
+    })?;
+    match (width, lsb) {
+        (Some(width), Some(lsb)) => Ok(FieldBits { name: ident.to_string(), width, lsb, signed }),
+        _ => Err(Error::new_spanned(attr, "#[bits] needs both `width` and `lsb`")),
+    }
+}
+
+/// Checks that `field` is non-empty, fits in a `word_bits` word and shares no bits with the
+/// fields declared before it
+fn check_field(field: &FieldBits, earlier: &[FieldBits], word_bits: u64) -> Result<(), String> {
+    if field.width == 0 {
+        return Err(format!("field `{}` has no bits", field.name));
+    }
+    if field.width > word_bits || field.lsb > word_bits - field.width {
+        return Err(format!("field `{}` ({} bits at {}) does not fit in a {} bit word", field.name, field.width, field.lsb, word_bits));
+    }
+    for other in earlier {
+        if field.lsb < other.lsb + other.width && other.lsb < field.lsb + field.width {
+            return Err(format!("field `{}` overlaps field `{}`", field.name, other.name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(name: &str, width: u64, lsb: u64) -> FieldBits {
+        FieldBits { name: name.to_string(), width, lsb, signed: false }
+    }
+
+    #[test]
+    fn check_field_test() {
+        let earlier = [bits("a", 9, 23), bits("b", 5, 18)];
+        assert_eq!(check_field(&bits("c", 5, 13), &earlier, 32), Ok(()));
+        assert_eq!(check_field(&bits("c", 64, 0), &[], 64), Ok(()));
+        assert!(check_field(&bits("c", 5, 17), &earlier, 32).unwrap_err().contains("overlaps field `b`"));
+        assert!(check_field(&bits("c", 2, 31), &earlier, 32).unwrap_err().contains("does not fit"));
+        assert!(check_field(&bits("c", 0, 0), &earlier, 32).unwrap_err().contains("no bits"));
+    }
+
+    #[test]
+    fn expand_error_test() {
+        let overlapping: DeriveInput = syn::parse_quote! {
+            struct Word {
+                #[bits(width = 9, lsb = 23)]
+                a: u64,
+                #[bits(width = 5, lsb = 22, signed)]
+                b: i64,
+            }
+        };
+        assert!(expand(&overlapping).unwrap_err().to_string().contains("overlaps"));
+
+        let narrow: DeriveInput = syn::parse_quote! {
+            #[bitpack(word_bits = 16)]
+            struct Word {
+                #[bits(width = 9, lsb = 8)]
+                a: u64,
+            }
+        };
+        assert!(expand(&narrow).unwrap_err().to_string().contains("16 bit word"));
+
+        let unmarked: DeriveInput = syn::parse_quote! {
+            struct Word {
+                a: u64,
+            }
+        };
+        assert!(expand(&unmarked).unwrap_err().to_string().contains("#[bits"));
+    }
+}
@@ -0,0 +1,46 @@
+use bitpack_derive::BitPack;
+
+#[derive(BitPack, Debug, PartialEq)]
+#[bitpack(word_bits = 32)]
+struct PixelWord {
+    #[bits(width = 9, lsb = 23)]
+    a: u64,
+    #[bits(width = 5, lsb = 18, signed)]
+    b: i64,
+    #[bits(width = 5, lsb = 13, signed)]
+    c: i32,
+    #[bits(width = 5, lsb = 8, signed)]
+    d: f32,
+    #[bits(width = 4, lsb = 4)]
+    avg_pb: usize,
+    #[bits(width = 4, lsb = 0)]
+    avg_pr: u8,
+}
+
+#[test]
+fn round_trip_test() {
+    let pixel = PixelWord { a: 511, b: -16, c: 15, d: -3.0, avg_pb: 9, avg_pr: 0 };
+    let word = pixel.to_word().unwrap();
+    assert!(word < (1 << 32));
+    assert_eq!(PixelWord::from_word(word), pixel);
+}
+
+#[test]
+fn matches_hand_packing_test() {
+    let pixel = PixelWord { a: 300, b: 7, c: -1, d: 0.0, avg_pb: 3, avg_pr: 12 };
+    let mut word = 0_u64;
+    word = bitpack::bitpack::newu(word, 9, 23, 300).unwrap();
+    word = bitpack::bitpack::news(word, 5, 18, 7).unwrap();
+    word = bitpack::bitpack::news(word, 5, 13, -1).unwrap();
+    word = bitpack::bitpack::news(word, 5, 8, 0).unwrap();
+    word = bitpack::bitpack::newu(word, 4, 4, 3).unwrap();
+    word = bitpack::bitpack::newu(word, 4, 0, 12).unwrap();
+    assert_eq!(pixel.to_word(), Some(word));
+}
+
+#[test]
+fn overflow_test() {
+    assert_eq!(PixelWord { a: 512, b: 0, c: 0, d: 0.0, avg_pb: 0, avg_pr: 0 }.to_word(), None);
+    assert_eq!(PixelWord { a: 0, b: 16, c: 0, d: 0.0, avg_pb: 0, avg_pr: 0 }.to_word(), None);
+    assert_eq!(PixelWord { a: 0, b: 0, c: 0, d: 0.0, avg_pb: 0, avg_pr: 16 }.to_word(), None);
+}
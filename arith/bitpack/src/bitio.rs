@@ -0,0 +1,355 @@
+use std::io;
+use crate::v2;
+
+// Documentation:
+// A bitstream is a run of fields of any width from 0 to 64 bits, laid end to end with no
+// regard for byte boundaries. In MSB-first order (the order of JPEG and most image formats)
+// each field is written from its top bit down and bytes fill from their top bit; in LSB-first
+// order (the order of DEFLATE) fields are written from bit 0 up and bytes fill from bit 0.
+// The last byte is padded with zero bits when the stream is aligned, flushed or finished.
+
+/// The order bits are written into (and read out of) each byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BitOrder {
+    #[default]
+    MsbFirst,
+    LsbFirst,
+}
+
+/// Turns a bitpack error into the `io::Error` the stream functions return
+fn invalid(err: v2::BitpackError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// The low `width` bits set, for a width of 0 to 8
+fn low_bits(width: u32) -> u8 {
+    ((1_u16 << width) - 1) as u8
+}
+
+/// Writes fields of any width to an underlying byte writer, such as a `Vec<u8>`
+#[derive(Debug)]
+pub struct BitWriter<W: io::Write> {
+    inner: W,
+    order: BitOrder,
+    /// The partly filled byte
+    buffer: u8,
+    /// Number of bits of `buffer` in use
+    filled: u32,
+    /// Number of bits written so far
+    position: u64,
+}
+
+impl<W: io::Write> BitWriter<W> {
+    /// Starts an MSB-first stream over `inner`
+    pub fn new(inner: W) -> Self {
+        BitWriter::with_order(inner, BitOrder::MsbFirst)
+    }
+
+    /// Starts a stream over `inner` in the given bit order
+    pub fn with_order(inner: W, order: BitOrder) -> Self {
+        BitWriter { inner, order, buffer: 0, filled: 0, position: 0 }
+    }
+
+    /// Number of bits written so far, including any not yet passed on to the writer
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns true iff the stream sits on a byte boundary
+    pub fn is_aligned(&self) -> bool {
+        self.filled == 0
+    }
+
+    /// Writes the low `width` bits of `value`
+    ///
+    /// # Arguments:
+    /// * `value`: the unsigned value to write, which must fit in `width` bits
+    /// * `width`: the width of the field, at most 64
+    pub fn write_bits(&mut self, value: u64, width: u64) -> io::Result<()> {
+        v2::newu(0, width, 0, value).map_err(invalid)?;
+
+        let width = width as u32;
+        let mut remaining = width;
+        while remaining > 0 {
+            let count = remaining.min(8 - self.filled);
+            let chunk = match self.order {
+                BitOrder::MsbFirst => {
+                    let chunk = (value >> (remaining - count)) as u8 & low_bits(count);
+                    chunk << (8 - self.filled - count)
+                }
+                BitOrder::LsbFirst => {
+                    let chunk = (value >> (width - remaining)) as u8 & low_bits(count);
+                    chunk << self.filled
+                }
+            };
+            self.buffer |= chunk;
+            self.filled += count;
+            remaining -= count;
+            if self.filled == 8 {
+                self.inner.write_all(&[self.buffer])?;
+                self.buffer = 0;
+                self.filled = 0;
+            }
+        }
+        self.position += width as u64;
+        Ok(())
+    }
+
+    /// Writes `value` as a two's complement field of `width` bits
+    ///
+    /// # Arguments:
+    /// * `value`: the signed value to write, which must fit in `width` bits
+    /// * `width`: the width of the field, at most 64
+    pub fn write_signed(&mut self, value: i64, width: u64) -> io::Result<()> {
+        let bits = v2::news(0, width, 0, value).map_err(invalid)?;
+        self.write_bits(bits, width)
+    }
+
+    /// Writes a single bit
+    pub fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+        self.write_bits(bit as u64, 1)
+    }
+
+    /// Pads the stream with zero bits up to the next byte boundary
+    pub fn align(&mut self) -> io::Result<()> {
+        if self.filled > 0 {
+            self.write_bits(0, (8 - self.filled) as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Aligns the stream and flushes the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.align()?;
+        self.inner.flush()
+    }
+
+    /// Aligns the stream and hands back the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.align()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads fields of any width from an underlying byte reader, such as a `&[u8]`.
+/// Bytes are read one at a time, so readers other than slices should be buffered
+#[derive(Debug)]
+pub struct BitReader<R: io::Read> {
+    inner: R,
+    order: BitOrder,
+    /// The byte being read
+    buffer: u8,
+    /// Number of bits of `buffer` not yet read
+    available: u32,
+    /// Number of bits read so far
+    position: u64,
+}
+
+impl<R: io::Read> BitReader<R> {
+    /// Starts reading an MSB-first stream from `inner`
+    pub fn new(inner: R) -> Self {
+        BitReader::with_order(inner, BitOrder::MsbFirst)
+    }
+
+    /// Starts reading a stream from `inner` in the given bit order
+    pub fn with_order(inner: R, order: BitOrder) -> Self {
+        BitReader { inner, order, buffer: 0, available: 0, position: 0 }
+    }
+
+    /// Number of bits read so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns true iff the stream sits on a byte boundary
+    pub fn is_aligned(&self) -> bool {
+        self.available == 0
+    }
+
+    /// Reads an unsigned field of `width` bits. Fails with `UnexpectedEof` if the
+    /// stream ends first
+    ///
+    /// # Arguments:
+    /// * `width`: the width of the field, at most 64
+    pub fn read_bits(&mut self, width: u64) -> io::Result<u64> {
+        v2::fitsu(0, width).map_err(invalid)?;
+
+        let width = width as u32;
+        let mut value = 0_u64;
+        let mut done = 0;
+        while done < width {
+            if self.available == 0 {
+                let mut byte = [0_u8];
+                self.inner.read_exact(&mut byte)?;
+                self.buffer = byte[0];
+                self.available = 8;
+            }
+            let count = (width - done).min(self.available);
+            let used = 8 - self.available;
+            match self.order {
+                BitOrder::MsbFirst => {
+                    let chunk = (self.buffer >> (self.available - count)) & low_bits(count);
+                    value = (value << count) | chunk as u64;
+                }
+                BitOrder::LsbFirst => {
+                    let chunk = (self.buffer >> used) & low_bits(count);
+                    value |= (chunk as u64) << done;
+                }
+            }
+            self.available -= count;
+            done += count;
+        }
+        self.position += width as u64;
+        Ok(value)
+    }
+
+    /// Reads a two's complement field of `width` bits
+    ///
+    /// # Arguments:
+    /// * `width`: the width of the field, at most 64
+    pub fn read_signed(&mut self, width: u64) -> io::Result<i64> {
+        let bits = self.read_bits(width)?;
+        v2::gets(bits, width, 0).map_err(invalid)
+    }
+
+    /// Reads a single bit
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Skips the rest of the current byte
+    pub fn align(&mut self) {
+        self.position += self.available as u64;
+        self.available = 0;
+    }
+
+    /// Hands back the underlying reader, dropping any unread bits of the current byte
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: [(u64, u64); 9] = [
+        (0b101, 3),
+        (0, 0),
+        (0x1f, 5),
+        (1, 1),
+        (0x1234, 13),
+        (u64::MAX, 64),
+        (0, 7),
+        (0x8000_0000_0000_0001, 64),
+        (0x2a, 6),
+    ];
+
+    fn round_trip(order: BitOrder) {
+        let mut writer = BitWriter::with_order(Vec::new(), order);
+        for (value, width) in FIELDS {
+            writer.write_bits(value, width).unwrap();
+        }
+        let total: u64 = FIELDS.iter().map(|(_, width)| width).sum();
+        assert_eq!(writer.position(), total);
+        let bytes = writer.into_inner().unwrap();
+        assert_eq!(bytes.len() as u64, total.div_ceil(8));
+
+        let mut reader = BitReader::with_order(bytes.as_slice(), order);
+        for (value, width) in FIELDS {
+            assert_eq!(reader.read_bits(width).unwrap(), value, "{} bits", width);
+        }
+        assert_eq!(reader.position(), total);
+    }
+
+    #[test]
+    fn msb_round_trip_test() {
+        round_trip(BitOrder::MsbFirst);
+    }
+
+    #[test]
+    fn lsb_round_trip_test() {
+        round_trip(BitOrder::LsbFirst);
+    }
+
+    #[test]
+    fn byte_layout_test() {
+        let mut msb = BitWriter::new(Vec::new());
+        msb.write_bits(0b101, 3).unwrap();
+        msb.write_bits(0b11110, 5).unwrap();
+        msb.write_bits(0b1, 1).unwrap();
+        assert_eq!(msb.into_inner().unwrap(), [0b1011_1110, 0b1000_0000]);
+
+        let mut lsb = BitWriter::with_order(Vec::new(), BitOrder::LsbFirst);
+        lsb.write_bits(0b101, 3).unwrap();
+        lsb.write_bits(0b11110, 5).unwrap();
+        lsb.write_bits(0b1, 1).unwrap();
+        assert_eq!(lsb.into_inner().unwrap(), [0b1111_0101, 0b0000_0001]);
+    }
+
+    #[test]
+    fn signed_test() {
+        for order in [BitOrder::MsbFirst, BitOrder::LsbFirst] {
+            let mut writer = BitWriter::with_order(Vec::new(), order);
+            writer.write_signed(-1, 1).unwrap();
+            writer.write_signed(-16, 5).unwrap();
+            writer.write_signed(15, 5).unwrap();
+            writer.write_signed(i64::MIN, 64).unwrap();
+            let bytes = writer.into_inner().unwrap();
+
+            let mut reader = BitReader::with_order(bytes.as_slice(), order);
+            assert_eq!(reader.read_signed(1).unwrap(), -1);
+            assert_eq!(reader.read_signed(5).unwrap(), -16);
+            assert_eq!(reader.read_signed(5).unwrap(), 15);
+            assert_eq!(reader.read_signed(64).unwrap(), i64::MIN);
+        }
+    }
+
+    #[test]
+    fn align_test() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bit(true).unwrap();
+        assert!(!writer.is_aligned());
+        writer.align().unwrap();
+        assert!(writer.is_aligned());
+        assert_eq!(writer.position(), 8);
+        writer.write_bits(0xab, 8).unwrap();
+        writer.align().unwrap();
+        assert_eq!(writer.position(), 16);
+        let bytes = writer.into_inner().unwrap();
+        assert_eq!(bytes, [0x80, 0xab]);
+
+        let mut reader = BitReader::new(bytes.as_slice());
+        assert!(reader.read_bit().unwrap());
+        reader.align();
+        assert!(reader.is_aligned());
+        assert_eq!(reader.position(), 8);
+        assert_eq!(reader.read_bits(8).unwrap(), 0xab);
+    }
+
+    #[test]
+    fn io_test() {
+        let mut writer = BitWriter::new(io::Cursor::new(Vec::new()));
+        writer.write_bits(0x3ff, 10).unwrap();
+        writer.flush().unwrap();
+        let bytes = writer.into_inner().unwrap().into_inner();
+        assert_eq!(bytes, [0xff, 0xc0]);
+
+        let mut reader = BitReader::new(io::Cursor::new(bytes));
+        assert_eq!(reader.read_bits(10).unwrap(), 0x3ff);
+        assert_eq!(reader.read_bits(6).unwrap(), 0);
+        assert_eq!(reader.read_bits(1).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn invalid_field_test() {
+        let mut writer = BitWriter::new(Vec::new());
+        assert_eq!(writer.write_bits(8, 3).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.write_signed(-5, 3).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.write_bits(0, 65).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.position(), 0);
+
+        let mut reader = BitReader::new([0_u8; 9].as_slice());
+        assert_eq!(reader.read_bits(65).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
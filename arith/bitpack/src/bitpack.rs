@@ -1,69 +1,59 @@
-use std::{convert::TryInto, arch::x86_64::_CMP_TRUE_UQ};
+use crate::v2;
 
+// Documentation:
+// These are the assignment's unchecked functions, kept for existing callers. They forward to
+// `v2`, so they work for every width from 0 to 64 and replace a field rather than OR-ing into
+// it; use `v2` directly to get a `BitpackError` instead of a panic or `None`.
 
 /// Returns true iff the signed value `n` fits into `width` signed bits.
+/// Every value fits in a field wider than 64 bits.
 /// 
 /// # Arguments:
 /// * `n`: A signed integer value
 /// * `width`: the width of a bit field
 pub fn fitss(n: i64, width: u64) -> bool {
-    
-    // Check if the n value is within the upper and lower bounds
-    // since it is a signed integer
-    if n >= -((1 << width) / 2) && n < ((1 << width) / 2){
-        true
-    }
-    else{
-        false
-    }
+    v2::fitss(n, width).unwrap_or(true)
 }
 
 /// Returns true iff the unsigned value `n` fits into `width` unsigned bits.
+/// Every value fits in a field wider than 64 bits.
 /// 
 /// # Arguments:
 /// * `n`: An usigned integer value
 /// * `width`: the width of a bit field
 pub fn fitsu(n: u64, width: u64) -> bool {
-
-    // Check if the n value is within 0 and the upper bound
-    // since it is an unsigned integer
-    if n < (1 << width){
-        true
-    }
-    else{
-        false
-    }
+    v2::fitsu(n, width).unwrap_or(true)
 }
 
 /// Retrieve a signed value from `word`, represented by `width` bits
 /// beginning at least-significant bit `lsb`.
+/// Panics if the field does not lie within the 64-bit word.
 /// 
 /// # Arguments:
 /// * `word`: An unsigned word
 /// * `width`: the width of a bit field
 /// * `lsb`: the least-significant bit of the bit field
 pub fn gets(word: u64, width: u64, lsb: u64) -> i64 {
-    let bitshft = 64 - width;
-    (word << (bitshft - lsb)) as i64 >> (bitshft)
+    v2::gets(word, width, lsb).unwrap_or_else(|err| panic!("{}", err))
 }
 
 /// Retrieve an unsigned value from `word`, represented by `width` bits
 /// beginning at least-significant bit `lsb`.
+/// Panics if the field does not lie within the 64-bit word.
 /// 
 /// # Arguments:
 /// * `word`: An unsigned word
 /// * `width`: the width of a bit field
 /// * `lsb`: the least-significant bit of the bit field
 pub fn getu(word: u64, width: u64, lsb: u64) -> u64 {
-    let bitshft = 64 - width;
-    (word << (bitshft - lsb)) >> (bitshft)    
+    v2::getu(word, width, lsb).unwrap_or_else(|err| panic!("{}", err))
 }
 
 /// Return a modified version of the unsigned `word`,
 /// which has been updated so that the `width` bits beginning at
 /// least-significant bit `lsb` now contain the unsigned `value`.
 /// Returns an `Option` which will be None iff the value does not fit
-/// in `width` unsigned bits.
+/// in `width` unsigned bits or the field does not lie within the word.
 /// 
 /// # Arguments:
 /// * `word`: An unsigned word
@@ -71,19 +61,14 @@ pub fn getu(word: u64, width: u64, lsb: u64) -> u64 {
 /// * `lsb`: the least-significant bit of the bit field
 /// * `value`: the unsigned value to place into that bit field
 pub fn newu(word: u64, width: u64, lsb: u64, value: u64) -> Option<u64> {
-    if fitsu(value, width){
-        Some(value << lsb | word)
-    }
-    else{
-        None
-    }
+    v2::newu(word, width, lsb, value).ok()
 }
 
 /// Return a modified version of the unsigned `word`,
 /// which has been updated so that the `width` bits beginning at
 /// least-significant bit `lsb` now contain the signed `value`.
 /// Returns an `Option` which will be None iff the value does not fit
-/// in `width` signed bits.
+/// in `width` signed bits or the field does not lie within the word.
 /// 
 /// # Arguments:
 /// * `word`: An unsigned word
@@ -91,12 +76,7 @@ pub fn newu(word: u64, width: u64, lsb: u64, value: u64) -> Option<u64> {
 /// * `lsb`: the least-significant bit of the bit field
 /// * `value`: the signed value to place into that bit field
 pub fn news(word: u64, width: u64, lsb: u64, value: i64) -> Option<u64> {
-    if fitss(value, width){
-        Some(((((!(-1_i64 << width)) & value) as i64) as u64) << lsb | word)
-    }
-    else{
-        None
-    }
+    v2::news(word, width, lsb, value).ok()
 }
 
 
@@ -105,6 +85,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn fitss_range_test() {
         assert_eq!(fitss(7, 5), true);
         assert_eq!(fitss(-4, 3), true);
@@ -113,6 +94,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn fitsu_range_test() {
         assert_eq!(fitsu(17, 5), true);
         assert_eq!(fitsu(7, 3), true);
@@ -136,7 +118,9 @@ mod tests {
 
     #[test]
     fn newu_test() {
-        assert_eq!(newu(255, 4, 1, 7 as u64).unwrap(), 255);
+        assert_eq!(newu(255, 4, 1, 7).unwrap(), 239);
+        assert_eq!(newu(255, 4, 1, 0).unwrap(), 225);
+        assert_eq!(newu(0, 64, 0, u64::MAX).unwrap(), u64::MAX);
     }
     
     #[test]
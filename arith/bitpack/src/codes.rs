@@ -0,0 +1,371 @@
+use std::io;
+use crate::bitio::{BitReader, BitWriter};
+
+// Documentation:
+// Variable-length codes spend few bits on small values and more on large ones, which suits
+// quantized coefficients that cluster around zero. Signed values are first zig-zag mapped
+// (0, -1, 1, -2, 2, ... become 0, 1, 2, 3, 4, ...) so that small magnitudes of either sign
+// stay short.
+//
+// * Exp-Golomb order k: `value + 2^k` in binary, preceded by one zero for each bit it has
+//   beyond k + 1. Order 0 is the ue(v) code of H.264.
+// * Golomb-Rice parameter k: `value >> k` in unary (that many ones, then a zero), followed by
+//   the low k bits of the value.
+// * Elias gamma: like Exp-Golomb order 0, but for values from 1 up.
+// * Elias delta: the bit length of the value in Elias gamma, then the value without its top bit.
+// * LEB128: seven bits per byte, least significant group first, with the top bit of each byte
+//   set when more bytes follow. Signed LEB128 sign-extends from the last group.
+
+/// Longest run of leading zeros (or unary ones) the decoders accept before calling the
+/// stream corrupt; no value that fits in 64 bits needs more
+const MAX_PREFIX: u32 = 64;
+
+/// Maps signed values onto unsigned ones so that small magnitudes stay small
+pub fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Reverses `zigzag`
+pub fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn invalid_input(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Number of bits needed to write `value`, which is 0 for 0
+fn bit_length(value: u128) -> u32 {
+    128 - value.leading_zeros()
+}
+
+/// Writes the low `width` bits of `value`, for widths up to 128
+fn write_wide<W: io::Write>(writer: &mut BitWriter<W>, value: u128, width: u32) -> io::Result<()> {
+    let value = if width == 0 { 0 } else { value & (u128::MAX >> (128 - width)) };
+    if width > 64 {
+        writer.write_bits((value >> 64) as u64, (width - 64) as u64)?;
+        return writer.write_bits(value as u64, 64);
+    }
+    writer.write_bits(value as u64, width as u64)
+}
+
+/// Reads `width` bits, for widths up to 128
+fn read_wide<R: io::Read>(reader: &mut BitReader<R>, width: u32) -> io::Result<u128> {
+    if width > 64 {
+        let high = reader.read_bits((width - 64) as u64)? as u128;
+        return Ok((high << 64) | reader.read_bits(64)? as u128);
+    }
+    Ok(reader.read_bits(width as u64)? as u128)
+}
+
+/// Counts the bits equal to `bit` before the first one that is not, consuming the one that ends the run
+fn read_run<R: io::Read>(reader: &mut BitReader<R>, bit: bool) -> io::Result<u32> {
+    let mut run = 0;
+    while reader.read_bit()? == bit {
+        run += 1;
+        if run > MAX_PREFIX {
+            return Err(invalid_data(format!("a run of more than {} prefix bits", MAX_PREFIX)));
+        }
+    }
+    Ok(run)
+}
+
+/// Turns a decoded value that may have grown past 64 bits back into a `u64`
+fn narrow(value: u128) -> io::Result<u64> {
+    u64::try_from(value).map_err(|_| invalid_data(format!("decoded value {} does not fit in 64 bits", value)))
+}
+
+// -----------------------------------------------------------------------------------
+//                              EXP-GOLOMB
+// -----------------------------------------------------------------------------------
+
+/// Writes `value` in the Exp-Golomb code of order `k`
+///
+/// # Arguments:
+/// * `writer`: the bitstream
+/// * `value`: the value to write
+/// * `k`: the order, below 64; larger orders suit larger typical values
+pub fn write_exp_golomb<W: io::Write>(writer: &mut BitWriter<W>, value: u64, k: u32) -> io::Result<()> {
+    if k >= 64 {
+        return Err(invalid_input(format!("Exp-Golomb order {} is not below 64", k)));
+    }
+    let shifted = value as u128 + (1 << k);
+    let length = bit_length(shifted);
+    write_wide(writer, 0, length - k - 1)?;
+    write_wide(writer, shifted, length)
+}
+
+/// Reads a value written by `write_exp_golomb` with the same order `k`
+pub fn read_exp_golomb<R: io::Read>(reader: &mut BitReader<R>, k: u32) -> io::Result<u64> {
+    if k >= 64 {
+        return Err(invalid_input(format!("Exp-Golomb order {} is not below 64", k)));
+    }
+    let zeros = read_run(reader, false)?;
+    let rest = read_wide(reader, zeros + k)?;
+    narrow(((1_u128 << (zeros + k)) | rest) - (1 << k))
+}
+
+/// Writes the zig-zag mapping of `value` in the Exp-Golomb code of order `k`
+pub fn write_signed_exp_golomb<W: io::Write>(writer: &mut BitWriter<W>, value: i64, k: u32) -> io::Result<()> {
+    write_exp_golomb(writer, zigzag(value), k)
+}
+
+/// Reads a value written by `write_signed_exp_golomb` with the same order `k`
+pub fn read_signed_exp_golomb<R: io::Read>(reader: &mut BitReader<R>, k: u32) -> io::Result<i64> {
+    Ok(unzigzag(read_exp_golomb(reader, k)?))
+}
+
+// -----------------------------------------------------------------------------------
+//                              GOLOMB-RICE
+// -----------------------------------------------------------------------------------
+
+/// Writes `value` in the Golomb-Rice code with parameter `k`. The quotient `value >> k` is
+/// written in unary, so it must be at most 64; pick `k` near the bit length of typical values
+///
+/// # Arguments:
+/// * `writer`: the bitstream
+/// * `value`: the value to write
+/// * `k`: the number of low bits written in binary, at most 64
+pub fn write_rice<W: io::Write>(writer: &mut BitWriter<W>, value: u64, k: u32) -> io::Result<()> {
+    if k > 64 {
+        return Err(invalid_input(format!("Rice parameter {} is above 64", k)));
+    }
+    let quotient = if k == 64 { 0 } else { value >> k };
+    if quotient > MAX_PREFIX as u64 {
+        return Err(invalid_input(format!("{} is too large for Rice parameter {}", value, k)));
+    }
+    write_wide(writer, (1_u128 << quotient) - 1, quotient as u32)?;
+    writer.write_bit(false)?;
+    write_wide(writer, value as u128 & ((1_u128 << k) - 1), k)
+}
+
+/// Reads a value written by `write_rice` with the same parameter `k`
+pub fn read_rice<R: io::Read>(reader: &mut BitReader<R>, k: u32) -> io::Result<u64> {
+    if k > 64 {
+        return Err(invalid_input(format!("Rice parameter {} is above 64", k)));
+    }
+    let quotient = read_run(reader, true)? as u128;
+    let remainder = read_wide(reader, k)?;
+    narrow((quotient << k) | remainder)
+}
+
+/// Writes the zig-zag mapping of `value` in the Golomb-Rice code with parameter `k`
+pub fn write_signed_rice<W: io::Write>(writer: &mut BitWriter<W>, value: i64, k: u32) -> io::Result<()> {
+    write_rice(writer, zigzag(value), k)
+}
+
+/// Reads a value written by `write_signed_rice` with the same parameter `k`
+pub fn read_signed_rice<R: io::Read>(reader: &mut BitReader<R>, k: u32) -> io::Result<i64> {
+    Ok(unzigzag(read_rice(reader, k)?))
+}
+
+// -----------------------------------------------------------------------------------
+//                              ELIAS
+// -----------------------------------------------------------------------------------
+
+/// Writes `value`, which must be at least 1, in the Elias gamma code
+pub fn write_elias_gamma<W: io::Write>(writer: &mut BitWriter<W>, value: u64) -> io::Result<()> {
+    if value == 0 {
+        return Err(invalid_input("Elias codes cannot hold 0".to_string()));
+    }
+    let length = bit_length(value as u128);
+    write_wide(writer, 0, length - 1)?;
+    writer.write_bits(value, length as u64)
+}
+
+/// Reads a value written by `write_elias_gamma`
+pub fn read_elias_gamma<R: io::Read>(reader: &mut BitReader<R>) -> io::Result<u64> {
+    let zeros = read_run(reader, false)?;
+    let rest = read_wide(reader, zeros)?;
+    narrow((1_u128 << zeros) | rest)
+}
+
+/// Writes `value`, which must be at least 1, in the Elias delta code
+pub fn write_elias_delta<W: io::Write>(writer: &mut BitWriter<W>, value: u64) -> io::Result<()> {
+    if value == 0 {
+        return Err(invalid_input("Elias codes cannot hold 0".to_string()));
+    }
+    let length = bit_length(value as u128);
+    write_elias_gamma(writer, length as u64)?;
+    write_wide(writer, value as u128, length - 1)
+}
+
+/// Reads a value written by `write_elias_delta`
+pub fn read_elias_delta<R: io::Read>(reader: &mut BitReader<R>) -> io::Result<u64> {
+    let length = read_elias_gamma(reader)?;
+    if length > 64 {
+        return Err(invalid_data(format!("an Elias delta value of {} bits", length)));
+    }
+    let rest = read_wide(reader, length as u32 - 1)?;
+    narrow((1_u128 << (length - 1)) | rest)
+}
+
+// -----------------------------------------------------------------------------------
+//                              LEB128
+// -----------------------------------------------------------------------------------
+
+/// Writes `value` as unsigned LEB128, eight bits to the byte
+pub fn write_leb128<W: io::Write>(writer: &mut BitWriter<W>, mut value: u64) -> io::Result<()> {
+    loop {
+        let group = value & 0x7f;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_bits(group, 8);
+        }
+        writer.write_bits(group | 0x80, 8)?;
+    }
+}
+
+/// Reads a value written by `write_leb128`
+pub fn read_leb128<R: io::Read>(reader: &mut BitReader<R>) -> io::Result<u64> {
+    let mut value = 0_u64;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_bits(8)?;
+        let group = byte & 0x7f;
+        if shift >= 64 || (shift == 63 && group > 1) {
+            return Err(invalid_data("LEB128 value does not fit in 64 bits".to_string()));
+        }
+        value |= group << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Writes `value` as signed LEB128
+pub fn write_sleb128<W: io::Write>(writer: &mut BitWriter<W>, mut value: i64) -> io::Result<()> {
+    loop {
+        let group = (value & 0x7f) as u64;
+        value >>= 7;
+        let sign_bit = group & 0x40 != 0;
+        if (value == 0 && !sign_bit) || (value == -1 && sign_bit) {
+            return writer.write_bits(group, 8);
+        }
+        writer.write_bits(group | 0x80, 8)?;
+    }
+}
+
+/// Reads a value written by `write_sleb128`
+pub fn read_sleb128<R: io::Read>(reader: &mut BitReader<R>) -> io::Result<i64> {
+    let mut value = 0_i64;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_bits(8)?;
+        if shift >= 64 {
+            return Err(invalid_data("signed LEB128 value does not fit in 64 bits".to_string()));
+        }
+        value |= ((byte & 0x7f) as i64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                value |= -1 << shift;
+            }
+            return Ok(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNSIGNED: [u64; 10] = [0, 1, 2, 3, 7, 8, 255, 1 << 40, u64::MAX - 1, u64::MAX];
+    const SIGNED: [i64; 9] = [0, -1, 1, -2, 63, -64, 1 << 40, i64::MIN, i64::MAX];
+
+    /// Writes `values` with `write`, reads them back with `read` and checks they match
+    fn round_trip<T: Copy + PartialEq + std::fmt::Debug>(
+        values: &[T],
+        write: impl Fn(&mut BitWriter<Vec<u8>>, T) -> io::Result<()>,
+        read: impl Fn(&mut BitReader<io::Cursor<Vec<u8>>>) -> io::Result<T>,
+    ) {
+        let mut writer = BitWriter::new(Vec::new());
+        for &value in values {
+            write(&mut writer, value).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+        let mut reader = BitReader::new(io::Cursor::new(bytes));
+        for &value in values {
+            assert_eq!(read(&mut reader).unwrap(), value);
+        }
+    }
+
+    /// The bits `write` produces, as a string of 0s and 1s
+    fn bits_of(write: impl Fn(&mut BitWriter<Vec<u8>>) -> io::Result<()>) -> String {
+        let mut writer = BitWriter::new(Vec::new());
+        write(&mut writer).unwrap();
+        let length = writer.position() as usize;
+        let bytes = writer.into_inner().unwrap();
+        bytes.iter().map(|byte| format!("{:08b}", byte)).collect::<String>()[..length].to_string()
+    }
+
+    #[test]
+    fn zigzag_test() {
+        assert_eq!([0, -1, 1, -2, 2].map(zigzag), [0, 1, 2, 3, 4]);
+        assert_eq!(zigzag(i64::MIN), u64::MAX);
+        assert_eq!(zigzag(i64::MAX), u64::MAX - 1);
+        for value in SIGNED {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+
+    #[test]
+    fn exp_golomb_test() {
+        assert_eq!(bits_of(|w| write_exp_golomb(w, 0, 0)), "1");
+        assert_eq!(bits_of(|w| write_exp_golomb(w, 3, 0)), "00100");
+        assert_eq!(bits_of(|w| write_exp_golomb(w, 3, 2)), "111");
+        assert_eq!(bits_of(|w| write_signed_exp_golomb(w, -1, 0)), "010");
+        for k in [0, 1, 5, 63] {
+            round_trip(&UNSIGNED, |w, v| write_exp_golomb(w, v, k), |r| read_exp_golomb(r, k));
+            round_trip(&SIGNED, |w, v| write_signed_exp_golomb(w, v, k), |r| read_signed_exp_golomb(r, k));
+        }
+    }
+
+    #[test]
+    fn rice_test() {
+        assert_eq!(bits_of(|w| write_rice(w, 9, 2)), "11001");
+        assert_eq!(bits_of(|w| write_signed_rice(w, -1, 0)), "10");
+        round_trip(&[0, 1, 5, 63, 64, 4095], |w, v| write_rice(w, v, 6), |r| read_rice(r, 6));
+        round_trip(&UNSIGNED, |w, v| write_rice(w, v, 64), |r| read_rice(r, 64));
+        round_trip(&[0, -1, 7, -8, 100], |w, v| write_signed_rice(w, v, 3), |r| read_signed_rice(r, 3));
+        let mut writer = BitWriter::new(Vec::new());
+        assert_eq!(write_rice(&mut writer, 1 << 20, 0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn elias_test() {
+        assert_eq!(bits_of(|w| write_elias_gamma(w, 1)), "1");
+        assert_eq!(bits_of(|w| write_elias_gamma(w, 5)), "00101");
+        assert_eq!(bits_of(|w| write_elias_delta(w, 1)), "1");
+        assert_eq!(bits_of(|w| write_elias_delta(w, 10)), "00100010");
+        round_trip(&UNSIGNED[1..], write_elias_gamma, read_elias_gamma);
+        round_trip(&UNSIGNED[1..], write_elias_delta, read_elias_delta);
+        let mut writer = BitWriter::new(Vec::new());
+        assert_eq!(write_elias_gamma(&mut writer, 0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(write_elias_delta(&mut writer, 0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn leb128_test() {
+        assert_eq!(bits_of(|w| write_leb128(w, 624485)), "111001011000111000100110");
+        assert_eq!(bits_of(|w| write_sleb128(w, -123456)), "110000001011101101111000");
+        round_trip(&UNSIGNED, write_leb128, read_leb128);
+        round_trip(&SIGNED, write_sleb128, read_sleb128);
+    }
+
+    #[test]
+    fn corrupt_stream_test() {
+        let zeros = [0_u8; 16];
+        assert_eq!(read_exp_golomb(&mut BitReader::new(zeros.as_slice()), 0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_elias_gamma(&mut BitReader::new(zeros.as_slice())).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let ones = [0xff_u8; 16];
+        assert_eq!(read_rice(&mut BitReader::new(ones.as_slice()), 0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_leb128(&mut BitReader::new(ones.as_slice())).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_leb128(&mut BitReader::new([0x80_u8].as_slice())).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,295 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::io;
+use crate::bitio::{BitReader, BitWriter};
+use crate::codes::{read_leb128, write_leb128};
+
+// Documentation:
+// A canonical Huffman code is fixed by the code length of each symbol alone: symbols are
+// sorted by length and then by value, and take consecutive codes, each length starting where
+// the shorter ones left off shifted left by the difference. So a table only needs the symbols
+// and their lengths, and both ends rebuild the same codes from it.
+//
+// A table is written as the number of symbols in LEB128, then each symbol in canonical order
+// as its value in LEB128 followed by its code length less one in 6 bits. Codes are at most 64
+// bits long. Skewed enough frequencies can still need longer ones (66 symbols with Fibonacci
+// frequencies make a tree 65 deep), so when the tree is too deep the frequencies are halved,
+// keeping each at least 1, and the tree is rebuilt until it fits; equal frequencies give a
+// balanced tree, so this always ends. A table of one symbol gives it the 1-bit code 0.
+
+/// Longest code a table can hold
+pub const MAX_LENGTH: u32 = 64;
+
+/// Bits used to write each code length in a table
+const LENGTH_BITS: u64 = 6;
+
+fn invalid_input(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// The codes of a canonical Huffman table, and what is needed to decode them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HuffmanCode {
+    /// Each symbol and its code length, in canonical order
+    lengths: Vec<(u64, u32)>,
+    /// Each symbol's code and code length
+    codes: BTreeMap<u64, (u64, u32)>,
+    /// For each code length: its first code, the index of its first symbol in `lengths`,
+    /// and how many symbols have it
+    first: Vec<(u128, usize, usize)>,
+}
+
+impl HuffmanCode {
+    /// Builds the optimal code for the given symbol frequencies, or a near-optimal one when
+    /// the optimal code needs lengths above `MAX_LENGTH`. Symbols with a frequency of 0 are
+    /// left out
+    ///
+    /// # Arguments:
+    /// * `frequencies`: the number of times each symbol occurs
+    pub fn from_frequencies(frequencies: &BTreeMap<u64, u64>) -> Self {
+        let symbols: Vec<(u64, u64)> = frequencies.iter()
+            .filter(|(_, &count)| count > 0)
+            .map(|(&symbol, &count)| (symbol, count))
+            .collect();
+        if symbols.len() == 1 {
+            return HuffmanCode::from_valid_lengths(vec![(symbols[0].0, 1)]);
+        }
+
+        let mut counts: Vec<u64> = symbols.iter().map(|&(_, count)| count).collect();
+        let mut depths = tree_depths(&counts);
+        while depths.iter().any(|&depth| depth > MAX_LENGTH) {
+            counts = counts.iter().map(|&count| (count >> 1).max(1)).collect();
+            depths = tree_depths(&counts);
+        }
+        let lengths = symbols.iter().zip(&depths).map(|(&(symbol, _), &depth)| (symbol, depth)).collect();
+        HuffmanCode::from_valid_lengths(lengths)
+    }
+
+    /// Builds the canonical code with the given code lengths. Fails with `InvalidData` if a
+    /// length is 0 or above `MAX_LENGTH`, a symbol appears twice, or the lengths ask for more
+    /// codes than there are
+    ///
+    /// # Arguments:
+    /// * `lengths`: each symbol and its code length, in any order
+    pub fn from_lengths(lengths: Vec<(u64, u32)>) -> io::Result<Self> {
+        if let Some(&(symbol, length)) = lengths.iter().find(|(_, length)| *length == 0 || *length > MAX_LENGTH) {
+            return Err(invalid_data(format!("symbol {} has a code length of {}", symbol, length)));
+        }
+        let mut seen = BTreeSet::new();
+        if let Some(&(symbol, _)) = lengths.iter().find(|(symbol, _)| !seen.insert(*symbol)) {
+            return Err(invalid_data(format!("symbol {} appears twice in the table", symbol)));
+        }
+
+        // Kraft's inequality: the lengths fit in a prefix code iff the sum of 2^-length is at most 1
+        let kraft: u128 = lengths.iter().map(|&(_, length)| 1_u128 << (MAX_LENGTH - length)).sum();
+        if kraft > 1 << MAX_LENGTH {
+            return Err(invalid_data("code lengths ask for more codes than there are".to_string()));
+        }
+        Ok(HuffmanCode::from_valid_lengths(lengths))
+    }
+
+    fn from_valid_lengths(mut lengths: Vec<(u64, u32)>) -> Self {
+        lengths.sort_by_key(|&(symbol, length)| (length, symbol));
+
+        let mut codes = BTreeMap::new();
+        let mut first = vec![(0_u128, 0_usize, 0_usize); MAX_LENGTH as usize + 1];
+        let mut code = 0_u128;
+        let mut previous = 0;
+        for (index, &(symbol, length)) in lengths.iter().enumerate() {
+            while previous < length {
+                code <<= 1;
+                previous += 1;
+                first[previous as usize] = (code, index, 0);
+            }
+            codes.insert(symbol, (code as u64, length));
+            first[length as usize].2 += 1;
+            code += 1;
+        }
+        HuffmanCode { lengths, codes, first }
+    }
+
+    /// Each symbol and its code length, in canonical order
+    pub fn lengths(&self) -> &[(u64, u32)] {
+        &self.lengths
+    }
+
+    /// The code and code length of `symbol`, if it is in the table
+    pub fn code(&self, symbol: u64) -> Option<(u64, u32)> {
+        self.codes.get(&symbol).copied()
+    }
+
+    /// Writes the table, so that `read_table` can rebuild the code
+    pub fn write_table<W: io::Write>(&self, writer: &mut BitWriter<W>) -> io::Result<()> {
+        write_leb128(writer, self.lengths.len() as u64)?;
+        for &(symbol, length) in &self.lengths {
+            write_leb128(writer, symbol)?;
+            writer.write_bits((length - 1) as u64, LENGTH_BITS)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a table written by `write_table`
+    pub fn read_table<R: io::Read>(reader: &mut BitReader<R>) -> io::Result<Self> {
+        let count = read_leb128(reader)?;
+        let mut lengths = Vec::new();
+        for _ in 0..count {
+            let symbol = read_leb128(reader)?;
+            let length = reader.read_bits(LENGTH_BITS)? as u32 + 1;
+            lengths.push((symbol, length));
+        }
+        HuffmanCode::from_lengths(lengths)
+    }
+
+    /// Writes the code for `symbol`. Fails with `InvalidInput` if it is not in the table
+    pub fn write_symbol<W: io::Write>(&self, writer: &mut BitWriter<W>, symbol: u64) -> io::Result<()> {
+        let (code, length) = self.code(symbol)
+            .ok_or_else(|| invalid_input(format!("symbol {} is not in the table", symbol)))?;
+        writer.write_bits(code, length as u64)
+    }
+
+    /// Reads one code and returns its symbol. Fails with `InvalidData` if the bits match no
+    /// code, which can only happen when the table leaves some codes unused
+    pub fn read_symbol<R: io::Read>(&self, reader: &mut BitReader<R>) -> io::Result<u64> {
+        let mut code = 0_u128;
+        for length in 1..=MAX_LENGTH as usize {
+            code = (code << 1) | reader.read_bit()? as u128;
+            let (first_code, first_index, count) = self.first[length];
+            if count > 0 && code >= first_code && code - first_code < count as u128 {
+                return Ok(self.lengths[first_index + (code - first_code) as usize].0);
+            }
+        }
+        Err(invalid_data("bits match no code in the table".to_string()))
+    }
+}
+
+/// Builds the Huffman tree for `counts` and returns the depth of each leaf
+fn tree_depths(counts: &[u64]) -> Vec<u32> {
+    // Repeatedly merge the two least frequent nodes; ties go to the node made first, so
+    // the same frequencies always give the same code. Nodes past the leaves are merges
+    let mut parents = vec![0_usize; counts.len()];
+    let mut heap: BinaryHeap<Reverse<(u128, usize)>> = counts.iter()
+        .enumerate()
+        .map(|(node, &count)| Reverse((count as u128, node)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((first, first_node)) = heap.pop().unwrap();
+        let Reverse((second, second_node)) = heap.pop().unwrap();
+        let merged = parents.len();
+        parents.push(merged);
+        parents[first_node] = merged;
+        parents[second_node] = merged;
+        heap.push(Reverse((first + second, merged)));
+    }
+
+    // Parents are always made after their children, so depths fill in from the root down
+    let mut depths = vec![0_u32; parents.len()];
+    for node in (0..parents.len().saturating_sub(1)).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+    depths.truncate(counts.len());
+    depths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frequencies(counts: &[(u64, u64)]) -> BTreeMap<u64, u64> {
+        counts.iter().copied().collect()
+    }
+
+    /// Writes the table and `symbols`, reads both back and checks they match
+    fn round_trip(code: &HuffmanCode, symbols: &[u64]) {
+        let mut writer = BitWriter::new(Vec::new());
+        code.write_table(&mut writer).unwrap();
+        for &symbol in symbols {
+            code.write_symbol(&mut writer, symbol).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let mut reader = BitReader::new(bytes.as_slice());
+        let read = HuffmanCode::read_table(&mut reader).unwrap();
+        assert_eq!(&read, code);
+        for &symbol in symbols {
+            assert_eq!(read.read_symbol(&mut reader).unwrap(), symbol);
+        }
+    }
+
+    #[test]
+    fn canonical_test() {
+        // The textbook example: lengths 1, 3, 3, 3, 4, 4
+        let code = HuffmanCode::from_frequencies(&frequencies(&[(10, 45), (11, 13), (12, 12), (13, 16), (14, 9), (15, 5)]));
+        assert_eq!(code.code(10), Some((0b0, 1)));
+        assert_eq!(code.code(11), Some((0b100, 3)));
+        assert_eq!(code.code(12), Some((0b101, 3)));
+        assert_eq!(code.code(13), Some((0b110, 3)));
+        assert_eq!(code.code(14), Some((0b1110, 4)));
+        assert_eq!(code.code(15), Some((0b1111, 4)));
+        assert_eq!(code.code(16), None);
+        round_trip(&code, &[10, 15, 11, 14, 12, 13, 10, 10]);
+    }
+
+    #[test]
+    fn small_table_test() {
+        let empty = HuffmanCode::from_frequencies(&BTreeMap::new());
+        assert!(empty.lengths().is_empty());
+        round_trip(&empty, &[]);
+
+        let single = HuffmanCode::from_frequencies(&frequencies(&[(7, 0), (u64::MAX, 3)]));
+        assert_eq!(single.lengths(), [(u64::MAX, 1)]);
+        round_trip(&single, &[u64::MAX, u64::MAX]);
+    }
+
+    /// The first `count` Fibonacci numbers as the frequencies of symbols 0 to `count` - 1
+    fn fibonacci(count: u64) -> BTreeMap<u64, u64> {
+        let mut counts = BTreeMap::new();
+        let (mut a, mut b) = (1_u64, 1_u64);
+        for symbol in 0..count {
+            counts.insert(symbol, a);
+            (a, b) = (b, a.saturating_add(b));
+        }
+        counts
+    }
+
+    #[test]
+    fn long_code_test() {
+        // Fibonacci frequencies give the deepest tree for the number of symbols
+        let code = HuffmanCode::from_frequencies(&fibonacci(MAX_LENGTH as u64 + 1));
+        assert_eq!(code.lengths().iter().map(|&(_, length)| length).max(), Some(MAX_LENGTH));
+        round_trip(&code, &(0..MAX_LENGTH as u64 + 1).collect::<Vec<_>>());
+
+        // One more symbol would need a 65-bit code, so the lengths are limited instead
+        for count in [MAX_LENGTH as u64 + 2, 90] {
+            let code = HuffmanCode::from_frequencies(&fibonacci(count));
+            assert_eq!(code.lengths().len(), count as usize);
+            assert!(code.lengths().iter().all(|&(_, length)| length <= MAX_LENGTH));
+            round_trip(&code, &(0..count).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn invalid_table_test() {
+        let kind = |lengths: Vec<(u64, u32)>| HuffmanCode::from_lengths(lengths).unwrap_err().kind();
+        assert_eq!(kind(vec![(0, 0)]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(vec![(0, MAX_LENGTH + 1)]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(vec![(0, 1), (0, 2)]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(vec![(0, 1), (1, 1), (2, 1)]), io::ErrorKind::InvalidData);
+
+        // An incomplete code decodes its own codes but not the unused ones
+        let code = HuffmanCode::from_lengths(vec![(5, 2), (6, 1)]).unwrap();
+        assert_eq!(code.code(6), Some((0b0, 1)));
+        assert_eq!(code.code(5), Some((0b10, 2)));
+        let bytes = [0b0101_1111, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let mut reader = BitReader::new(bytes.as_slice());
+        assert_eq!(code.read_symbol(&mut reader).unwrap(), 6);
+        assert_eq!(code.read_symbol(&mut reader).unwrap(), 5);
+        assert_eq!(code.read_symbol(&mut reader).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut writer = BitWriter::new(Vec::new());
+        assert_eq!(code.write_symbol(&mut writer, 7).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
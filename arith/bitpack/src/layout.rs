@@ -0,0 +1,365 @@
+use std::fmt;
+use crate::v2;
+
+/// A named bit field of a `Layout`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub width: u64,
+    pub lsb: u64,
+    pub signed: bool,
+}
+
+impl Field {
+    /// The bits of a word this field occupies
+    fn mask(&self) -> u64 {
+        (u64::MAX >> (64 - self.width)) << self.lsb
+    }
+}
+
+/// Errors from building a `Layout` or moving values in and out of it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The word is not between 1 and 64 bits wide
+    WordTooLarge(u64),
+    /// A field was declared with no bits
+    EmptyField(String),
+    /// A field reaches past the top of the word
+    FieldOutOfWord { field: String, width: u64, lsb: u64 },
+    /// Two fields share at least one bit
+    Overlap { first: String, second: String },
+    /// Two fields were declared with the same name
+    DuplicateName(String),
+    /// No field has the given name or index
+    UnknownField(String),
+    /// A value is outside the range its field can hold
+    ValueDoesNotFit { field: String, value: i128 },
+    /// A set of values does not have one value per field
+    WrongValueCount { expected: usize, actual: usize },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::WordTooLarge(bits) => write!(f, "a {} bit word is not 1 to 64 bits", bits),
+            LayoutError::EmptyField(field) => write!(f, "field {} has no bits", field),
+            LayoutError::FieldOutOfWord { field, width, lsb } => {
+                write!(f, "field {} ({} bits at {}) does not fit in the word", field, width, lsb)
+            }
+            LayoutError::Overlap { first, second } => write!(f, "fields {} and {} overlap", first, second),
+            LayoutError::DuplicateName(field) => write!(f, "field {} is declared twice", field),
+            LayoutError::UnknownField(field) => write!(f, "no field {}", field),
+            LayoutError::ValueDoesNotFit { field, value } => write!(f, "{} does not fit in field {}", value, field),
+            LayoutError::WrongValueCount { expected, actual } => {
+                write!(f, "expected {} values but got {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// Something that picks out one field of a `Layout`: its name or its index
+pub trait FieldKey {
+    /// Index of the field this key names, if there is one
+    fn index_in(&self, layout: &Layout) -> Option<usize>;
+
+    /// How the key is shown in errors
+    fn describe(&self) -> String;
+}
+
+impl FieldKey for &str {
+    fn index_in(&self, layout: &Layout) -> Option<usize> {
+        layout.fields.iter().position(|field| field.name == *self)
+    }
+
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl FieldKey for usize {
+    fn index_in(&self, layout: &Layout) -> Option<usize> {
+        if *self < layout.fields.len() { Some(*self) } else { None }
+    }
+
+    fn describe(&self) -> String {
+        format!("#{}", self)
+    }
+}
+
+/// Declares the fields of a `Layout` one at a time
+#[derive(Clone, Debug)]
+pub struct LayoutBuilder {
+    word_bits: u64,
+    fields: Vec<Field>,
+}
+
+impl LayoutBuilder {
+    /// Adds an unsigned field of `width` bits beginning at least-significant bit `lsb`
+    pub fn unsigned(mut self, name: &str, width: u64, lsb: u64) -> Self {
+        self.fields.push(Field { name: name.to_string(), width, lsb, signed: false });
+        self
+    }
+
+    /// Adds a two's complement signed field of `width` bits beginning at least-significant bit `lsb`
+    pub fn signed(mut self, name: &str, width: u64, lsb: u64) -> Self {
+        self.fields.push(Field { name: name.to_string(), width, lsb, signed: true });
+        self
+    }
+
+    /// Checks the declared fields and returns the finished layout. Fails if the word is
+    /// wider than 64 bits, or if any field is empty, reaches past the word, overlaps
+    /// another field or reuses another field's name
+    pub fn build(self) -> Result<Layout, LayoutError> {
+        if self.word_bits == 0 || self.word_bits > 64 {
+            return Err(LayoutError::WordTooLarge(self.word_bits));
+        }
+        for (i, field) in self.fields.iter().enumerate() {
+            if field.width == 0 {
+                return Err(LayoutError::EmptyField(field.name.clone()));
+            }
+            if field.width > self.word_bits || field.lsb > self.word_bits - field.width {
+                return Err(LayoutError::FieldOutOfWord { field: field.name.clone(), width: field.width, lsb: field.lsb });
+            }
+            for earlier in &self.fields[..i] {
+                if earlier.name == field.name {
+                    return Err(LayoutError::DuplicateName(field.name.clone()));
+                }
+                if earlier.mask() & field.mask() != 0 {
+                    return Err(LayoutError::Overlap { first: earlier.name.clone(), second: field.name.clone() });
+                }
+            }
+        }
+        Ok(Layout { word_bits: self.word_bits, fields: self.fields })
+    }
+}
+
+/// A checked set of named, non-overlapping bit fields within a word of up to 64 bits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    word_bits: u64,
+    fields: Vec<Field>,
+}
+
+impl Layout {
+    /// Starts declaring a layout for a word of `word_bits` bits
+    pub fn builder(word_bits: u64) -> LayoutBuilder {
+        LayoutBuilder { word_bits, fields: Vec::new() }
+    }
+
+    /// Number of bits in the word
+    pub fn word_bits(&self) -> u64 {
+        self.word_bits
+    }
+
+    /// The fields, in the order they were declared
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Number of fields
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Returns true iff the layout has no fields
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The field named by `key`
+    pub fn field<K: FieldKey>(&self, key: K) -> Option<&Field> {
+        key.index_in(self).map(|index| &self.fields[index])
+    }
+
+    fn lookup<K: FieldKey>(&self, key: K) -> Result<&Field, LayoutError> {
+        key.index_in(self)
+            .map(|index| &self.fields[index])
+            .ok_or_else(|| LayoutError::UnknownField(key.describe()))
+    }
+
+    /// Places `value` in `field` of `word`, replacing what was there
+    fn place(word: u64, field: &Field, value: i128) -> Result<u64, LayoutError> {
+        let placed = if field.signed {
+            i64::try_from(value).ok().and_then(|value| v2::news(word, field.width, field.lsb, value).ok())
+        } else {
+            u64::try_from(value).ok().and_then(|value| v2::newu(word, field.width, field.lsb, value).ok())
+        };
+        placed.ok_or_else(|| LayoutError::ValueDoesNotFit { field: field.name.clone(), value })
+    }
+
+    /// Reads `field` out of `word`, sign-extending it if the field is signed
+    fn read(word: u64, field: &Field) -> i128 {
+        // Fields are checked against the word when the layout is built
+        if field.signed {
+            v2::gets(word, field.width, field.lsb).expect("field lies within the word") as i128
+        } else {
+            v2::getu(word, field.width, field.lsb).expect("field lies within the word") as i128
+        }
+    }
+
+    /// Returns `word` with the field named by `key` set to `value`
+    ///
+    /// # Arguments:
+    /// * `word`: the word to update
+    /// * `key`: the field's name or index
+    /// * `value`: the value to store, which must fit in the field
+    pub fn set<K: FieldKey>(&self, word: u64, key: K, value: i64) -> Result<u64, LayoutError> {
+        Layout::place(word, self.lookup(key)?, value as i128)
+    }
+
+    /// Unsigned version of `set`
+    pub fn setu<K: FieldKey>(&self, word: u64, key: K, value: u64) -> Result<u64, LayoutError> {
+        Layout::place(word, self.lookup(key)?, value as i128)
+    }
+
+    /// Reads the field named by `key` out of `word`. A 64-bit unsigned field wraps
+    /// around to a negative value above `i64::MAX`; use `getu` for those
+    pub fn get<K: FieldKey>(&self, word: u64, key: K) -> Result<i64, LayoutError> {
+        Ok(Layout::read(word, self.lookup(key)?) as i64)
+    }
+
+    /// Reads the field named by `key` out of `word` as an unsigned value. Negative values
+    /// of signed fields come back as their two's complement bit pattern
+    pub fn getu<K: FieldKey>(&self, word: u64, key: K) -> Result<u64, LayoutError> {
+        Ok(Layout::read(word, self.lookup(key)?) as u64)
+    }
+
+    /// Packs one value per field, in declaration order, into a new word
+    ///
+    /// # Arguments:
+    /// * `values`: the values of every field
+    pub fn pack(&self, values: &[i64]) -> Result<u64, LayoutError> {
+        self.pack_all(values.iter().map(|&value| value as i128), values.len())
+    }
+
+    /// Unsigned version of `pack`
+    pub fn packu(&self, values: &[u64]) -> Result<u64, LayoutError> {
+        self.pack_all(values.iter().map(|&value| value as i128), values.len())
+    }
+
+    fn pack_all(&self, values: impl Iterator<Item = i128>, count: usize) -> Result<u64, LayoutError> {
+        if count != self.fields.len() {
+            return Err(LayoutError::WrongValueCount { expected: self.fields.len(), actual: count });
+        }
+        self.fields.iter().zip(values).try_fold(0_u64, |word, (field, value)| Layout::place(word, field, value))
+    }
+
+    /// Reads every field out of `word`, in declaration order, with the same wrapping as `get`
+    pub fn unpack(&self, word: u64) -> Vec<i64> {
+        self.fields.iter().map(|field| Layout::read(word, field) as i64).collect()
+    }
+
+    /// Reads every field out of `word` as unsigned values, with the same wrapping as `getu`
+    pub fn unpacku(&self, word: u64) -> Vec<u64> {
+        self.fields.iter().map(|field| Layout::read(word, field) as u64).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpeg_layout() -> Layout {
+        Layout::builder(32)
+            .unsigned("a", 9, 23)
+            .signed("b", 5, 18)
+            .signed("c", 5, 13)
+            .signed("d", 5, 8)
+            .unsigned("pb", 4, 4)
+            .unsigned("pr", 4, 0)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn build_test() {
+        let layout = rpeg_layout();
+        assert_eq!(layout.len(), 6);
+        assert_eq!(layout.word_bits(), 32);
+        assert_eq!(layout.field("c").unwrap().lsb, 13);
+        assert_eq!(layout.field(4).unwrap().name, "pb");
+        assert!(layout.field("e").is_none());
+        assert!(layout.field(6).is_none());
+    }
+
+    #[test]
+    fn build_error_test() {
+        assert_eq!(Layout::builder(65).build(), Err(LayoutError::WordTooLarge(65)));
+        assert_eq!(Layout::builder(0).build(), Err(LayoutError::WordTooLarge(0)));
+        assert_eq!(Layout::builder(8).unsigned("x", 0, 0).build(), Err(LayoutError::EmptyField("x".to_string())));
+        assert_eq!(
+            Layout::builder(32).unsigned("x", 9, 24).build(),
+            Err(LayoutError::FieldOutOfWord { field: "x".to_string(), width: 9, lsb: 24 })
+        );
+        assert_eq!(
+            Layout::builder(32).unsigned("x", 4, 4).signed("y", 2, 7).build(),
+            Err(LayoutError::Overlap { first: "x".to_string(), second: "y".to_string() })
+        );
+        assert_eq!(
+            Layout::builder(32).unsigned("x", 4, 4).signed("x", 4, 0).build(),
+            Err(LayoutError::DuplicateName("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn pack_unpack_test() {
+        let layout = rpeg_layout();
+        let values = [511, -16, 15, -1, 9, 0];
+        let word = layout.pack(&values).unwrap();
+        assert!(word < (1 << 32));
+        assert_eq!(layout.unpack(word), values);
+        assert_eq!(layout.get(word, "b").unwrap(), -16);
+        assert_eq!(layout.get(word, 3).unwrap(), -1);
+        assert_eq!(layout.getu(word, "pb").unwrap(), 9);
+    }
+
+    #[test]
+    fn packu_unpacku_test() {
+        let layout = Layout::builder(64).unsigned("high", 32, 32).unsigned("low", 32, 0).build().unwrap();
+        let word = layout.packu(&[0xdead_beef, 0x1234_5678]).unwrap();
+        assert_eq!(word, 0xdead_beef_1234_5678);
+        assert_eq!(layout.unpacku(word), [0xdead_beef, 0x1234_5678]);
+    }
+
+    #[test]
+    fn full_width_test() {
+        let unsigned = Layout::builder(64).unsigned("x", 64, 0).build().unwrap();
+        assert_eq!(unsigned.packu(&[u64::MAX]).unwrap(), u64::MAX);
+        assert_eq!(unsigned.getu(u64::MAX, "x").unwrap(), u64::MAX);
+
+        let signed = Layout::builder(64).signed("x", 64, 0).build().unwrap();
+        assert_eq!(signed.pack(&[i64::MIN]).unwrap(), 1 << 63);
+        assert_eq!(signed.get(u64::MAX, 0).unwrap(), -1);
+    }
+
+    #[test]
+    fn set_replaces_test() {
+        let layout = rpeg_layout();
+        let word = layout.pack(&[511, -16, 15, -1, 15, 15]).unwrap();
+        let word = layout.set(word, "d", 6).unwrap();
+        let word = layout.setu(word, "pr", 0).unwrap();
+        assert_eq!(layout.unpack(word), [511, -16, 15, 6, 15, 0]);
+    }
+
+    #[test]
+    fn value_error_test() {
+        let layout = rpeg_layout();
+        assert_eq!(
+            layout.pack(&[512, 0, 0, 0, 0, 0]),
+            Err(LayoutError::ValueDoesNotFit { field: "a".to_string(), value: 512 })
+        );
+        assert_eq!(
+            layout.pack(&[-1, 0, 0, 0, 0, 0]),
+            Err(LayoutError::ValueDoesNotFit { field: "a".to_string(), value: -1 })
+        );
+        assert_eq!(
+            layout.set(0, "b", 16),
+            Err(LayoutError::ValueDoesNotFit { field: "b".to_string(), value: 16 })
+        );
+        assert_eq!(layout.pack(&[0, 0]), Err(LayoutError::WrongValueCount { expected: 6, actual: 2 }));
+        assert_eq!(layout.set(0, "e", 1), Err(LayoutError::UnknownField("e".to_string())));
+        assert_eq!(layout.get(0, 9), Err(LayoutError::UnknownField("#9".to_string())));
+    }
+}
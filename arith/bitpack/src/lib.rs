@@ -1 +1,7 @@
-pub mod bitpack;
\ No newline at end of file
+pub mod bitio;
+pub mod bitpack;
+pub mod codes;
+pub mod huffman;
+pub mod layout;
+pub mod v2;
+pub mod word;
@@ -0,0 +1,191 @@
+use std::fmt;
+
+/// Errors from the checked bitpack functions, here and in `word`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitpackError {
+    /// The field is wider than the word
+    WidthTooLarge { width: u64 },
+    /// The field reaches past the top of the word
+    FieldOutOfWord { width: u64, lsb: u64 },
+    /// The value is outside the range the field can hold
+    ValueDoesNotFit { value: i128, width: u64 },
+}
+
+impl fmt::Display for BitpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitpackError::WidthTooLarge { width } => write!(f, "a {} bit field is wider than the word", width),
+            BitpackError::FieldOutOfWord { width, lsb } => {
+                write!(f, "a {} bit field at bit {} reaches past the word", width, lsb)
+            }
+            BitpackError::ValueDoesNotFit { value, width } => write!(f, "{} does not fit in {} bits", value, width),
+        }
+    }
+}
+
+impl std::error::Error for BitpackError {}
+
+/// Returns whether the signed value `n` fits into `width` signed bits.
+/// A zero-width field holds only 0.
+///
+/// # Arguments:
+/// * `n`: A signed integer value
+/// * `width`: the width of a bit field, at most 64
+pub fn fitss(n: i64, width: u64) -> Result<bool, BitpackError> {
+    crate::word::fitss::<u64>(n, width)
+}
+
+/// Returns whether the unsigned value `n` fits into `width` unsigned bits.
+/// A zero-width field holds only 0.
+///
+/// # Arguments:
+/// * `n`: An unsigned integer value
+/// * `width`: the width of a bit field, at most 64
+pub fn fitsu(n: u64, width: u64) -> Result<bool, BitpackError> {
+    crate::word::fitsu(n, width)
+}
+
+/// Retrieve a signed value from `word`, represented by `width` bits
+/// beginning at least-significant bit `lsb`. A zero-width field reads as 0.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+pub fn gets(word: u64, width: u64, lsb: u64) -> Result<i64, BitpackError> {
+    crate::word::gets(word, width, lsb)
+}
+
+/// Retrieve an unsigned value from `word`, represented by `width` bits
+/// beginning at least-significant bit `lsb`. A zero-width field reads as 0.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+pub fn getu(word: u64, width: u64, lsb: u64) -> Result<u64, BitpackError> {
+    crate::word::getu(word, width, lsb)
+}
+
+/// Return a modified version of the unsigned `word`, in which the `width` bits
+/// beginning at least-significant bit `lsb` have been replaced by the unsigned `value`.
+/// Every other bit of `word` is left as it was.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+/// * `value`: the unsigned value to place into that bit field
+pub fn newu(word: u64, width: u64, lsb: u64, value: u64) -> Result<u64, BitpackError> {
+    crate::word::newu(word, width, lsb, value)
+}
+
+/// Return a modified version of the unsigned `word`, in which the `width` bits
+/// beginning at least-significant bit `lsb` have been replaced by the signed `value`.
+/// Every other bit of `word` is left as it was.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+/// * `value`: the signed value to place into that bit field
+pub fn news(word: u64, width: u64, lsb: u64, value: i64) -> Result<u64, BitpackError> {
+    crate::word::news(word, width, lsb, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every field that fits in a word, as (width, lsb)
+    fn every_field() -> impl Iterator<Item = (u64, u64)> {
+        (0..=64).flat_map(|width| (0..=64 - width).map(move |lsb| (width, lsb)))
+    }
+
+    /// The largest unsigned and the smallest and largest signed values of a `width` bit field
+    fn limits(width: u64) -> (u64, i64, i64) {
+        match width {
+            0 => (0, 0, 0),
+            64 => (u64::MAX, i64::MIN, i64::MAX),
+            _ => ((1 << width) - 1, -(1 << (width - 1)), (1 << (width - 1)) - 1),
+        }
+    }
+
+    #[test]
+    fn fits_boundary_test() {
+        for width in 0..=64 {
+            let (max_u, min_s, max_s) = limits(width);
+            assert_eq!(fitsu(max_u, width), Ok(true));
+            assert_eq!(fitss(min_s, width), Ok(true));
+            assert_eq!(fitss(max_s, width), Ok(true));
+            if width < 64 {
+                assert_eq!(fitsu(max_u + 1, width), Ok(false), "width {}", width);
+                assert_eq!(fitss(min_s - 1, width), Ok(false), "width {}", width);
+                assert_eq!(fitss(max_s + 1, width), Ok(false), "width {}", width);
+            }
+        }
+        assert_eq!(fitsu(0, 65), Err(BitpackError::WidthTooLarge { width: 65 }));
+        assert_eq!(fitss(0, 65), Err(BitpackError::WidthTooLarge { width: 65 }));
+    }
+
+    #[test]
+    fn unsigned_round_trip_test() {
+        for (width, lsb) in every_field() {
+            let (max_u, _, _) = limits(width);
+            for value in [0, 1 & max_u, max_u / 2, max_u] {
+                for word in [0, u64::MAX] {
+                    let packed = newu(word, width, lsb, value).unwrap();
+                    assert_eq!(getu(packed, width, lsb), Ok(value), "{} bits at {}", width, lsb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn signed_round_trip_test() {
+        for (width, lsb) in every_field() {
+            let (_, min_s, max_s) = limits(width);
+            for value in [min_s, (-1).max(min_s), 0, 1.min(max_s), max_s] {
+                for word in [0, u64::MAX] {
+                    let packed = news(word, width, lsb, value).unwrap();
+                    assert_eq!(gets(packed, width, lsb), Ok(value), "{} bits at {}", width, lsb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replace_keeps_other_bits_test() {
+        for (width, lsb) in every_field() {
+            let (max_u, min_s, _) = limits(width);
+            let outside = if width == 0 { u64::MAX } else { !((u64::MAX >> (64 - width)) << lsb) };
+
+            // Overwriting a field of ones with zero clears it and nothing else
+            assert_eq!(newu(u64::MAX, width, lsb, 0), Ok(outside));
+            assert_eq!(news(u64::MAX, width, lsb, 0), Ok(outside));
+
+            // Writing a field into an empty word sets only that field
+            assert_eq!(newu(0, width, lsb, max_u), Ok(!outside));
+            assert_eq!(news(0, width, lsb, (-1).max(min_s)), Ok(!outside));
+        }
+    }
+
+    #[test]
+    fn value_does_not_fit_test() {
+        assert_eq!(newu(0, 4, 0, 16), Err(BitpackError::ValueDoesNotFit { value: 16, width: 4 }));
+        assert_eq!(news(0, 4, 0, 8), Err(BitpackError::ValueDoesNotFit { value: 8, width: 4 }));
+        assert_eq!(news(0, 4, 0, -9), Err(BitpackError::ValueDoesNotFit { value: -9, width: 4 }));
+        assert_eq!(newu(0, 0, 64, 1), Err(BitpackError::ValueDoesNotFit { value: 1, width: 0 }));
+    }
+
+    #[test]
+    fn field_error_test() {
+        assert_eq!(getu(0, 65, 0), Err(BitpackError::WidthTooLarge { width: 65 }));
+        assert_eq!(gets(0, 65, 0), Err(BitpackError::WidthTooLarge { width: 65 }));
+        assert_eq!(newu(0, 65, 0, 0), Err(BitpackError::WidthTooLarge { width: 65 }));
+        assert_eq!(getu(0, 9, 56), Err(BitpackError::FieldOutOfWord { width: 9, lsb: 56 }));
+        assert_eq!(gets(0, 1, 64), Err(BitpackError::FieldOutOfWord { width: 1, lsb: 64 }));
+        assert_eq!(news(0, 64, 1, 0), Err(BitpackError::FieldOutOfWord { width: 64, lsb: 1 }));
+        assert_eq!(getu(u64::MAX, 0, 64), Ok(0));
+    }
+}
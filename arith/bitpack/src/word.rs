@@ -0,0 +1,277 @@
+use std::fmt;
+use std::ops::{BitAnd, BitOr, Not, Shl, Shr};
+use crate::v2::BitpackError;
+
+/// An unsigned primitive integer that bit fields can be packed into
+pub trait Word:
+    Copy
+    + Eq
+    + fmt::Debug
+    + Not<Output = Self>
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + Shl<u32, Output = Self>
+    + Shr<u32, Output = Self>
+{
+    /// The signed integer of the same size, which signed fields are read as
+    type Signed: Copy + Eq + fmt::Debug + Shr<u32, Output = Self::Signed>;
+
+    /// Number of bits in the word
+    const BITS: u64;
+    const ZERO: Self;
+    const MAX: Self;
+
+    /// Reinterprets the bits of the word as a signed integer
+    fn to_signed(self) -> Self::Signed;
+
+    /// Reinterprets the bits of a signed integer as a word
+    fn from_signed(value: Self::Signed) -> Self;
+
+    /// The word as an `i128` for error reports, saturating for `u128` values above `i128::MAX`
+    fn to_i128(self) -> i128;
+
+    /// A signed value as an `i128` for error reports
+    fn signed_to_i128(value: Self::Signed) -> i128;
+}
+
+macro_rules! impl_word {
+    ($($word:ty => $signed:ty),*) => {
+        $(
+            impl Word for $word {
+                type Signed = $signed;
+
+                const BITS: u64 = <$word>::BITS as u64;
+                const ZERO: Self = 0;
+                const MAX: Self = <$word>::MAX;
+
+                fn to_signed(self) -> $signed {
+                    self as $signed
+                }
+
+                fn from_signed(value: $signed) -> Self {
+                    value as $word
+                }
+
+                fn to_i128(self) -> i128 {
+                    i128::try_from(self).unwrap_or(i128::MAX)
+                }
+
+                fn signed_to_i128(value: $signed) -> i128 {
+                    value as i128
+                }
+            }
+        )*
+    };
+}
+
+impl_word!(u8 => i8, u16 => i16, u32 => i32, u64 => i64, u128 => i128);
+
+/// The low `width` bits set, for a width of 0 to `W::BITS`
+fn mask<W: Word>(width: u64) -> W {
+    if width == 0 {
+        W::ZERO
+    } else {
+        W::MAX >> (W::BITS - width) as u32
+    }
+}
+
+/// Checks that a `width` bit field at least-significant bit `lsb` lies within a `W`
+fn check_field<W: Word>(width: u64, lsb: u64) -> Result<(), BitpackError> {
+    if width > W::BITS {
+        return Err(BitpackError::WidthTooLarge { width });
+    }
+    if lsb > W::BITS - width {
+        return Err(BitpackError::FieldOutOfWord { width, lsb });
+    }
+    Ok(())
+}
+
+/// Returns whether the signed value `n` fits into `width` signed bits of a `W`.
+/// A zero-width field holds only 0.
+///
+/// # Arguments:
+/// * `n`: A signed integer value
+/// * `width`: the width of a bit field, at most `W::BITS`
+pub fn fitss<W: Word>(n: W::Signed, width: u64) -> Result<bool, BitpackError> {
+    if width > W::BITS {
+        return Err(BitpackError::WidthTooLarge { width });
+    }
+    if width == 0 {
+        return Ok(n == W::ZERO.to_signed());
+    }
+
+    // Every bit from the field's sign bit up must match it
+    let top = n >> (width - 1) as u32;
+    Ok(top == W::ZERO.to_signed() || top == W::MAX.to_signed())
+}
+
+/// Returns whether the unsigned value `n` fits into `width` unsigned bits of a `W`.
+/// A zero-width field holds only 0.
+///
+/// # Arguments:
+/// * `n`: An unsigned integer value
+/// * `width`: the width of a bit field, at most `W::BITS`
+pub fn fitsu<W: Word>(n: W, width: u64) -> Result<bool, BitpackError> {
+    if width > W::BITS {
+        return Err(BitpackError::WidthTooLarge { width });
+    }
+    Ok(width == W::BITS || n >> width as u32 == W::ZERO)
+}
+
+/// Retrieve a signed value from `word`, represented by `width` bits
+/// beginning at least-significant bit `lsb`. A zero-width field reads as 0.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+pub fn gets<W: Word>(word: W, width: u64, lsb: u64) -> Result<W::Signed, BitpackError> {
+    check_field::<W>(width, lsb)?;
+    if width == 0 {
+        return Ok(W::ZERO.to_signed());
+    }
+    Ok((word << (W::BITS - width - lsb) as u32).to_signed() >> (W::BITS - width) as u32)
+}
+
+/// Retrieve an unsigned value from `word`, represented by `width` bits
+/// beginning at least-significant bit `lsb`. A zero-width field reads as 0.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+pub fn getu<W: Word>(word: W, width: u64, lsb: u64) -> Result<W, BitpackError> {
+    check_field::<W>(width, lsb)?;
+    if width == 0 {
+        return Ok(W::ZERO);
+    }
+    Ok((word >> lsb as u32) & mask(width))
+}
+
+/// Return a modified version of `word`, in which the `width` bits beginning at
+/// least-significant bit `lsb` have been replaced by the unsigned `value`.
+/// Every other bit of `word` is left as it was.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+/// * `value`: the unsigned value to place into that bit field
+pub fn newu<W: Word>(word: W, width: u64, lsb: u64, value: W) -> Result<W, BitpackError> {
+    check_field::<W>(width, lsb)?;
+    if !fitsu(value, width)? {
+        return Err(BitpackError::ValueDoesNotFit { value: value.to_i128(), width });
+    }
+    if width == 0 {
+        return Ok(word);
+    }
+    let field = mask::<W>(width) << lsb as u32;
+    Ok((word & !field) | (value << lsb as u32))
+}
+
+/// Return a modified version of `word`, in which the `width` bits beginning at
+/// least-significant bit `lsb` have been replaced by the signed `value`.
+/// Every other bit of `word` is left as it was.
+///
+/// # Arguments:
+/// * `word`: An unsigned word
+/// * `width`: the width of a bit field
+/// * `lsb`: the least-significant bit of the bit field
+/// * `value`: the signed value to place into that bit field
+pub fn news<W: Word>(word: W, width: u64, lsb: u64, value: W::Signed) -> Result<W, BitpackError> {
+    check_field::<W>(width, lsb)?;
+    if !fitss::<W>(value, width)? {
+        return Err(BitpackError::ValueDoesNotFit { value: W::signed_to_i128(value), width });
+    }
+    if width == 0 {
+        return Ok(word);
+    }
+    let field = mask::<W>(width) << lsb as u32;
+    Ok((word & !field) | ((W::from_signed(value) & mask(width)) << lsb as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs the extremes of every field that fits in a `W` into words of all zeros and
+    /// all ones, and checks that they read back and leave the rest of the word alone
+    fn check_every_field<W: Word>() {
+        for width in 0..=W::BITS {
+            let max_u = mask::<W>(width);
+            let (min_s, max_s) = if width == 0 {
+                (W::ZERO.to_signed(), W::ZERO.to_signed())
+            } else {
+                ((!mask::<W>(width - 1)).to_signed(), mask::<W>(width - 1).to_signed())
+            };
+            assert_eq!(fitsu(max_u, width), Ok(true));
+            assert_eq!(fitss::<W>(min_s, width), Ok(true));
+            assert_eq!(fitss::<W>(max_s, width), Ok(true));
+            if width < W::BITS {
+                let past_max_u = mask::<W>(width + 1) & !max_u;
+                assert_eq!(fitsu(past_max_u, width), Ok(false), "width {}", width);
+            }
+            if width > 0 && width < W::BITS {
+                let past_max_s = mask::<W>(width) & !mask::<W>(width - 1);
+                assert_eq!(fitss::<W>(past_max_s.to_signed(), width), Ok(false), "width {}", width);
+                assert_eq!(fitss::<W>((!past_max_s).to_signed(), width), Ok(false), "width {}", width);
+            }
+
+            for lsb in 0..=W::BITS - width {
+                let outside = if width == 0 { W::MAX } else { !(max_u << lsb as u32) };
+                for word in [W::ZERO, W::MAX] {
+                    let packed = newu(word, width, lsb, max_u).unwrap();
+                    assert_eq!(getu(packed, width, lsb), Ok(max_u));
+                    assert_eq!(packed & outside, word & outside);
+
+                    for value in [min_s, max_s] {
+                        let packed = news(word, width, lsb, value).unwrap();
+                        assert_eq!(gets(packed, width, lsb), Ok(value), "{} bits at {}", width, lsb);
+                        assert_eq!(packed & outside, word & outside);
+                    }
+                }
+            }
+        }
+        assert_eq!(fitsu(W::ZERO, W::BITS + 1), Err(BitpackError::WidthTooLarge { width: W::BITS + 1 }));
+        assert_eq!(getu(W::ZERO, 1, W::BITS), Err(BitpackError::FieldOutOfWord { width: 1, lsb: W::BITS }));
+    }
+
+    #[test]
+    fn u8_test() {
+        check_every_field::<u8>();
+    }
+
+    #[test]
+    fn u16_test() {
+        check_every_field::<u16>();
+    }
+
+    #[test]
+    fn u32_test() {
+        check_every_field::<u32>();
+    }
+
+    #[test]
+    fn u64_test() {
+        check_every_field::<u64>();
+    }
+
+    #[test]
+    fn u128_test() {
+        check_every_field::<u128>();
+    }
+
+    #[test]
+    fn narrow_word_test() {
+        assert_eq!(newu(0xff_u8, 4, 2, 0), Ok(0b1100_0011));
+        assert_eq!(news(0_u16, 5, 11, -1), Ok(0xf800));
+        assert_eq!(gets(0xf800_u16, 5, 11), Ok(-1));
+        assert_eq!(getu(0x8000_0000_0000_0000_0000_0000_0000_0000_u128, 1, 127), Ok(1));
+        assert_eq!(newu(0_u32, 9, 24, 0), Err(BitpackError::FieldOutOfWord { width: 9, lsb: 24 }));
+        assert_eq!(news(0_u8, 4, 0, 8), Err(BitpackError::ValueDoesNotFit { value: 8, width: 4 }));
+        assert_eq!(
+            newu(0_u128, 4, 0, u128::MAX),
+            Err(BitpackError::ValueDoesNotFit { value: i128::MAX, width: 4 })
+        );
+    }
+}
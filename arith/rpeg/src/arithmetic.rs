@@ -0,0 +1,406 @@
+use crate::error::RpegError;
+use crate::transform::{Coefficient, CoefficientKind};
+
+// Documentation:
+// A binary range coder in the style of LZMA: each bit is coded against an 11-bit probability
+// that it is 0, and the probability moves 1/32 of the way towards every bit it sees, so the
+// model follows the statistics of the image as it goes.
+//
+// Each coefficient position of a block (a, b, c, d, every chroma sample) has its own models.
+// Unsigned values are coded most significant bit first down a binary tree, so the model learns
+// their whole distribution for the first TREE_BITS bits and one probability per bit after that.
+// Signed values are coded as a zero flag, a sign and an Exp-Golomb binarization of the
+// magnitude whose prefix and suffix bits each have their own probabilities. Signed models come
+// in three sets, picked by the size of the same coefficient in the previous block, since
+// detail in one block tends to continue into the next. Counts are coded like signed values
+// with no sign, in a single set.
+
+/// Bits of precision in a probability
+const PROBABILITY_BITS: u32 = 11;
+
+/// A probability of one half
+const HALF: u16 = 1 << (PROBABILITY_BITS - 1);
+
+/// How quickly probabilities adapt; each bit moves them 1/2^ADAPT_SHIFT of the way
+const ADAPT_SHIFT: u32 = 5;
+
+/// The range is renormalized whenever it falls below this
+const TOP: u32 = 1 << 24;
+
+/// Leading bits of an unsigned value modelled as a tree
+const TREE_BITS: u64 = 8;
+
+/// Longest Exp-Golomb prefix a 64-bit magnitude needs
+const MAX_PREFIX: usize = 64;
+
+/// Writes bits coded against adaptive probabilities into a byte buffer
+struct Encoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    output: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Encoder { low: 0, range: u32::MAX, cache: 0, cache_size: 1, output: Vec::new() }
+    }
+
+    fn encode(&mut self, probability: &mut u16, bit: bool) {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> ADAPT_SHIFT;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    /// Moves the top byte of `low` out, holding back runs of 0xFF until a carry is ruled out
+    fn shift_low(&mut self) {
+        if self.low < 0xFF00_0000 || self.low > u32::MAX as u64 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.cache_size > 0 {
+                self.output.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        return self.output;
+    }
+}
+
+/// Reads bits written by `Encoder`, given the same sequence of probabilities
+struct Decoder<'a> {
+    input: &'a [u8],
+    code: u32,
+    range: u32,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(mut input: &'a [u8]) -> Result<Self, RpegError> {
+        // The encoder's first byte is always the empty cache
+        let mut code = 0;
+        for _ in 0..5 {
+            let (&byte, rest) = input.split_first().ok_or(RpegError::Truncated)?;
+            code = (code << 8) | byte as u32;
+            input = rest;
+        }
+        return Ok(Decoder { input, code, range: u32::MAX });
+    }
+
+    fn decode(&mut self, probability: &mut u16) -> Result<bool, RpegError> {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> ADAPT_SHIFT;
+        }
+        while self.range < TOP {
+            let (&byte, rest) = self.input.split_first().ok_or(RpegError::Truncated)?;
+            self.input = rest;
+            self.range <<= 8;
+            self.code = (self.code << 8) | byte as u32;
+        }
+        return Ok(bit);
+    }
+}
+
+/// Probabilities for an unsigned value of a fixed number of bits
+struct UnsignedModel {
+    bits: u64,
+    /// One probability per node of the tree over the leading bits
+    tree: Vec<u16>,
+    /// One probability per bit after the tree
+    tail: Vec<u16>,
+}
+
+impl UnsignedModel {
+    fn new(bits: u64) -> Self {
+        let tree_bits = bits.min(TREE_BITS);
+        UnsignedModel { bits, tree: vec![HALF; 1 << tree_bits], tail: vec![HALF; (bits - tree_bits) as usize] }
+    }
+
+    /// The tree node and tail index for bit `i` (counting from the top) after `prefix` has been coded
+    fn probability(&mut self, i: u64, prefix: u64) -> &mut u16 {
+        if i < TREE_BITS {
+            &mut self.tree[((1 << i) | prefix) as usize]
+        } else {
+            &mut self.tail[(i - TREE_BITS) as usize]
+        }
+    }
+
+    fn encode(&mut self, encoder: &mut Encoder, value: u64) {
+        for i in 0..self.bits {
+            let bit = (value >> (self.bits - 1 - i)) & 1 == 1;
+            let prefix = if i == 0 { 0 } else { value >> (self.bits - i) };
+            encoder.encode(self.probability(i, prefix), bit);
+        }
+    }
+
+    fn decode(&mut self, decoder: &mut Decoder) -> Result<u64, RpegError> {
+        let mut value = 0_u64;
+        for i in 0..self.bits {
+            let bit = decoder.decode(self.probability(i, value))?;
+            value = (value << 1) | bit as u64;
+        }
+        return Ok(value);
+    }
+}
+
+/// Probabilities for a signed value of any size
+struct SignedModel {
+    zero: u16,
+    sign: u16,
+    /// One probability per bit of the unary prefix
+    prefix: Vec<u16>,
+    /// One probability per bit position of the suffix
+    suffix: Vec<u16>,
+}
+
+impl SignedModel {
+    fn new() -> Self {
+        SignedModel { zero: HALF, sign: HALF, prefix: vec![HALF; MAX_PREFIX + 1], suffix: vec![HALF; MAX_PREFIX] }
+    }
+
+    fn encode(&mut self, encoder: &mut Encoder, value: i64) {
+        encoder.encode(&mut self.zero, value != 0);
+        if value == 0 {
+            return;
+        }
+        encoder.encode(&mut self.sign, value < 0);
+        self.encode_magnitude(encoder, value.unsigned_abs());
+    }
+
+    /// Codes a value that is never negative, leaving out the sign
+    fn encode_count(&mut self, encoder: &mut Encoder, value: u64) {
+        encoder.encode(&mut self.zero, value != 0);
+        if value != 0 {
+            self.encode_magnitude(encoder, value);
+        }
+    }
+
+    fn encode_magnitude(&mut self, encoder: &mut Encoder, magnitude: u64) {
+        // The magnitude is at least 1, so its bit length less one is written in unary, then
+        // the bits below its top bit
+        let length = (64 - magnitude.leading_zeros()) as usize;
+        for i in 0..length {
+            encoder.encode(&mut self.prefix[i], i + 1 < length);
+        }
+        for i in (0..length - 1).rev() {
+            encoder.encode(&mut self.suffix[i], (magnitude >> i) & 1 == 1);
+        }
+    }
+
+    fn decode(&mut self, decoder: &mut Decoder) -> Result<i64, RpegError> {
+        if !decoder.decode(&mut self.zero)? {
+            return Ok(0);
+        }
+        let negative = decoder.decode(&mut self.sign)?;
+
+        // Wrapping keeps i64::MIN, whose magnitude does not fit in an i64
+        let value = self.decode_magnitude(decoder)? as i64;
+        return Ok(if negative { value.wrapping_neg() } else { value });
+    }
+
+    /// Reverses `encode_count`
+    fn decode_count(&mut self, decoder: &mut Decoder) -> Result<u64, RpegError> {
+        if !decoder.decode(&mut self.zero)? {
+            return Ok(0);
+        }
+        return self.decode_magnitude(decoder);
+    }
+
+    fn decode_magnitude(&mut self, decoder: &mut Decoder) -> Result<u64, RpegError> {
+        let mut length = 1;
+        while decoder.decode(&mut self.prefix[length - 1])? {
+            length += 1;
+            if length > MAX_PREFIX {
+                return Err(RpegError::InvalidHeader("corrupt arithmetic-coded payload".to_string()));
+            }
+        }
+        let mut magnitude = 1_u64;
+        for i in (0..length - 1).rev() {
+            magnitude = (magnitude << 1) | decoder.decode(&mut self.suffix[i])? as u64;
+        }
+        return Ok(magnitude);
+    }
+}
+
+/// The models for one coefficient position of a block
+enum Model {
+    Unsigned(UnsignedModel),
+    /// Picked by how large the coefficient was in the previous block: 0, 1 or more
+    Signed([SignedModel; 3], i64),
+    Count(SignedModel),
+}
+
+impl Model {
+    fn new(coefficient: &Coefficient) -> Self {
+        match coefficient.kind {
+            CoefficientKind::Unsigned => Model::Unsigned(UnsignedModel::new(coefficient.bits)),
+            CoefficientKind::Signed => Model::Signed([SignedModel::new(), SignedModel::new(), SignedModel::new()], 0),
+            CoefficientKind::Count => Model::Count(SignedModel::new()),
+        }
+    }
+}
+
+/// Which of the three signed models follows a previous value of `previous`
+fn context(previous: i64) -> usize {
+    previous.unsigned_abs().min(2) as usize
+}
+
+/// Writes every block's coefficients with the adaptive arithmetic coder
+///
+/// # Arguments:
+/// * `blocks`: the coefficients of each block
+/// * `layout`: describes each coefficient of a block, as the transform's `layout` returns
+pub fn write_arithmetic(blocks: &[Vec<i64>], layout: &[Coefficient]) -> Result<Vec<u8>, RpegError> {
+    let mut models: Vec<Model> = layout.iter().map(Model::new).collect();
+    let mut encoder = Encoder::new();
+    for block in blocks {
+        if block.len() != layout.len() {
+            return Err(RpegError::DimensionMismatch { expected: layout.len(), actual: block.len() });
+        }
+        for ((&value, model), coefficient) in block.iter().zip(models.iter_mut()).zip(layout) {
+            if !matches!(model, Model::Signed(..)) && !coefficient.holds(value) {
+                return Err(RpegError::FieldOverflow { field: coefficient.name, value });
+            }
+            match model {
+                Model::Unsigned(unsigned) => unsigned.encode(&mut encoder, value as u64),
+                Model::Signed(signed, previous) => {
+                    signed[context(*previous)].encode(&mut encoder, value);
+                    *previous = value;
+                }
+                Model::Count(count) => count.encode_count(&mut encoder, value as u64),
+            }
+        }
+    }
+    return Ok(encoder.finish());
+}
+
+/// Reads `count` blocks written by `write_arithmetic` with the same `layout`
+pub fn read_arithmetic(payload: &[u8], layout: &[Coefficient], count: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+    let mut models: Vec<Model> = layout.iter().map(Model::new).collect();
+    let mut decoder = Decoder::new(payload)?;
+
+    // Likely bits can cost far less than a bit each, so the payload's size does not bound the
+    // count; blocks are only kept as they are decoded, and the decoder runs out of bytes first
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        let mut block = Vec::with_capacity(layout.len());
+        for (model, coefficient) in models.iter_mut().zip(layout) {
+            let value = match model {
+                Model::Unsigned(unsigned) => unsigned.decode(&mut decoder)? as i64,
+                Model::Signed(signed, previous) => {
+                    *previous = signed[context(*previous)].decode(&mut decoder)?;
+                    *previous
+                }
+                Model::Count(count) => {
+                    // A magnitude of up to 64 bits can stand for a count too large to be one
+                    let value = count.decode_count(&mut decoder)? as i64;
+                    if !coefficient.holds(value) {
+                        return Err(RpegError::FieldOverflow { field: coefficient.name, value });
+                    }
+                    value
+                }
+            };
+            block.push(value);
+        }
+        blocks.push(block);
+    }
+    return Ok(blocks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Vec<Coefficient> {
+        vec![
+            Coefficient { name: "a", kind: CoefficientKind::Unsigned, bits: 9 },
+            Coefficient { name: "b", kind: CoefficientKind::Signed, bits: 6 },
+            Coefficient { name: "wide", kind: CoefficientKind::Unsigned, bits: 64 },
+            Coefficient { name: "any", kind: CoefficientKind::Signed, bits: 64 },
+        ]
+    }
+
+    /// Blocks that mix small, repeated values with the extremes of every coefficient
+    fn blocks(count: usize) -> Vec<Vec<i64>> {
+        let mut state = 0x2545_f491_u64;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            state >> 33
+        };
+        let mut blocks: Vec<Vec<i64>> = (0..count)
+            .map(|_| vec![(next() % 512) as i64, (next() % 7) as i64 - 3, next() as i64, (next() % 5) as i64 - 2])
+            .collect();
+        blocks.push(vec![511, i64::MIN, -1, i64::MIN]);
+        blocks.push(vec![0, i64::MAX, i64::MAX, i64::MAX]);
+        blocks.push(vec![0, 0, 0, 0]);
+        blocks
+    }
+
+    #[test]
+    fn round_trip_test() {
+        for count in [0, 1, 100] {
+            let blocks = blocks(count);
+            let payload = write_arithmetic(&blocks, &layout()).unwrap();
+            assert_eq!(read_arithmetic(&payload, &layout(), blocks.len()).unwrap(), blocks);
+        }
+        let payload = write_arithmetic(&[], &layout()).unwrap();
+        assert!(read_arithmetic(&payload, &layout(), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn adaptive_test() {
+        // Once the models have learned them, identical blocks of 75 coded bits take well
+        // under a byte each
+        let blocks = vec![vec![17, 0, 3, 0]; 10_000];
+        let payload = write_arithmetic(&blocks, &layout()).unwrap();
+        assert!(payload.len() < blocks.len() / 2, "{} bytes", payload.len());
+        assert_eq!(read_arithmetic(&payload, &layout(), blocks.len()).unwrap(), blocks);
+    }
+
+    #[test]
+    fn truncated_test() {
+        let blocks = blocks(100);
+        let payload = write_arithmetic(&blocks, &layout()).unwrap();
+        for length in 0..5 {
+            assert!(matches!(read_arithmetic(&payload[..length], &layout(), 1), Err(RpegError::Truncated)));
+        }
+        assert!(matches!(read_arithmetic(&payload[..payload.len() / 2], &layout(), blocks.len()), Err(RpegError::Truncated)));
+
+        // More blocks than were written run out of bytes rather than decoding forever
+        assert!(matches!(read_arithmetic(&payload, &layout(), usize::MAX), Err(RpegError::Truncated)));
+    }
+
+    #[test]
+    fn overflow_test() {
+        let write = |block: Vec<i64>| write_arithmetic(&[block], &layout());
+        assert!(matches!(write(vec![512, 0, 0, 0]), Err(RpegError::FieldOverflow { field: "a", value: 512 })));
+        assert!(matches!(write(vec![-1, 0, 0, 0]), Err(RpegError::FieldOverflow { field: "a", value: -1 })));
+        assert!(matches!(write(vec![0, 0, 0]), Err(RpegError::DimensionMismatch { expected: 4, actual: 3 })));
+    }
+}
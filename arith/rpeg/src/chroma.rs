@@ -0,0 +1,202 @@
+use crate::compress_decompress::Ypbpr;
+use crate::error::RpegError;
+
+// Documentation:
+// The J:a:b names count chroma samples in a 4 pixel wide, 2 pixel tall region: 4:4:4 keeps
+// every sample, 4:2:2 averages horizontal pairs and 4:2:0 averages 2x2 squares.
+
+/// How much Pb and Pr are reduced relative to Y before they are coded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ChromaSampling {
+    /// Full resolution chroma
+    Yuv444,
+    /// Half horizontal resolution
+    Yuv422,
+    /// Half horizontal and half vertical resolution, as the original assignment did
+    #[default]
+    Yuv420,
+}
+
+/// How the decoder brings reduced chroma back up to full resolution
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ChromaUpsampling {
+    /// Copy each sample to every pixel it was averaged from, which is bit-exact with older decoders
+    #[default]
+    Replicate,
+    /// Interpolate between the centres of neighbouring samples, which hides the steps at block edges
+    Bilinear,
+}
+
+impl ChromaSampling {
+    /// Every mode, in the order of their ids
+    pub const ALL: [ChromaSampling; 3] = [ChromaSampling::Yuv444, ChromaSampling::Yuv422, ChromaSampling::Yuv420];
+
+    /// Value recorded in the stream for this mode
+    pub fn id(&self) -> u8 {
+        match self {
+            ChromaSampling::Yuv444 => 0,
+            ChromaSampling::Yuv422 => 1,
+            ChromaSampling::Yuv420 => 2,
+        }
+    }
+
+    /// Parses the value written by `id`
+    pub fn from_id(id: u8) -> Result<Self, RpegError> {
+        return ChromaSampling::ALL.get(id as usize).copied()
+            .ok_or(RpegError::InvalidHeader(format!("unknown chroma subsampling {}", id)));
+    }
+
+    /// Looks up a mode by its J:a:b name, with or without the colons ("420" or "4:2:0")
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name.replace(':', "").as_str() {
+            "444" => Some(ChromaSampling::Yuv444),
+            "422" => Some(ChromaSampling::Yuv422),
+            "420" => Some(ChromaSampling::Yuv420),
+            _ => None,
+        }
+    }
+
+    /// Width and height of the pixel area that shares one chroma sample
+    pub fn factors(&self) -> (usize, usize) {
+        match self {
+            ChromaSampling::Yuv444 => (1, 1),
+            ChromaSampling::Yuv422 => (2, 1),
+            ChromaSampling::Yuv420 => (2, 2),
+        }
+    }
+
+    /// Size of the chroma planes for a `width` x `height` image
+    pub fn plane_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let (horizontal, vertical) = self.factors();
+        return (width.div_ceil(horizontal), height.div_ceil(vertical));
+    }
+
+    /// Averages one channel of a row-major `width` x `height` image down to this mode's
+    /// resolution, repeating the last row and column when a dimension does not divide evenly
+    ///
+    /// # Arguments:
+    /// * `pixels`: the image (or block) to reduce
+    /// * `width`: the width of `pixels`
+    /// * `height`: the height of `pixels`
+    /// * `channel`: picks the value to average out of each pixel
+    pub fn downsample(&self, pixels: &[Ypbpr], width: usize, height: usize, channel: impl Fn(&Ypbpr) -> f32) -> Vec<f32> {
+        let (horizontal, vertical) = self.factors();
+        let (plane_width, plane_height) = self.plane_size(width, height);
+
+        let mut samples = Vec::with_capacity(plane_width * plane_height);
+        for y in (0..height).step_by(vertical) {
+            for x in (0..width).step_by(horizontal) {
+                let mut sum = 0.0;
+                for row in y..y + vertical {
+                    for col in x..x + horizontal {
+                        sum += channel(&pixels[(row.min(height - 1) * width) + col.min(width - 1)]);
+                    }
+                }
+                samples.push(sum / (horizontal * vertical) as f32);
+            }
+        }
+        return samples;
+    }
+
+    /// Number of chroma samples (per channel) in a `block_size` x `block_size` block
+    pub fn block_samples(&self, block_size: usize) -> usize {
+        let (plane_width, plane_height) = self.plane_size(block_size, block_size);
+        return plane_width * plane_height;
+    }
+
+    /// Index of the chroma sample that covers the pixel at column `x`, row `y`
+    ///
+    /// # Arguments:
+    /// * `x`: the pixel's column
+    /// * `y`: the pixel's row
+    /// * `plane_width`: the width of the chroma plane, from `plane_size`
+    pub fn sample_index(&self, x: usize, y: usize, plane_width: usize) -> usize {
+        let (horizontal, vertical) = self.factors();
+        return ((y / vertical) * plane_width) + (x / horizontal);
+    }
+
+    /// Replaces the replicated chroma of a decoded row-major image with a bilinear
+    /// interpolation of the samples it was built from
+    ///
+    /// # Arguments:
+    /// * `image`: a decoded image whose chroma is still constant over each sample's area
+    /// * `width`: the width of the image
+    /// * `height`: the height of the image
+    pub fn interpolate(&self, image: &mut [Ypbpr], width: usize, height: usize) {
+        if *self == ChromaSampling::Yuv444 || image.is_empty() {
+            return;
+        }
+
+        // Every pixel in a sample's area holds that sample, so averaging gives it back exactly
+        let (plane_width, plane_height) = self.plane_size(width, height);
+        let pb = self.downsample(image, width, height, |pixel| pixel.pb);
+        let pr = self.downsample(image, width, height, |pixel| pixel.pr);
+
+        let (horizontal, vertical) = self.factors();
+        let columns: Vec<_> = (0..width).map(|x| neighbours(x, horizontal, plane_width)).collect();
+        for y in 0..height {
+            let (top, bottom, down) = neighbours(y, vertical, plane_height);
+            for (x, &(left, right, across)) in columns.iter().enumerate() {
+                let blend = |plane: &[f32]| {
+                    let upper = plane[(top * plane_width) + left] * (1.0 - across) + plane[(top * plane_width) + right] * across;
+                    let lower = plane[(bottom * plane_width) + left] * (1.0 - across) + plane[(bottom * plane_width) + right] * across;
+                    upper * (1.0 - down) + lower * down
+                };
+                let pixel = &mut image[(y * width) + x];
+                pixel.pb = blend(&pb);
+                pixel.pr = blend(&pr);
+            }
+        }
+    }
+}
+
+/// Finds the two samples on either side of pixel `position` along one axis, and how far
+/// between them it lies. Samples sit at the centre of the `factor` pixels they cover, and
+/// pixels beyond the first or last centre take that sample alone
+fn neighbours(position: usize, factor: usize, samples: usize) -> (usize, usize, f32) {
+    let coordinate = ((position as f32 + 0.5) / factor as f32) - 0.5;
+    if coordinate <= 0.0 {
+        return (0, 0, 0.0);
+    }
+    let before = (coordinate.floor() as usize).min(samples - 1);
+    let after = (before + 1).min(samples - 1);
+    return (before, after, coordinate - before as f32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsample_test() {
+        // A 3x3 image: odd sizes repeat the last row and column
+        let pixels: Vec<Ypbpr> = (0..9).map(|i| Ypbpr { y: 0.0, pb: i as f32, pr: 0.0 }).collect();
+        let pb = |pixel: &Ypbpr| pixel.pb;
+        assert_eq!(ChromaSampling::Yuv444.downsample(&pixels, 3, 3, pb), [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        assert_eq!(ChromaSampling::Yuv422.downsample(&pixels, 3, 3, pb), [0.5, 2.0, 3.5, 5.0, 6.5, 8.0]);
+        assert_eq!(ChromaSampling::Yuv420.downsample(&pixels, 3, 3, pb), [2.0, 3.5, 6.5, 8.0]);
+        assert_eq!(ChromaSampling::Yuv420.plane_size(3, 3), (2, 2));
+        assert_eq!(ChromaSampling::Yuv420.sample_index(2, 1, 2), 1);
+    }
+
+    #[test]
+    fn names_test() {
+        for sampling in ChromaSampling::ALL {
+            assert_eq!(ChromaSampling::from_id(sampling.id()).unwrap(), sampling);
+        }
+        assert_eq!(ChromaSampling::by_name("4:2:2"), Some(ChromaSampling::Yuv422));
+        assert_eq!(ChromaSampling::by_name("444"), Some(ChromaSampling::Yuv444));
+        assert_eq!(ChromaSampling::by_name("411"), None);
+        assert!(matches!(ChromaSampling::from_id(3), Err(RpegError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn interpolate_test() {
+        // A flat plane stays flat, and a ramp between two samples is filled in between them
+        let mut image: Vec<Ypbpr> = (0..8).map(|i| Ypbpr { y: 0.0, pb: if i % 4 < 2 { 0.0 } else { 1.0 }, pr: 0.25 }).collect();
+        ChromaSampling::Yuv422.interpolate(&mut image, 4, 2);
+        let pb: Vec<f32> = image[..4].iter().map(|pixel| pixel.pb).collect();
+        assert_eq!(pb, [0.0, 0.25, 0.75, 1.0]);
+        assert!(image.iter().all(|pixel| pixel.pr == 0.25));
+    }
+}
@@ -1,108 +1,619 @@
-use csc411_image::{Read, RgbImage};
-use csc411_rpegio::{output_rpeg_data, input_rpeg_data};
-use crate::compress_decompress::{prepare_ppm, convert_rgb_to_rgb_float, convert_rgb_float_to_component_video, pack_as_32_bit, convert_rgb_float_to_rgb, convert_component_video_to_rgb_float, unpack_to_pixel_values};
-use crate::transform::{discrete_cosine_transfer, inverse_discrete_cosine_transfer};
-use crate::compress_decompress::Ypbpr;
-use crate::compress_decompress::PixelBlockValues;
-use csc411_image::Write;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use csc411_image::RgbImage;
+use crate::compress_decompress::{prepare_ppm, crop_rgb, collect_block, place_block, Padding, convert_rgb_to_rgb_float, convert_rgb_float_to_component_video, convert_rgb_float_to_rgb, convert_component_video_to_rgb_float};
+use crate::chroma::{ChromaSampling, ChromaUpsampling};
+use crate::transform::{from_parameters, BlockTransform, Depth, Haar2x2, QuantParams};
+use crate::compress_decompress::{Ypbpr, WordLayout};
+use crate::deblock::Deblock;
+use crate::entropy::Coding;
+use crate::prediction::Prediction;
+use crate::container::{write_compressed, read_compressed, CompressedImage, ContainerFormat, Header};
+use crate::error::RpegError;
+use crate::lossless::Lossless;
+use crate::ppm::{read_ppm, write_ppm};
+use crate::wavelet::Wavelet;
 
-pub fn compress(filename: Option<&str>) {
+/// Options that control how an image is encoded
+#[derive(Clone, Debug)]
+pub struct EncodeOptions {
+    /// Quality from 1 (smallest values) to 100 (the original assignment's quantizer). With
+    /// `Fixed` coding every block still takes a whole word, so lower qualities only make the
+    /// file smaller with one of the variable-length codings
+    pub quality: u8,
+    /// Explicit quantizer settings for the 2x2 transform, which take precedence over `quality` when set
+    pub quantization: Option<QuantParams>,
+    /// Which file layout the compressed image is written in
+    pub container: ContainerFormat,
+    /// How widths and heights are filled out to whole blocks
+    pub padding: Padding,
+    /// Chroma subsampling for the 2x2 transform built from `quality` or `quantization`
+    pub chroma: ChromaSampling,
+    /// Word depth for the 2x2 transform built from `quality`; `Deep` keeps 16-bit precision
+    pub depth: Depth,
+    /// Custom word layout for the 2x2 transform, in place of the one `depth` implies
+    pub layout: Option<WordLayout>,
+    /// How the 2x2 transform writes its coefficients; the other transforms only take `Fixed`
+    pub coding: Coding,
+    /// Whether the 2x2 transform stores a and the chroma indices as differences from
+    /// neighbouring blocks; needs a coding other than `Fixed`
+    pub prediction: Prediction,
+    /// Whether the 2x2 transform leaves out runs of blocks that repeat the block before with no
+    /// detail; needs a coding other than `Fixed`
+    pub skip_runs: bool,
+    /// The block transform to use; the 2x2 transform from `quality` or `quantization` when unset
+    pub transform: Option<Arc<dyn BlockTransform>>,
+    /// Codes the whole image with a multi-level wavelet instead of a block transform when set
+    pub wavelet: Option<Wavelet>,
+    /// Codes the whole image losslessly instead of a block transform when set. Its coding must
+    /// be the one `Lossless::new` picks for `coding`
+    pub lossless: Option<Lossless>,
+}
 
-    // Load in image
-    let image = RgbImage::read(Some(filename).expect("REASON").as_deref()).unwrap();
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            quality: 100,
+            quantization: None,
+            container: ContainerFormat::default(),
+            padding: Padding::default(),
+            chroma: ChromaSampling::default(),
+            depth: Depth::default(),
+            layout: None,
+            coding: Coding::default(),
+            prediction: Prediction::default(),
+            skip_runs: false,
+            transform: None,
+            wavelet: None,
+            lossless: None,
+        }
+    }
+}
 
-    // Trim the image here so that we can reference the new sizings later
-    let mut width: u32 = image.width;
-    let mut height: u32 = image.height;
+impl EncodeOptions {
+    /// The quantizer settings these options select for the 2x2 transform
+    pub fn quant_params(&self) -> QuantParams {
+        match (&self.quantization, &self.layout) {
+            (Some(params), _) => params.clone(),
+            (None, Some(layout)) => QuantParams::for_layout(self.quality, self.depth, layout),
+            (None, None) => QuantParams::for_depth(self.quality, self.depth),
+        }
+    }
+
+    /// The block transform these options select
+    pub fn block_transform(&self) -> Result<Arc<dyn BlockTransform>, RpegError> {
+        if let Some(transform) = &self.transform {
+            return Ok(transform.clone());
+        }
 
-    if image.width % 2 != 0 {
-        width -= 1;
+        // The quantizer is derived from the layout's fields, so they must all be there first
+        if let Some(layout) = &self.layout {
+            layout.validate(self.chroma.block_samples(2))?;
+        }
+        let params = self.quant_params();
+        let mut transform = match &self.layout {
+            Some(layout) => Haar2x2::with_layout(params, self.chroma, layout.clone())?,
+            None => Haar2x2::with_sampling(params, self.chroma),
+        };
+        transform.validate()?;
+        transform.coding = self.coding;
+        transform.prediction = self.prediction;
+        transform.skip_runs = self.skip_runs;
+        return Ok(Arc::new(transform));
     }
+}
+
+/// Options that control how an image is decoded
+#[derive(Clone, Debug, Default)]
+pub struct DecodeOptions {
+    /// How reduced chroma is brought back to full resolution
+    pub upsampling: ChromaUpsampling,
+    /// Smooths the edges between blocks when set; the wavelet mode has no blocks and ignores it
+    pub deblock: Option<Deblock>,
+}
+
+// -----------------------------------------------------------------------------------
+//                              IN-MEMORY API
+// -----------------------------------------------------------------------------------
 
-    if image.height % 2 != 0 {
-        height -= 1;
+/// Compresses an image into a buffer holding a complete rpeg file
+///
+/// # Arguments:
+/// * `image`: the image to compress
+/// * `options`: settings for the encoder
+pub fn encode(image: &RgbImage, options: &EncodeOptions) -> Result<Vec<u8>, RpegError> {
+
+    if options.coding != Coding::Fixed && (options.transform.is_some() || options.wavelet.is_some()) {
+        return Err(RpegError::IncompatibleOptions("only the 2x2 transform has a choice of coefficient coding".to_string()));
+    }
+    if options.prediction != Prediction::None && (options.transform.is_some() || options.wavelet.is_some()) {
+        return Err(RpegError::IncompatibleOptions("only the 2x2 transform can predict blocks from their neighbours".to_string()));
+    }
+    if options.prediction != Prediction::None && options.coding == Coding::Fixed {
+        return Err(RpegError::IncompatibleOptions("predicted values need a variable-length coding, not fixed words".to_string()));
+    }
+    if options.skip_runs && (options.transform.is_some() || options.wavelet.is_some()) {
+        return Err(RpegError::IncompatibleOptions("only the 2x2 transform can skip runs of blocks".to_string()));
+    }
+    if options.skip_runs && options.coding == Coding::Fixed {
+        return Err(RpegError::IncompatibleOptions("run counts need a variable-length coding, not fixed words".to_string()));
+    }
+
+    if let Some(lossless) = &options.lossless {
+        if options.transform.is_some() || options.wavelet.is_some() {
+            return Err(RpegError::IncompatibleOptions("the lossless mode takes the place of the other transforms".to_string()));
+        }
+        if Lossless::new(options.coding) != *lossless {
+            return Err(RpegError::IncompatibleOptions(format!("the lossless mode's {:?} coding does not match the {:?} coding asked for", lossless.coding, options.coding)));
+        }
+
+        // Trimming drops pixels, and the 2x2 transform's block options do not apply
+        if options.padding == Padding::Trim {
+            return Err(RpegError::IncompatibleOptions("the lossless mode cannot trim the image; pad it instead".to_string()));
+        }
+        if options.prediction != Prediction::None || options.skip_runs {
+            return Err(RpegError::IncompatibleOptions("the lossless mode makes its own predictions and cannot skip blocks".to_string()));
+        }
+        return encode_lossless(image, lossless, options);
+    }
+
+    // The wavelet works on the image as a whole, so it needs no padding
+    let transform = options.block_transform()?;
+    let block_size = if options.wavelet.is_some() { 1 } else { transform.block_size() };
+
+    // Pad (or trim) the image here so that we can reference the new sizings later
+    let width = options.padding.coded_length(image.width as usize, block_size);
+    let height = options.padding.coded_length(image.height as usize, block_size);
+
+    // The legacy format only records the coded size, so it cannot undo padding
+    if options.container == ContainerFormat::Legacy && (width > image.width as usize || height > image.height as usize) {
+        return Err(RpegError::IncompatibleOptions(
+            "the legacy format cannot record odd dimensions; trim the image instead".to_string()));
     }
 
     // Load image into a Vec<Rgb>
-    let rgb_image = prepare_ppm(&image, width, height);
-    
+    let rgb_image = prepare_ppm(image, width, height, options.padding)?;
+
     // Translate the rgb vec into a vec with floating points for the rgb values
     let rgb_float_image = convert_rgb_to_rgb_float(&rgb_image, image.denominator);
 
     // Translate the rgb float image into a vec of custom struct: Ypbpr
     let component_video_image = convert_rgb_float_to_component_video(&rgb_float_image);
 
-    // Collect pixels into squares and feed them into the discrete cosine transfer function
-    let mut averaged_pixels: Vec<PixelBlockValues> =  Vec::new();
-    let mut pixel_square: Vec<Ypbpr> = Vec::new();
-
-    for i in (0..height).step_by(2) {
-        for j in (0..width).step_by(2) {
-            pixel_square.push(component_video_image[((width * i) + j) as usize].clone());
-            pixel_square.push(component_video_image[((width * i) + (j+1)) as usize].clone());
-            pixel_square.push(component_video_image[((width * (i+1)) + j) as usize].clone());
-            pixel_square.push(component_video_image[((width * (i+1)) + (j+1)) as usize].clone());
+    if let Some(wavelet) = &options.wavelet {
+        let header = Header {
+            original_width: image.width as usize,
+            original_height: image.height as usize,
+            denominator: image.denominator,
+            width,
+            height,
+            transform: Wavelet::ID,
+            parameters: wavelet.parameters(),
+        };
+        let compressed = CompressedImage { header, payload: wavelet.encode(&component_video_image, width, height)? };
+        return write_compressed(&compressed, options.container);
+    }
 
-            averaged_pixels.push(discrete_cosine_transfer(pixel_square));
-            pixel_square = Vec::new();
+    // Collect pixels into squares and feed them into the block transform
+    let mut blocks: Vec<Vec<i64>> = Vec::new();
+    for i in (0..height).step_by(block_size) {
+        for j in (0..width).step_by(block_size) {
+            let pixel_square = collect_block(&component_video_image, width, j, i, block_size);
+            blocks.push(transform.forward(&pixel_square)?);
         }
     }
-    
-    // Pack the bits into the final image file
-    let final_image = pack_as_32_bit(&averaged_pixels);
-    
-    // Write to output
-    csc411_rpegio::output_rpeg_data(&final_image, width as usize, height as usize).unwrap();
+
+    // Pack the coefficients into the final image file
+    let compressed = CompressedImage {
+        header: Header::new(image.width as usize, image.height as usize, image.denominator, width, height, transform.as_ref()),
+        payload: transform.write_blocks(&blocks, width / block_size)?,
+    };
+
+    return write_compressed(&compressed, options.container);
 }
 
-pub fn decompress(filename: Option<&str>) {
-    
-    // Load in compressed image
-    let (word_vec, _width, _height) = csc411_rpegio::input_rpeg_data(filename).unwrap();
+/// Compresses an image with the lossless mode, which skips the float conversions entirely
+///
+/// # Arguments:
+/// * `image`: the image to compress
+/// * `lossless`: the lossless mode's settings
+/// * `options`: settings for the encoder, of which only the container and padding apply
+fn encode_lossless(image: &RgbImage, lossless: &Lossless, options: &EncodeOptions) -> Result<Vec<u8>, RpegError> {
+    let width = options.padding.coded_length(image.width as usize, 2);
+    let height = options.padding.coded_length(image.height as usize, 2);
+    let rgb_image = prepare_ppm(image, width, height, options.padding)?;
+    let header = Header {
+        original_width: image.width as usize,
+        original_height: image.height as usize,
+        denominator: image.denominator,
+        width,
+        height,
+        transform: Lossless::ID,
+        parameters: lossless.parameters(),
+    };
+    let compressed = CompressedImage { header, payload: lossless.encode(&rgb_image, width, height, image.denominator)? };
+    return write_compressed(&compressed, options.container);
+}
 
-    // Unpack compressed image to PixelBlockValues type vec
-    let unpacked_pixel_vec = unpack_to_pixel_values(word_vec);        
+/// Decompresses a buffer holding a complete rpeg file back into an image
+///
+/// # Arguments:
+/// * `bytes`: the compressed image
+pub fn decode(bytes: &[u8]) -> Result<RgbImage, RpegError> {
+    return decode_with(bytes, &DecodeOptions::default());
+}
 
-    // Loop through the unpacked values, and run them through the inverse discrete cosine transfer
-    // function, then take those values and assign them to the image once again via the block 
-    // formatting they were originally placed in. 
-    // for instance: 
-    // two blocks next to each other will have to be translated into a vector as such
-    // ( 0 1 ) ( 2 3 )
-    // ( 4 5 ) ( 6 7 )
-    let mut decompressed_pixels = Vec::new();
-    for i in 0..unpacked_pixel_vec.len() {
-        decompressed_pixels.push(inverse_discrete_cosine_transfer(&unpacked_pixel_vec[i]));
+/// Decompresses a buffer holding a complete rpeg file back into an image
+///
+/// # Arguments:
+/// * `bytes`: the compressed image
+/// * `options`: settings for the decoder
+pub fn decode_with(bytes: &[u8], options: &DecodeOptions) -> Result<RgbImage, RpegError> {
+
+    // Load in compressed image
+    let CompressedImage { header, payload } = read_compressed(bytes)?;
+    let (_width, _height) = (header.width, header.height);
+    let output_width = header.original_width.min(_width);
+    let output_height = header.original_height.min(_height);
+
+    // The lossless mode gives back integer pixels, which need nothing but the padding cropped off
+    if header.transform == Lossless::ID {
+        let lossless = Lossless::from_parameters(&header.parameters)?;
+        let rgb_image = lossless.decode(&payload, _width, _height, header.denominator)?;
+        return Ok(RgbImage {
+            pixels: crop_rgb(rgb_image, _width, output_width, output_height),
+            width: output_width as u32,
+            height: output_height as u32,
+            denominator: header.denominator,
+        });
     }
 
-    let mut component_video_image = vec![Ypbpr {y: 0.0, pb: 0.0, pr: 0.0}; _width * _height];
+    let (mut component_video_image, sampling, block_size) = if header.transform == Wavelet::ID {
+        let wavelet = Wavelet::from_parameters(&header.parameters)?;
+        (wavelet.decode(&payload, _width, _height)?, wavelet.sampling, 1)
+    } else {
+        let transform = from_parameters(header.transform, &header.parameters)?;
+        (decode_blocks(transform.as_ref(), &header, &payload)?, transform.sampling(), transform.block_size())
+    };
 
-    let mut pixel_index = 0;
-    for i in (0.._height).step_by(2) {
-        for j in (0.._width).step_by(2) {
-            component_video_image[((_width * i) + j) as usize] = decompressed_pixels[pixel_index][0].clone();
-            component_video_image[((_width * i) + (j+1)) as usize] = decompressed_pixels[pixel_index][1].clone();
-            component_video_image[((_width * (i+1)) + j) as usize] = decompressed_pixels[pixel_index][2].clone();
-            component_video_image[((_width * (i+1)) + (j+1)) as usize] = decompressed_pixels[pixel_index][3].clone();
-            pixel_index = pixel_index + 1;
-        }
+    // Smooth the chroma across sample (and block) edges when asked to
+    if options.upsampling == ChromaUpsampling::Bilinear {
+        sampling.interpolate(&mut component_video_image, _width, _height);
+    }
+    if let Some(deblock) = &options.deblock {
+        deblock.apply(&mut component_video_image, _width, _height, block_size);
     }
 
     // Translate these component video pixels into an rgb float vector
     let rgb_float_image = convert_component_video_to_rgb_float(&component_video_image);
 
     // // Translate the rgb float vector into rgb values
-    let rgb_image = convert_rgb_float_to_rgb(&rgb_float_image);
+    let rgb_image = convert_rgb_float_to_rgb(&rgb_float_image, header.denominator);
+
+    // Remove any padding the encoder added, restoring the original dimensions
+    let rgb_image = crop_rgb(rgb_image, _width, output_width, output_height);
 
     // Create a PPM image from these rgb values
     let image = RgbImage {
         pixels: rgb_image,
-        width: _width as u32,
-        height: _height as u32,
-        denominator: 255 as u16,
+        width: output_width as u32,
+        height: output_height as u32,
+        denominator: header.denominator,
+    };
+
+    return Ok(image);
+}
+
+/// Runs every block of a block-transform payload through the inverse transform
+///
+/// # Arguments:
+/// * `transform`: the transform named in the header
+/// * `header`: the header describing the payload
+/// * `payload`: the packed blocks
+fn decode_blocks(transform: &dyn BlockTransform, header: &Header, payload: &[u8]) -> Result<Vec<Ypbpr>, RpegError> {
+    let (_width, _height) = (header.width, header.height);
+    let block_size = transform.block_size();
+
+    // Every block of the image is stored in the payload
+    if _width % block_size != 0 || _height % block_size != 0 {
+        return Err(RpegError::InvalidHeader(format!("dimensions {}x{} are not whole {}x{} blocks", _width, _height, block_size, block_size)));
+    }
+    let block_count = (_width / block_size) * (_height / block_size);
+
+    // Unpack compressed image to one vec of coefficients per block
+    let blocks = transform.read_blocks(payload, _width / block_size, block_count)?;
+
+    // Loop through the unpacked values, and run them through the inverse transform, then take
+    // those values and assign them to the image once again via the block formatting they were
+    // originally placed in.
+    // for instance:
+    // two 2x2 blocks next to each other will have to be translated into a vector as such
+    // ( 0 1 ) ( 2 3 )
+    // ( 4 5 ) ( 6 7 )
+    let mut component_video_image = vec![Ypbpr {y: 0.0, pb: 0.0, pr: 0.0}; _width * _height];
+
+    let mut block_index = 0;
+    for i in (0.._height).step_by(block_size) {
+        for j in (0.._width).step_by(block_size) {
+            let pixel_square = transform.inverse(&blocks[block_index])?;
+            if pixel_square.len() != block_size * block_size {
+                return Err(RpegError::DimensionMismatch { expected: block_size * block_size, actual: pixel_square.len() });
+            }
+            place_block(&mut component_video_image, _width, j, i, block_size, pixel_square);
+            block_index += 1;
+        }
+    }
+
+    return Ok(component_video_image);
+}
+
+// -----------------------------------------------------------------------------------
+//                              STREAMING API
+// -----------------------------------------------------------------------------------
+
+/// Compresses an image and writes the rpeg file to `writer`
+///
+/// # Arguments:
+/// * `image`: the image to compress
+/// * `options`: settings for the encoder
+/// * `writer`: where the compressed image is written
+pub fn encode_to<W: io::Write>(image: &RgbImage, options: &EncodeOptions, writer: &mut W) -> Result<(), RpegError> {
+    let bytes = encode(image, options)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    return Ok(());
+}
+
+/// Reads an rpeg file from `reader` and decompresses it into an image
+///
+/// # Arguments:
+/// * `reader`: where the compressed image is read from
+/// * `options`: settings for the decoder
+pub fn decode_from<R: io::Read>(reader: &mut R, options: &DecodeOptions) -> Result<RgbImage, RpegError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    return decode_with(&bytes, options);
+}
+
+// -----------------------------------------------------------------------------------
+//                              FILE HELPERS
+// -----------------------------------------------------------------------------------
+
+/// Compresses the ppm at `filename` (or standard input) and writes the result to standard output
+pub fn compress(filename: Option<&str>, options: &EncodeOptions) -> Result<(), RpegError> {
+
+    // Load in image, at whatever maxval it was saved with
+    let bytes = match filename {
+        Some(path) => std::fs::read(path)?,
+        None => {
+            let mut bytes = Vec::new();
+            io::stdin().lock().read_to_end(&mut bytes)?;
+            bytes
+        }
     };
+    let image = read_ppm(&bytes)?;
 
-    // Write the final decompressed image to a file
-    image.write(None).unwrap();
+    return encode_to(&image, options, &mut io::stdout().lock());
+}
+
+/// Decompresses the rpeg file at `filename` (or standard input) and writes the ppm to standard output
+pub fn decompress(filename: Option<&str>, options: &DecodeOptions) -> Result<(), RpegError> {
+
+    // Load in compressed image
+    let image = match filename {
+        Some(path) => decode_with(&std::fs::read(path)?, options)?,
+        None => decode_from(&mut io::stdin().lock(), options)?,
+    };
+
+    // Write the final decompressed image to standard output, with the denominator it was compressed with
+    let mut output = io::stdout().lock();
+    output.write_all(&write_ppm(&image)?)?;
+    output.flush()?;
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csc411_image::Rgb;
+    use crate::transform::by_name;
+
+    /// A smooth colour gradient over the middle of the range
+    fn gradient(width: u32, height: u32, denominator: u16) -> RgbImage {
+        let level = |value: u32, range: u32| (((value * denominator as u32) / (4 * range)) + (denominator as u32 / 4)) as u16;
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| Rgb {
+                red: level(x, width),
+                green: level(y, height),
+                blue: level(x + y, width + height) + denominator / 8,
+            }))
+            .collect();
+        RgbImage { pixels, width, height, denominator }
+    }
+
+    /// Checks that `decoded` has the size of `original` and that no channel moved further than
+    /// `tolerance` of the denominator
+    fn assert_close(original: &RgbImage, decoded: &RgbImage, tolerance: f32) {
+        assert_eq!((decoded.width, decoded.height, decoded.denominator), (original.width, original.height, original.denominator));
+        let limit = (tolerance * original.denominator as f32) as i32;
+        for (before, after) in original.pixels.iter().zip(&decoded.pixels) {
+            for (a, b) in [(before.red, after.red), (before.green, after.green), (before.blue, after.blue)] {
+                assert!((a as i32 - b as i32).abs() <= limit, "{:?} became {:?}", before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn haar_round_trip_test() {
+        let image = gradient(10, 6, 255);
+        assert_close(&image, &decode(&encode(&image, &EncodeOptions::default()).unwrap()).unwrap(), 0.1);
+
+        // The legacy format holds the same words behind the assignment's header
+        let options = EncodeOptions { container: ContainerFormat::Legacy, ..EncodeOptions::default() };
+        assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.1);
+    }
+
+    #[test]
+    fn layout_test() {
+        let image = gradient(10, 6, 255);
+        let options = EncodeOptions { layout: Some(WordLayout::from_widths(&[9, 6, 6, 6, 4, 4])), ..EncodeOptions::default() };
+        assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.1);
+
+        // Layouts missing fields are refused before a quantizer is made from them
+        for widths in [&[9][..], &[9, 5], &[9, 5, 5, 5], &[9, 5, 5, 5, 4]] {
+            let options = EncodeOptions { layout: Some(WordLayout::from_widths(widths)), ..EncodeOptions::default() };
+            assert!(matches!(encode(&image, &options), Err(RpegError::IncompatibleOptions(_))));
+        }
+        assert_eq!(WordLayout::from_widths(&[9, 5]).chroma_bits(), 0);
+    }
+
+    #[test]
+    fn arithmetic_round_trip_test() {
+        let image = gradient(10, 6, 255);
+        for sampling in [ChromaSampling::Yuv420, ChromaSampling::Yuv444] {
+            let options = EncodeOptions { coding: Coding::Arithmetic, chroma: sampling, ..EncodeOptions::default() };
+            let bytes = encode(&image, &options).unwrap();
+            assert!(bytes.len() < encode(&image, &EncodeOptions { chroma: sampling, ..EncodeOptions::default() }).unwrap().len());
+            assert_close(&image, &decode(&bytes).unwrap(), 0.1);
+        }
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn huffman_round_trip_test() {
+        let image = gradient(10, 6, 255);
+        for depth in [Depth::Standard, Depth::Deep] {
+            let options = EncodeOptions { coding: Coding::Huffman, depth, ..EncodeOptions::default() };
+            assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.1);
+        }
+    }
+
+    #[test]
+    fn prediction_round_trip_test() {
+        let image = gradient(14, 10, 255);
+        for coding in [Coding::VariableLength, Coding::Arithmetic, Coding::Huffman] {
+            let options = EncodeOptions { coding, prediction: Prediction::Med, ..EncodeOptions::default() };
+            assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.1);
+        }
+        let options = EncodeOptions { prediction: Prediction::Med, ..EncodeOptions::default() };
+        assert!(matches!(encode(&image, &options), Err(RpegError::IncompatibleOptions(_))));
+    }
+
+    #[test]
+    fn skip_runs_round_trip_test() {
+        // Flat rows give long runs of repeated blocks between the gradient's
+        let mut image = gradient(16, 12, 255);
+        for pixel in &mut image.pixels[..16 * 6] {
+            *pixel = Rgb { red: 100, green: 120, blue: 140 };
+        }
+        for coding in [Coding::VariableLength, Coding::Arithmetic, Coding::Huffman] {
+            for prediction in [Prediction::None, Prediction::Med] {
+                let options = EncodeOptions { coding, prediction, skip_runs: true, ..EncodeOptions::default() };
+                let bytes = encode(&image, &options).unwrap();
+                assert!(bytes.len() < encode(&image, &EncodeOptions { skip_runs: false, ..options.clone() }).unwrap().len());
+                assert_close(&image, &decode(&bytes).unwrap(), 0.1);
+            }
+        }
+    }
+
+    #[test]
+    fn huge_header_test() {
+        // A header can claim any size; a payload too short for it must fail rather than
+        // make the decoder allocate for it
+        let image = gradient(8, 8, 255);
+        let mut all_options = vec![
+            EncodeOptions { transform: by_name("dct", 50, ChromaSampling::Yuv420, Depth::Standard), ..EncodeOptions::default() },
+            EncodeOptions { wavelet: Wavelet::by_name("wavelet", 3, 50, ChromaSampling::Yuv420).unwrap(), ..EncodeOptions::default() },
+        ];
+        for coding in [Coding::Fixed, Coding::VariableLength, Coding::Arithmetic, Coding::Huffman] {
+            all_options.push(EncodeOptions { coding, ..EncodeOptions::default() });
+            all_options.push(EncodeOptions { coding, lossless: Some(Lossless::new(coding)), ..EncodeOptions::default() });
+        }
+        all_options.push(EncodeOptions { coding: Coding::VariableLength, skip_runs: true, ..EncodeOptions::default() });
+
+        for options in all_options {
+            let CompressedImage { header, payload } = read_compressed(&encode(&image, &options).unwrap()).unwrap();
+            for size in [0xFFFF_FFF8, 65536] {
+                // A wavelet plane of this size is valid and would be allocated in full
+                if options.wavelet.is_some() && size == 65536 {
+                    continue;
+                }
+                let header = Header { original_width: size, original_height: size, width: size, height: size, ..header.clone() };
+                let bytes = write_compressed(&CompressedImage { header, payload: payload.clone() }, ContainerFormat::Rpeg).unwrap();
+                assert!(decode(&bytes).is_err(), "{:?} at {}", options, size);
+            }
+        }
+    }
+
+    #[test]
+    fn dct_round_trip_test() {
+        let image = gradient(20, 13, 255);
+        for quality in [50, 90] {
+            let options = EncodeOptions { transform: by_name("dct", quality, ChromaSampling::Yuv420, Depth::Standard), ..EncodeOptions::default() };
+            assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.1);
+        }
+    }
+
+    #[test]
+    fn wavelet_round_trip_test() {
+        let image = gradient(19, 10, 255);
+        for name in ["wavelet", "cdf53"] {
+            let options = EncodeOptions { wavelet: Wavelet::by_name(name, 3, 90, ChromaSampling::Yuv420).unwrap(), ..EncodeOptions::default() };
+            assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.1);
+        }
+    }
+
+    #[test]
+    fn sampling_round_trip_test() {
+        let image = gradient(12, 10, 255);
+        for sampling in ChromaSampling::ALL {
+            let haar = EncodeOptions { chroma: sampling, ..EncodeOptions::default() };
+            let dct = EncodeOptions { transform: by_name("dct", 90, sampling, Depth::Standard), ..EncodeOptions::default() };
+            for options in [haar, dct] {
+                let bytes = encode(&image, &options).unwrap();
+                assert_close(&image, &decode(&bytes).unwrap(), 0.1);
+                let bilinear = DecodeOptions { upsampling: ChromaUpsampling::Bilinear, ..DecodeOptions::default() };
+                assert_close(&image, &decode_with(&bytes, &bilinear).unwrap(), 0.1);
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_test() {
+        let image = gradient(4, 4, 255);
+        let mut bytes = Vec::new();
+        encode_to(&image, &EncodeOptions::default(), &mut bytes).unwrap();
+        assert_eq!(bytes, encode(&image, &EncodeOptions::default()).unwrap());
+        assert_close(&image, &decode_from(&mut bytes.as_slice(), &DecodeOptions::default()).unwrap(), 0.1);
+    }
+
+    #[test]
+    fn lossless_round_trip_test() {
+        // Odd sizes are padded to whole 2x2 blocks and the padding cropped off bit for bit
+        for padding in [Padding::Replicate, Padding::Mirror, Padding::Zero] {
+            let image = gradient(7, 5, 1000);
+            let options = EncodeOptions { padding, coding: Coding::Huffman, lossless: Some(Lossless::new(Coding::Huffman)), ..EncodeOptions::default() };
+            assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.0);
+        }
+
+        // A variable-length coding gets the block options past the generic checks, so only the
+        // lossless mode's own checks can refuse them
+        let image = gradient(8, 8, 255);
+        let lossless = Some(Lossless::new(Coding::Huffman));
+        for (options, reason) in [
+            (EncodeOptions { padding: Padding::Trim, coding: Coding::Huffman, lossless: lossless.clone(), ..EncodeOptions::default() }, "cannot trim"),
+            (EncodeOptions { coding: Coding::VariableLength, lossless: lossless.clone(), ..EncodeOptions::default() }, "does not match"),
+            (EncodeOptions { lossless: lossless.clone(), ..EncodeOptions::default() }, "does not match"),
+            (EncodeOptions { prediction: Prediction::Med, coding: Coding::Huffman, lossless: lossless.clone(), ..EncodeOptions::default() }, "own predictions"),
+            (EncodeOptions { skip_runs: true, coding: Coding::Huffman, lossless: lossless.clone(), ..EncodeOptions::default() }, "cannot skip blocks"),
+        ] {
+            let result = encode(&image, &options);
+            assert!(matches!(&result, Err(RpegError::IncompatibleOptions(message)) if message.contains(reason)), "{:?} gave {:?}", options, result);
+        }
+
+        // Fixed words are the default coding, which the lossless mode takes as the arithmetic coder
+        let options = EncodeOptions { lossless: Some(Lossless::new(Coding::Fixed)), ..EncodeOptions::default() };
+        assert_close(&image, &decode(&encode(&image, &options).unwrap()).unwrap(), 0.0);
+    }
+}
@@ -1,6 +1,7 @@
 
 use csc411_image::{RgbImage, Rgb};
-use bitpack::bitpack::{newu, news, getu, gets};
+use bitpack::layout::{Layout, LayoutError};
+use crate::error::RpegError;
 
 // Documenatation:
 // Rgb: https://docs.rs/csc411_image/latest/csc411_image/imgtype/struct.Rgb.html
@@ -31,30 +32,187 @@ pub struct PixelBlockValues {
     pub b: f32,
     pub c: f32,
     pub d: f32,
-    pub avg_pb: usize,
-    pub avg_pr: usize,
+    /// Chroma indices, one per chroma sample of the block in row-major order
+    pub avg_pb: Vec<usize>,
+    pub avg_pr: Vec<usize>,
+}
+
+/// How the blocks along the right and bottom edges are filled when a dimension is odd
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Padding {
+    /// Repeat the last column and/or row
+    #[default]
+    Replicate,
+    /// Reflect the image about its last column and/or row
+    Mirror,
+    /// Fill with black
+    Zero,
+    /// Drop the last column and/or row, as the original assignment did
+    Trim,
+}
+
+impl Padding {
+    /// Rounds `length` to the whole number of `block_size` blocks the encoder will actually work on
+    pub fn coded_length(&self, length: usize, block_size: usize) -> usize {
+        let remainder = length % block_size;
+        match self {
+            Padding::Trim => length - remainder,
+            _ if remainder == 0 => length,
+            _ => length + block_size - remainder,
+        }
+    }
+
+    /// Picks which source index fills position `index` of a line `length` pixels long,
+    /// or `None` if the position should be filled with black
+    fn source_index(&self, index: usize, length: usize) -> Option<usize> {
+        if index < length {
+            return Some(index);
+        }
+        match self {
+            Padding::Zero => None,
+            Padding::Mirror if 2 * length >= index + 2 => Some(2 * length - 2 - index),
+            _ => Some(length - 1),
+        }
+    }
+}
+
+/// Width and least-significant bit of one field of a packed word
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FieldLayout {
+    pub width: u64,
+    pub lsb: u64,
+}
+
+/// Describes how the values of one block are packed into a word: the word's size and where
+/// each field sits. Fields hold a, b, c, d, then every Pb sample, then every Pr sample
+#[derive(Clone, Debug, PartialEq)]
+pub struct WordLayout {
+    /// Bits per word; a whole number of bytes, at most 64
+    pub word_bits: u64,
+    pub fields: Vec<FieldLayout>,
+}
+
+impl WordLayout {
+    /// Places fields of the given widths from the top bit down, in a word rounded up to whole bytes
+    pub fn from_widths(widths: &[u64]) -> Self {
+        let word_bits = widths.iter().sum::<u64>().div_ceil(8) * 8;
+        let mut lsb = word_bits;
+        let fields = widths.iter()
+            .map(|&width| {
+                lsb -= width;
+                FieldLayout { width, lsb }
+            })
+            .collect();
+        return WordLayout { word_bits, fields };
+    }
+
+    /// The assignment's 9/5/5/5 luma split with 4-bit chroma, which is the 32-bit
+    /// 9/5/5/5/4/4 word for a 4:2:0 block
+    pub fn standard(chroma_count: usize) -> Self {
+        return WordLayout::with_chroma(9, 5, 4, chroma_count);
+    }
+
+    /// The 64-bit profile: 16-bit a, 10-bit b, c and d, and 9-bit chroma, which fills
+    /// 64 bits exactly for a 4:2:0 block
+    pub fn wide(chroma_count: usize) -> Self {
+        return WordLayout::with_chroma(16, 10, 9, chroma_count);
+    }
+
+    fn with_chroma(a: u64, bcd: u64, chroma: u64, chroma_count: usize) -> Self {
+        let mut widths = vec![a, bcd, bcd, bcd];
+        widths.extend(std::iter::repeat_n(chroma, 2 * chroma_count));
+        return WordLayout::from_widths(&widths);
+    }
+
+    /// Number of Pb (and of Pr) samples the layout has room for
+    pub fn chroma_count(&self) -> usize {
+        return self.fields.len().saturating_sub(4) / 2;
+    }
+
+    /// Width of the narrowest of `fields`, which bounds the values all of them can hold; 0 when
+    /// the layout is too short to have them
+    fn narrowest(fields: &[FieldLayout]) -> u64 {
+        return fields.iter().map(|field| field.width).min().unwrap_or(0);
+    }
+
+    /// Width of the `a` field
+    pub fn a_bits(&self) -> u64 {
+        return WordLayout::narrowest(self.fields.get(..1).unwrap_or_default());
+    }
+
+    /// Width of the narrowest of the `b`, `c` and `d` fields
+    pub fn bcd_bits(&self) -> u64 {
+        return WordLayout::narrowest(self.fields.get(1..4).unwrap_or_default());
+    }
+
+    /// Width of the narrowest chroma field
+    pub fn chroma_bits(&self) -> u64 {
+        return WordLayout::narrowest(self.fields.get(4..).unwrap_or_default());
+    }
+
+    /// Checks that the layout holds a, b, c, d and `chroma_count` samples of each chroma
+    /// channel in non-overlapping fields of a word that `pack_words` can write
+    pub fn validate(&self, chroma_count: usize) -> Result<(), RpegError> {
+        let invalid = |reason: String| Err(RpegError::IncompatibleOptions(format!("bad word layout: {}", reason)));
+        if self.fields.len() != 4 + (2 * chroma_count) {
+            return invalid(format!("{} fields for {} chroma samples", self.fields.len(), chroma_count));
+        }
+        if self.word_bits == 0 || self.word_bits > 64 || !self.word_bits.is_multiple_of(8) {
+            return invalid(format!("a {} bit word is not 1 to 8 whole bytes", self.word_bits));
+        }
+
+        self.bit_layout()?;
+        return Ok(());
+    }
+
+    /// Names the fields a, b, c, d, avg_pb0.., avg_pr0.. in a `bitpack` layout, which
+    /// checks that every field fits in the word and that none overlap
+    pub fn bit_layout(&self) -> Result<Layout, RpegError> {
+        let chroma_count = self.chroma_count();
+        let mut builder = Layout::builder(self.word_bits);
+        for (i, field) in self.fields.iter().enumerate() {
+            builder = match i {
+                0 => builder.unsigned("a", field.width, field.lsb),
+                1..=3 => builder.signed(["b", "c", "d"][i - 1], field.width, field.lsb),
+                _ if i - 4 < chroma_count => builder.unsigned(&format!("avg_pb{}", i - 4), field.width, field.lsb),
+                _ => builder.unsigned(&format!("avg_pr{}", i - 4 - chroma_count), field.width, field.lsb),
+            };
+        }
+        return builder.build().map_err(|err| RpegError::IncompatibleOptions(format!("bad word layout: {}", err)));
+    }
 }
 
 // -----------------------------------------------------------------------------------
 //                          COMPRESSION FUNCTIONS
 // -----------------------------------------------------------------------------------
 
-// Function that reads in an RgbImage, trims off either the last row and/or column
-// to make the image evenly dimensioned, then loads the information into an Array2
+// Function that reads in an RgbImage, pads (or trims) the last rows and/or columns
+// to make the image a whole number of blocks, then loads the information into a vec
 // of rgb values 
-pub fn prepare_ppm(image: &RgbImage, trimmed_width: u32, trimmed_height: u32) -> Vec<csc411_image::Rgb> {
+pub fn prepare_ppm(image: &RgbImage, coded_width: usize, coded_height: usize, padding: Padding) -> Result<Vec<csc411_image::Rgb>, RpegError> {
 
-    let mut pixel_data: Vec<Rgb> = vec![Rgb{red: 0, green: 0, blue: 0}; (trimmed_width * trimmed_height) as usize];
+    // Make sure the image actually holds as many pixels as its header claims
+    let expected = image.width as usize * image.height as usize;
+    if image.pixels.len() != expected {
+        return Err(RpegError::DimensionMismatch { expected, actual: image.pixels.len() });
+    }
+
+    let mut pixel_data: Vec<Rgb> = vec![Rgb{red: 0, green: 0, blue: 0}; coded_width * coded_height];
 
     // Looks through each pixel (rgb value) in the image, and pushes each pixel into 
-    // a new vec for later manipulation
-    for i in 0..trimmed_height {
-        for j in 0..trimmed_width {
-            pixel_data[(trimmed_width as usize * i as usize) + j as usize] = image.pixels[(image.width as usize * i as usize) + j as usize].clone();
+    // a new vec for later manipulation. Positions past the edge of the image are
+    // filled in according to the padding mode
+    for i in 0..coded_height {
+        for j in 0..coded_width {
+            let source_row = padding.source_index(i, image.height as usize);
+            let source_col = padding.source_index(j, image.width as usize);
+            if let (Some(row), Some(col)) = (source_row, source_col) {
+                pixel_data[(coded_width * i) + j] = image.pixels[(image.width as usize * row) + col].clone();
+            }
         }
     }
 
-    return pixel_data;
+    return Ok(pixel_data);
 }
 
 pub fn convert_rgb_to_rgb_float(rbg_vec: &Vec<csc411_image::Rgb>, denominator: u16) -> Vec<RGBFloat> {
@@ -95,26 +253,55 @@ pub fn convert_rgb_float_to_component_video(rbg_float_vec: &Vec<RGBFloat>) -> Ve
     return component_video_per_pixel;
 }
 
-/// Function packs a group of a, b, c, d, avg_pb, avg_pr, values with bitshifting
-pub fn pack_as_32_bit(compression_vec: &Vec<PixelBlockValues>) -> Vec<[u8; 4]>{
-    
-    let mut final_image = Vec::new();
-    for i in 0..compression_vec.len() {
+/// Function copies the `size` x `size` block whose top-left pixel is at column `x`, row `y`
+/// out of a row-major image `width` pixels wide, in row-major order
+pub fn collect_block(image: &[Ypbpr], width: usize, x: usize, y: usize, size: usize) -> Vec<Ypbpr> {
+    let mut block = Vec::with_capacity(size * size);
+    for row in y..y + size {
+        block.extend_from_slice(&image[(width * row) + x..(width * row) + x + size]);
+    }
+    return block;
+}
+
+/// Function packs a group of a, b, c, d, avg_pb, avg_pr, values with bitshifting into
+/// big-endian words placed according to `layout`
+/// Returns a `FieldOverflow` error naming the first value that does not fit in its field
+pub fn pack_words(compression_vec: &[PixelBlockValues], layout: &WordLayout) -> Result<Vec<u8>, RpegError>{
+
+    layout.validate(layout.chroma_count())?;
+    let bit_layout = layout.bit_layout()?;
+    let chroma_count = layout.chroma_count();
+    let word_bytes = (layout.word_bits / 8) as usize;
+    let mut final_image = Vec::with_capacity(compression_vec.len() * word_bytes);
+    for block in compression_vec {
+        if block.avg_pb.len() != chroma_count || block.avg_pr.len() != chroma_count {
+            return Err(RpegError::DimensionMismatch { expected: chroma_count, actual: block.avg_pb.len() });
+        }
+
+        // Line the values up in field order and let the layout place them in the word
+        let values: Vec<i64> = [block.a, block.b, block.c, block.d].iter().map(|&value| value as i64)
+            .chain(block.avg_pb.iter().chain(&block.avg_pr).map(|&value| value as i64))
+            .collect();
+        let word = bit_layout.pack(&values).map_err(|err| match err {
+            LayoutError::ValueDoesNotFit { field, value } => RpegError::FieldOverflow { field: field_label(&field), value: value as i64 },
+            err => RpegError::IncompatibleOptions(format!("bad word layout: {}", err)),
+        })?;
 
-        // Create a word to store information in
-        let mut word = 0_u64;
+        final_image.extend_from_slice(&word.to_be_bytes()[8 - word_bytes..]);
+    }
+    return Ok(final_image);
+}
 
-        // Bitshift our values into the word at certain points
-        word = newu(word, 9, 23, compression_vec[i].a as u64 ).unwrap();
-        word = news(word, 5, 18, compression_vec[i].b as i64 ).unwrap();
-        word = news(word, 5, 13, compression_vec[i].c as i64 ).unwrap();
-        word = news(word, 5, 8, compression_vec[i].d as i64 ).unwrap();
-        word = newu(word, 4, 4, compression_vec[i].avg_pb as u64 ).unwrap();
-        word = newu(word, 4, 0, compression_vec[i].avg_pr as u64 ).unwrap();
-        
-        final_image.push((word as u32).to_be_bytes());
+/// The name `FieldOverflow` reports for a field of `WordLayout::bit_layout`
+fn field_label(field: &str) -> &'static str {
+    match field.trim_end_matches(|c: char| c.is_ascii_digit()) {
+        "a" => "a",
+        "b" => "b",
+        "c" => "c",
+        "d" => "d",
+        "avg_pb" => "avg_pb",
+        _ => "avg_pr",
     }
-    return final_image;
 }
 
 // -----------------------------------------------------------------------------------
@@ -139,40 +326,72 @@ pub fn convert_component_video_to_rgb_float(component_video_pixel_vec: &Vec<Ypbp
     return rgb_float_vec;
 }
 
-/// Function converts the rgb float image to an rgb image
-pub fn convert_rgb_float_to_rgb(rbg_float_vec: &Vec<RGBFloat>) -> Vec<csc411_image::Rgb>{
+/// Function writes a row-major `size` x `size` block back into a row-major image `width` pixels
+/// wide, with the block's top-left pixel at column `x`, row `y`
+pub fn place_block(image: &mut [Ypbpr], width: usize, x: usize, y: usize, size: usize, block: Vec<Ypbpr>) {
+    for (offset, pixel) in block.into_iter().enumerate() {
+        image[(width * (y + offset / size)) + x + offset % size] = pixel;
+    }
+}
+
+/// Function crops a row-major rgb image down to its top-left `new_width` by `new_height` pixels
+pub fn crop_rgb(pixels: Vec<csc411_image::Rgb>, width: usize, new_width: usize, new_height: usize) -> Vec<csc411_image::Rgb> {
+    if new_width == width {
+        return pixels.into_iter().take(new_width * new_height).collect();
+    }
+
+    let cropped: Vec<csc411_image::Rgb> = pixels.chunks(width)
+        .take(new_height)
+        .flat_map(|row| row[..new_width].iter().cloned())
+        .collect();
+
+    return cropped;
+}
+
+/// Function converts the rgb float image to an rgb image with the given denominator.
+/// Values outside 0..=1 (which component video can produce) are clamped to the gamut
+/// before they are scaled, and then rounded to the nearest level
+pub fn convert_rgb_float_to_rgb(rbg_float_vec: &Vec<RGBFloat>, denominator: u16) -> Vec<csc411_image::Rgb>{
 
+    let scale = |value: f32| (value.clamp(0.0, 1.0) * denominator as f32).round() as u16;
     let pixel_data: Vec<csc411_image::Rgb> = rbg_float_vec.iter()
         .map(|el| csc411_image::Rgb {
-            red: (el.r * 255 as f32) as u16, 
-            green: (el.g * 255 as f32) as u16,
-            blue: (el.b * 255 as f32) as u16,
+            red: scale(el.r),
+            green: scale(el.g),
+            blue: scale(el.b),
         })
         .collect();
 
     return pixel_data;
 }
 
-/// Function unpacks the pixel values from the raw bytes using bitshifting
-pub fn unpack_to_pixel_values(_word_vec: Vec<[u8; 4]>) -> Vec<PixelBlockValues>{
-    
-    let mut unpacked_pixel_vec = Vec::new();
-    
-    for el in _word_vec {
+/// Function unpacks the pixel values from the raw bytes using bitshifting, reading
+/// big-endian words placed according to `layout`
+pub fn unpack_words(bytes: &[u8], layout: &WordLayout) -> Result<Vec<PixelBlockValues>, RpegError>{
 
-        // Collects the word from the raw bytes to be decompressed
-        let word = u32::from_be_bytes(el);
+    let bit_layout = layout.bit_layout()?;
+    let chroma_count = layout.chroma_count();
+    let mut unpacked_pixel_vec = Vec::new();
 
-        // Decompresses the word at points to get the a, b, c, d, avg_pb, and avg_pr values
-        let decompressed_a = getu(word as u64, 9, 23);
-        let decompressed_b = gets(word as u64, 5, 18);
-        let decompressed_c = gets(word as u64, 5, 13);
-        let decompressed_d = gets(word as u64, 5, 8);
-        let decompressed_avg_pb = getu(word as u64, 4, 4);
-        let decompressed_avg_pr = getu(word as u64, 4, 0);
+    for el in bytes.chunks_exact((layout.word_bits / 8) as usize) {
 
-        unpacked_pixel_vec.push(PixelBlockValues {a: decompressed_a as f32, b: decompressed_b as f32, c: decompressed_c as f32, d: decompressed_d as f32, avg_pb: decompressed_avg_pb as usize, avg_pr: decompressed_avg_pr as usize});
+        // Collects the word from the raw bytes to be decompressed
+        let word = el.iter().fold(0_u64, |word, &byte| (word << 8) | byte as u64);
+
+        // Decompresses the word into the a, b, c, d, avg_pb, and avg_pr values, in field order
+        let values = bit_layout.unpack(word);
+        let (luma, chroma) = values.split_at(4);
+        let (avg_pb, avg_pr) = chroma.split_at(chroma_count);
+
+        unpacked_pixel_vec.push(PixelBlockValues {
+            a: luma[0] as f32,
+            b: luma[1] as f32,
+            c: luma[2] as f32,
+            d: luma[3] as f32,
+            avg_pb: avg_pb.iter().map(|&value| value as usize).collect(),
+            avg_pr: avg_pr.iter().map(|&value| value as usize).collect(),
+        });
     }
 
-    return unpacked_pixel_vec;
-}
\ No newline at end of file
+    return Ok(unpacked_pixel_vec);
+}
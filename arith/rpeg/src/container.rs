@@ -0,0 +1,406 @@
+use crate::error::RpegError;
+use crate::transform::{BlockTransform, Haar2x2, QuantParams};
+
+// Documentation:
+// The legacy layout is the one produced by csc411_rpegio::output_rpeg_data:
+//     Compressed image format 2\n
+//     <width> <height>\n
+//     <big-endian 32-bit words, one per 2x2 block>
+//
+// Our own layout describes everything the decoder needs to know. Every number is big-endian:
+//     "RPEG"                                       magic
+//     <version u8>                                 FORMAT_VERSION
+//     <original width u32> <original height u32>  size of the image that was compressed
+//     <denominator u16>                            maxval of the image that was compressed
+//     <width u32> <height u32>                     size of the image the payload describes
+//     <transform id u8>                            which BlockTransform (or Wavelet::ID for the
+//                                                  whole-image wavelet, or Lossless::ID for the
+//                                                  lossless mode) produced the payload
+//     <parameters length u16> <parameters>         that transform's settings (for the 2x2
+//                                                  transform: word layout and quantizer)
+//     <payload length u32> <crc32 u32>             checksum of every byte before it, then of
+//                                                  the payload, so a damaged header is caught
+//     <payload>
+
+const LEGACY_MAGIC: &[u8] = b"Compressed image format ";
+const LEGACY_VERSION: u32 = 2;
+const RPEG_MAGIC: &[u8] = b"RPEG";
+
+/// Version of the rpeg layout written by `write_compressed`
+pub const FORMAT_VERSION: u8 = 3;
+
+/// Denominator of images whose stream does not record one
+pub const DEFAULT_DENOMINATOR: u16 = 255;
+
+/// Which on-disk layout a compressed image is written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ContainerFormat {
+    /// The self-describing rpeg layout
+    #[default]
+    Rpeg,
+    /// The csc411 "Compressed image format 2" layout, which only records the image size
+    Legacy,
+}
+
+/// Everything about a compressed image other than its payload
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    /// Width of the image before it was compressed
+    pub original_width: usize,
+    /// Height of the image before it was compressed
+    pub original_height: usize,
+    /// Maxval of the image before it was compressed
+    pub denominator: u16,
+    /// Width of the image the payload describes
+    pub width: usize,
+    /// Height of the image the payload describes
+    pub height: usize,
+    /// Id of the transform the payload was produced with
+    pub transform: u8,
+    /// Settings of the transform the payload was produced with
+    pub parameters: Vec<u8>,
+}
+
+impl Header {
+    /// Builds a header for an image compressed with `transform`
+    pub fn new(original_width: usize, original_height: usize, denominator: u16, width: usize, height: usize, transform: &dyn BlockTransform) -> Self {
+        Header {
+            original_width,
+            original_height,
+            denominator,
+            width,
+            height,
+            transform: transform.id(),
+            parameters: transform.parameters(),
+        }
+    }
+}
+
+/// A header together with the payload it describes
+#[derive(Clone, Debug)]
+pub struct CompressedImage {
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+
+// -----------------------------------------------------------------------------------
+//                              RPEG LAYOUT
+// -----------------------------------------------------------------------------------
+
+/// Serializes a compressed image in the requested layout
+///
+/// # Arguments:
+/// * `image`: the header and payload to write
+/// * `format`: which layout to write them in
+pub fn write_compressed(image: &CompressedImage, format: ContainerFormat) -> Result<Vec<u8>, RpegError> {
+    let header = &image.header;
+    if format == ContainerFormat::Legacy {
+        let legacy = Haar2x2::new(QuantParams::legacy());
+        if header.transform != legacy.id() || header.parameters != legacy.parameters() {
+            return Err(RpegError::IncompatibleOptions(
+                "the legacy format can only hold images from the 2x2 transform with the legacy parameters".to_string()));
+        }
+        // The denominator is not recorded, so these images always decode with DEFAULT_DENOMINATOR
+        if header.denominator != DEFAULT_DENOMINATOR {
+            return Err(RpegError::IncompatibleOptions(format!(
+                "the legacy format cannot record a denominator of {}", header.denominator)));
+        }
+        return Ok(write_legacy(&image.payload, header.width, header.height));
+    }
+
+    let mut output = Vec::with_capacity(64 + image.payload.len());
+    output.extend_from_slice(RPEG_MAGIC);
+    output.push(FORMAT_VERSION);
+    put_u32(&mut output, header.original_width)?;
+    put_u32(&mut output, header.original_height)?;
+    output.extend_from_slice(&header.denominator.to_be_bytes());
+    put_u32(&mut output, header.width)?;
+    put_u32(&mut output, header.height)?;
+
+    output.push(header.transform);
+    let parameters_length = u16::try_from(header.parameters.len())
+        .map_err(|_| RpegError::IncompatibleOptions("transform parameters are too long".to_string()))?;
+    output.extend_from_slice(&parameters_length.to_be_bytes());
+    output.extend_from_slice(&header.parameters);
+
+    put_u32(&mut output, image.payload.len())?;
+    let checksum = crc32_extend(crc32(&output), &image.payload);
+    output.extend_from_slice(&checksum.to_be_bytes());
+    output.extend_from_slice(&image.payload);
+
+    return Ok(output);
+}
+
+/// Parses a compressed image in either layout, checking the header and payload against their checksum
+///
+/// # Arguments:
+/// * `bytes`: the complete contents of a compressed image
+pub fn read_compressed(bytes: &[u8]) -> Result<CompressedImage, RpegError> {
+    if !bytes.starts_with(RPEG_MAGIC) {
+        let (payload, width, height) = read_legacy(bytes)?;
+        let header = Header::new(width, height, DEFAULT_DENOMINATOR, width, height, &Haar2x2::new(QuantParams::legacy()));
+        return Ok(CompressedImage { header, payload });
+    }
+
+    let mut rest = &bytes[RPEG_MAGIC.len()..];
+    let version = take_array::<1>(&mut rest)?[0];
+    if version != FORMAT_VERSION {
+        return Err(RpegError::UnsupportedVersion(version as u32));
+    }
+
+    let original_width = take_u32(&mut rest)?;
+    let original_height = take_u32(&mut rest)?;
+    let denominator = u16::from_be_bytes(take_array(&mut rest)?);
+    if denominator == 0 {
+        return Err(RpegError::InvalidHeader("denominator is zero".to_string()));
+    }
+    let width = take_u32(&mut rest)?;
+    let height = take_u32(&mut rest)?;
+
+    let transform = take_array::<1>(&mut rest)?[0];
+    let parameters_length = u16::from_be_bytes(take_array(&mut rest)?) as usize;
+    if rest.len() < parameters_length {
+        return Err(RpegError::Truncated);
+    }
+    let (parameters, after) = rest.split_at(parameters_length);
+    rest = after;
+
+    let payload_length = take_u32(&mut rest)?;
+    let header_bytes = &bytes[..bytes.len() - rest.len()];
+    let expected = u32::from_be_bytes(take_array(&mut rest)?);
+    if rest.len() < payload_length {
+        return Err(RpegError::Truncated);
+    }
+    let payload = rest[..payload_length].to_vec();
+
+    let actual = crc32_extend(crc32(header_bytes), &payload);
+    if actual != expected {
+        return Err(RpegError::ChecksumMismatch { expected, actual });
+    }
+
+    let header = Header {
+        original_width,
+        original_height,
+        denominator,
+        width,
+        height,
+        transform,
+        parameters: parameters.to_vec(),
+    };
+    return Ok(CompressedImage { header, payload });
+}
+
+// -----------------------------------------------------------------------------------
+//                              LEGACY LAYOUT
+// -----------------------------------------------------------------------------------
+
+/// Serializes packed words into the csc411 "Compressed image format 2" layout
+///
+/// # Arguments:
+/// * `payload`: the packed words, one per 2x2 block in row-major block order
+/// * `width`: the width of the (trimmed) image the words describe
+/// * `height`: the height of the (trimmed) image the words describe
+pub fn write_legacy(payload: &[u8], width: usize, height: usize) -> Vec<u8> {
+
+    let mut output = Vec::with_capacity(LEGACY_MAGIC.len() + 24 + payload.len());
+    output.extend_from_slice(LEGACY_MAGIC);
+    output.extend_from_slice(format!("{}\n{} {}\n", LEGACY_VERSION, width, height).as_bytes());
+    output.extend_from_slice(payload);
+
+    return output;
+}
+
+/// Parses a csc411 "Compressed image format 2" buffer back into its packed words
+/// and the width and height they describe
+///
+/// # Arguments:
+/// * `bytes`: the complete contents of a compressed image
+pub fn read_legacy(bytes: &[u8]) -> Result<(Vec<u8>, usize, usize), RpegError> {
+
+    if !bytes.starts_with(LEGACY_MAGIC) {
+        return Err(RpegError::InvalidHeader("missing \"Compressed image format\" line".to_string()));
+    }
+    let (version_line, rest) = split_line(&bytes[LEGACY_MAGIC.len()..])?;
+
+    let version = version_line.trim().parse::<u32>()
+        .map_err(|_| RpegError::InvalidHeader(format!("bad format version \"{}\"", version_line)))?;
+    if version != LEGACY_VERSION {
+        return Err(RpegError::UnsupportedVersion(version));
+    }
+
+    // The dimensions are written as text on their own line
+    let (line, payload) = split_line(rest)?;
+    let dimensions: Vec<usize> = line.split_whitespace()
+        .map(|field| field.parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| RpegError::InvalidHeader(format!("bad dimensions \"{}\"", line)))?;
+    if dimensions.len() != 2 {
+        return Err(RpegError::InvalidHeader(format!("bad dimensions \"{}\"", line)));
+    }
+
+    // Everything after the dimensions line is raw big-endian words
+    return Ok((payload.to_vec(), dimensions[0], dimensions[1]));
+}
+
+/// Splits the text line at the front of `bytes` from whatever follows its newline
+fn split_line(bytes: &[u8]) -> Result<(&str, &[u8]), RpegError> {
+    let line_end = bytes.iter().position(|&byte| byte == b'\n').ok_or(RpegError::Truncated)?;
+    let line = std::str::from_utf8(&bytes[..line_end])
+        .map_err(|_| RpegError::InvalidHeader("header line is not text".to_string()))?;
+
+    return Ok((line, &bytes[line_end + 1..]));
+}
+
+// -----------------------------------------------------------------------------------
+//                              HELPERS
+// -----------------------------------------------------------------------------------
+
+/// Appends `value` as a big-endian u32, failing if it is too large to be recorded
+fn put_u32(output: &mut Vec<u8>, value: usize) -> Result<(), RpegError> {
+    let value = u32::try_from(value)
+        .map_err(|_| RpegError::IncompatibleOptions(format!("{} is too large for the header", value)))?;
+    output.extend_from_slice(&value.to_be_bytes());
+    return Ok(());
+}
+
+/// Removes a big-endian u32 from the front of `bytes`
+fn take_u32(bytes: &mut &[u8]) -> Result<usize, RpegError> {
+    return Ok(u32::from_be_bytes(take_array(bytes)?) as usize);
+}
+
+/// Removes the first `N` bytes from the front of `bytes`
+pub(crate) fn take_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], RpegError> {
+    if bytes.len() < N {
+        return Err(RpegError::Truncated);
+    }
+    let (front, rest) = bytes.split_at(N);
+    *bytes = rest;
+
+    let mut array = [0_u8; N];
+    array.copy_from_slice(front);
+    return Ok(array);
+}
+
+/// Lookup table for the reflected CRC-32 polynomial 0xEDB88320 (the one used by zip and png)
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+/// Computes the CRC-32 checksum of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    return crc32_extend(0, bytes);
+}
+
+/// Turns `crc`, the checksum of some bytes, into the checksum of those bytes followed by `bytes`
+pub fn crc32_extend(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    return !crc;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small image from the legacy 2x2 transform, which either layout can hold
+    fn legacy_image() -> CompressedImage {
+        let header = Header::new(3, 2, DEFAULT_DENOMINATOR, 4, 2, &Haar2x2::new(QuantParams::legacy()));
+        CompressedImage { header, payload: vec![0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0] }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let image = legacy_image();
+        let bytes = write_compressed(&image, ContainerFormat::Rpeg).unwrap();
+        assert!(bytes.starts_with(RPEG_MAGIC));
+        let read = read_compressed(&bytes).unwrap();
+        assert_eq!(read.header, image.header);
+        assert_eq!(read.payload, image.payload);
+    }
+
+    #[test]
+    fn checksum_test() {
+        let mut bytes = write_compressed(&legacy_image(), ContainerFormat::Rpeg).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        assert!(matches!(read_compressed(&bytes), Err(RpegError::ChecksumMismatch { .. })));
+
+        // The header is covered too: here the original width
+        let mut bytes = write_compressed(&legacy_image(), ContainerFormat::Rpeg).unwrap();
+        bytes[RPEG_MAGIC.len() + 4] ^= 0x01;
+        assert!(matches!(read_compressed(&bytes), Err(RpegError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn truncated_test() {
+        let bytes = write_compressed(&legacy_image(), ContainerFormat::Rpeg).unwrap();
+        for length in RPEG_MAGIC.len()..bytes.len() {
+            assert!(matches!(read_compressed(&bytes[..length]), Err(RpegError::Truncated)), "cut at {}", length);
+        }
+    }
+
+    #[test]
+    fn version_test() {
+        let mut bytes = write_compressed(&legacy_image(), ContainerFormat::Rpeg).unwrap();
+        for version in [0, FORMAT_VERSION - 1, FORMAT_VERSION + 1, u8::MAX] {
+            bytes[RPEG_MAGIC.len()] = version;
+            assert!(matches!(read_compressed(&bytes), Err(RpegError::UnsupportedVersion(v)) if v == version as u32));
+        }
+    }
+
+    #[test]
+    fn legacy_test() {
+        let image = legacy_image();
+        let bytes = write_compressed(&image, ContainerFormat::Legacy).unwrap();
+        assert_eq!(&bytes[..LEGACY_MAGIC.len() + 6], b"Compressed image format 2\n4 2\n");
+
+        // Only the coded size survives, and the denominator is always the default
+        let read = read_compressed(&bytes).unwrap();
+        assert_eq!((read.header.original_width, read.header.original_height), (4, 2));
+        assert_eq!((read.header.width, read.header.height), (4, 2));
+        assert_eq!(read.header.denominator, DEFAULT_DENOMINATOR);
+        assert_eq!(read.header.parameters, image.header.parameters);
+        assert_eq!(read.payload, image.payload);
+    }
+
+    #[test]
+    fn legacy_error_test() {
+        assert!(matches!(read_legacy(b"Compressed image format 3\n2 2\n"), Err(RpegError::UnsupportedVersion(3))));
+        assert!(matches!(read_legacy(b"Compressed image format 2\n2 2"), Err(RpegError::Truncated)));
+        assert!(matches!(read_legacy(b"Compressed image format 2\n2\n"), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(read_legacy(b"Compressed image format x\n2 2\n"), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(read_compressed(b"P6\n2 2\n255\n"), Err(RpegError::InvalidHeader(_))));
+
+        // Other transforms and settings have no place in the legacy layout
+        let mut image = legacy_image();
+        image.header.parameters[0] = 64;
+        assert!(matches!(write_compressed(&image, ContainerFormat::Legacy), Err(RpegError::IncompatibleOptions(_))));
+        let mut image = legacy_image();
+        image.header.denominator = 65535;
+        assert!(matches!(write_compressed(&image, ContainerFormat::Legacy), Err(RpegError::IncompatibleOptions(_))));
+    }
+
+    #[test]
+    fn crc32_test() {
+        // The check value of CRC-32/ISO-HDLC
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32_extend(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,408 @@
+use std::f32::consts::PI;
+use bitpack::bitio::{BitReader, BitWriter};
+use bitpack::codes::{read_leb128, unzigzag, write_leb128, zigzag};
+use crate::chroma::ChromaSampling;
+use crate::compress_decompress::Ypbpr;
+use crate::container::take_array;
+use crate::error::RpegError;
+use crate::entropy::stream_error;
+use crate::rle::{write_run_length, read_run_length};
+use crate::transform::{check_block, BlockTransform, Coefficient, CoefficientKind};
+
+// Documentation:
+// JPEG (ITU T.81) Annex A.3 describes the DCT, Annex K.1 the example quantization tables,
+// and Figure A.6 the zig-zag order. The quality scaling follows the Independent JPEG Group's
+// libjpeg: below 50 the tables are scaled by 5000 / quality percent, above it by 200 - 2 * quality.
+
+/// Side length of a luma block
+const BLOCK: usize = 8;
+
+/// ITU T.81 Table K.1, luminance quantization table in row-major order
+const LUMA_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// ITU T.81 Table K.2, chrominance quantization table in row-major order
+const CHROMA_TABLE: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// One channel of a block: its size, its quantizer steps and its zig-zag order
+#[derive(Clone, Debug)]
+struct Plane {
+    width: usize,
+    height: usize,
+    /// Quantizer step for each coefficient, in row-major order
+    steps: Vec<f32>,
+    /// Row-major index of each coefficient, in zig-zag order
+    zigzag: Vec<usize>,
+    /// DCT bases along a row and down a column
+    row_basis: Vec<f32>,
+    column_basis: Vec<f32>,
+}
+
+impl Plane {
+    /// Builds a `width` x `height` plane quantized by the top-left corner of `table` at `quality`
+    fn new(width: usize, height: usize, table: &[u16; 64], quality: u8) -> Self {
+
+        // The orthonormal DCT of a smaller block has proportionally smaller coefficients,
+        // so the steps shrink with it to keep the same precision per pixel
+        let norm = ((width * height) as f32).sqrt() / BLOCK as f32;
+        let scale = quality_scale(quality);
+
+        let mut steps = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let entry = ((table[(y * BLOCK) + x] as u32 * scale + 50) / 100).clamp(1, 255);
+                steps.push(entry as f32 * norm);
+            }
+        }
+
+        Plane {
+            width,
+            height,
+            steps,
+            zigzag: zigzag_order(width, height),
+            row_basis: dct_basis(width),
+            column_basis: dct_basis(height),
+        }
+    }
+
+    /// Transforms and quantizes `samples`, returning coefficients in zig-zag order
+    fn forward(&self, samples: &[f32]) -> Vec<i64> {
+        let coefficients = self.dct_2d(samples, false);
+        return self.zigzag.iter()
+            .map(|&index| (coefficients[index] / self.steps[index]).round() as i64)
+            .collect();
+    }
+
+    /// Dequantizes and inverse transforms zig-zag ordered `coefficients` back into samples
+    fn inverse(&self, coefficients: &[i64]) -> Vec<f32> {
+        let mut dequantized = vec![0.0; self.width * self.height];
+        for (position, &index) in self.zigzag.iter().enumerate() {
+            dequantized[index] = coefficients[position] as f32 * self.steps[index];
+        }
+        return self.dct_2d(&dequantized, true);
+    }
+
+    /// Separable 2D DCT-II of a row-major block, or the DCT-III (its inverse) when `inverse`
+    /// is set. The bases are orthonormal, so the inverse just uses their transposes
+    fn dct_2d(&self, samples: &[f32], inverse: bool) -> Vec<f32> {
+        let (width, height) = (self.width, self.height);
+
+        // Entry (i, j) of a 1D transform matrix, transposed for the inverse
+        let entry = |basis: &[f32], n: usize, i: usize, j: usize| {
+            if inverse { basis[(j * n) + i] } else { basis[(i * n) + j] }
+        };
+
+        // Transform every row, then every column of the result
+        let mut rows = vec![0.0; width * height];
+        for y in 0..height {
+            for u in 0..width {
+                rows[(y * width) + u] = (0..width)
+                    .map(|x| entry(&self.row_basis, width, u, x) * samples[(y * width) + x])
+                    .sum();
+            }
+        }
+
+        let mut output = vec![0.0; width * height];
+        for v in 0..height {
+            for u in 0..width {
+                output[(v * width) + u] = (0..height)
+                    .map(|y| entry(&self.column_basis, height, v, y) * rows[(y * width) + u])
+                    .sum();
+            }
+        }
+        return output;
+    }
+
+    fn len(&self) -> usize {
+        self.width * self.height
+    }
+}
+
+/// 8x8 DCT-II with JPEG-style quantization. Luma is coded at full resolution and each
+/// chroma channel is reduced according to `sampling` first (to 4x4 for 4:2:0, so every block
+/// yields 64 + 16 + 16 coefficients), each channel in zig-zag order
+#[derive(Clone, Debug)]
+pub struct Dct8x8 {
+    pub quality: u8,
+    pub sampling: ChromaSampling,
+    luma: Plane,
+    chroma: Plane,
+}
+
+impl Dct8x8 {
+    pub const ID: u8 = 1;
+    pub const NAME: &'static str = "dct";
+
+    /// Builds the transform with its tables scaled for `quality` (clamped to 1..=100) and 4:2:0 chroma
+    pub fn new(quality: u8) -> Self {
+        Dct8x8::with_sampling(quality, ChromaSampling::Yuv420)
+    }
+
+    pub fn with_sampling(quality: u8, sampling: ChromaSampling) -> Self {
+        let quality = quality.clamp(1, 100);
+        let (chroma_width, chroma_height) = sampling.plane_size(BLOCK, BLOCK);
+        Dct8x8 {
+            quality,
+            sampling,
+            luma: Plane::new(BLOCK, BLOCK, &LUMA_TABLE, quality),
+            chroma: Plane::new(chroma_width, chroma_height, &CHROMA_TABLE, quality),
+        }
+    }
+
+    /// Parses the bytes written by `parameters`
+    pub fn from_parameters(parameters: &[u8]) -> Result<Self, RpegError> {
+        let mut rest = parameters;
+        let [quality, sampling] = take_array::<2>(&mut rest)?;
+        if quality == 0 || quality > 100 {
+            return Err(RpegError::InvalidHeader(format!("dct quality {} is out of range", quality)));
+        }
+        if !rest.is_empty() {
+            return Err(RpegError::InvalidHeader(format!("{} unexpected bytes after the dct parameters", rest.len())));
+        }
+        return Ok(Dct8x8::with_sampling(quality, ChromaSampling::from_id(sampling)?));
+    }
+
+    fn planes(&self) -> [&Plane; 3] {
+        [&self.luma, &self.chroma, &self.chroma]
+    }
+}
+
+impl BlockTransform for Dct8x8 {
+    fn id(&self) -> u8 {
+        Dct8x8::ID
+    }
+
+    fn name(&self) -> &'static str {
+        Dct8x8::NAME
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK
+    }
+
+    fn sampling(&self) -> ChromaSampling {
+        self.sampling
+    }
+
+    fn layout(&self) -> Vec<Coefficient> {
+        // With the smallest step of 1, a DC of up to 8 * 128 needs 12 signed bits
+        let names = ["y", "pb", "pr"];
+        let mut layout = Vec::new();
+        for (plane, name) in self.planes().iter().zip(names) {
+            layout.extend((0..plane.len()).map(|_| Coefficient { name, kind: CoefficientKind::Signed, bits: 12 }));
+        }
+        return layout;
+    }
+
+    fn parameters(&self) -> Vec<u8> {
+        vec![self.quality, self.sampling.id()]
+    }
+
+    fn forward(&self, pixels: &[Ypbpr]) -> Result<Vec<i64>, RpegError> {
+        if pixels.len() != BLOCK * BLOCK {
+            return Err(RpegError::DimensionMismatch { expected: BLOCK * BLOCK, actual: pixels.len() });
+        }
+
+        // Work in the 8-bit sample range JPEG's tables were designed for, centred on zero
+        let luma: Vec<f32> = pixels.iter().map(|pixel| (pixel.y * 255.0) - 128.0).collect();
+        let pb: Vec<f32> = self.sampling.downsample(pixels, BLOCK, BLOCK, |pixel| pixel.pb * 255.0);
+        let pr: Vec<f32> = self.sampling.downsample(pixels, BLOCK, BLOCK, |pixel| pixel.pr * 255.0);
+
+        let mut coefficients = self.luma.forward(&luma);
+        coefficients.extend(self.chroma.forward(&pb));
+        coefficients.extend(self.chroma.forward(&pr));
+        return Ok(coefficients);
+    }
+
+    fn inverse(&self, coefficients: &[i64]) -> Result<Vec<Ypbpr>, RpegError> {
+        let expected = self.luma.len() + 2 * self.chroma.len();
+        if coefficients.len() != expected {
+            return Err(RpegError::DimensionMismatch { expected, actual: coefficients.len() });
+        }
+
+        let (luma, chroma) = coefficients.split_at(self.luma.len());
+        let (pb, pr) = chroma.split_at(self.chroma.len());
+        let luma = self.luma.inverse(luma);
+        let pb = self.chroma.inverse(pb);
+        let pr = self.chroma.inverse(pr);
+
+        // Each chroma sample is copied to every pixel it was averaged from
+        let mut pixels = Vec::with_capacity(BLOCK * BLOCK);
+        for y in 0..BLOCK {
+            for x in 0..BLOCK {
+                let chroma_index = self.sampling.sample_index(x, y, self.chroma.width);
+                pixels.push(Ypbpr {
+                    y: (luma[(y * BLOCK) + x] + 128.0) / 255.0,
+                    pb: pb[chroma_index] / 255.0,
+                    pr: pr[chroma_index] / 255.0,
+                });
+            }
+        }
+        return Ok(pixels);
+    }
+
+    fn write_blocks(&self, blocks: &[Vec<i64>], _columns: usize) -> Result<Vec<u8>, RpegError> {
+
+        // As in JPEG, each DC coefficient is stored as the difference from the previous
+        // block's DC in the same channel, and the AC coefficients as zero runs
+        let mut writer = BitWriter::new(Vec::new());
+        let mut previous_dc = [0_i64; 3];
+        for block in blocks {
+            let mut rest = block.as_slice();
+            for (channel, plane) in self.planes().iter().enumerate() {
+                if rest.len() < plane.len() {
+                    return Err(RpegError::DimensionMismatch { expected: plane.len(), actual: rest.len() });
+                }
+                let (coefficients, after) = rest.split_at(plane.len());
+                rest = after;
+
+                write_leb128(&mut writer, zigzag(coefficients[0] - previous_dc[channel])).map_err(stream_error)?;
+                previous_dc[channel] = coefficients[0];
+                write_run_length(&coefficients[1..], &mut writer)?;
+            }
+        }
+        return writer.into_inner().map_err(stream_error);
+    }
+
+    fn read_blocks(&self, payload: &[u8], _columns: usize, count: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+        let mut reader = BitReader::new(payload);
+        let layout = self.layout();
+        let mut previous_dc = [0_i64; 3];
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            let mut block = Vec::with_capacity(self.luma.len() + 2 * self.chroma.len());
+            for (channel, plane) in self.planes().iter().enumerate() {
+                let difference = unzigzag(read_leb128(&mut reader).map_err(stream_error)?);
+                let dc = previous_dc[channel].checked_add(difference)
+                    .ok_or(RpegError::FieldOverflow { field: layout[block.len()].name, value: difference })?;
+                previous_dc[channel] = dc;
+                block.push(dc);
+                block.extend(read_run_length(&mut reader, plane.len() - 1)?);
+            }
+
+            // Corrupt levels would otherwise decode to garbage rather than fail
+            check_block(&block, &layout)?;
+            blocks.push(block);
+        }
+        return Ok(blocks);
+    }
+}
+
+// -----------------------------------------------------------------------------------
+//                              HELPERS
+// -----------------------------------------------------------------------------------
+
+/// Scale factor (in percent) that libjpeg applies to the base tables at `quality`
+fn quality_scale(quality: u8) -> u32 {
+    let quality = quality.clamp(1, 100) as u32;
+    if quality < 50 {
+        5000 / quality
+    } else {
+        200 - (2 * quality)
+    }
+}
+
+/// Orthonormal DCT-II basis of size `n`, where row `u` holds frequency `u` sampled at each position
+fn dct_basis(n: usize) -> Vec<f32> {
+    let mut basis = Vec::with_capacity(n * n);
+    for u in 0..n {
+        let alpha = if u == 0 { (1.0 / n as f32).sqrt() } else { (2.0 / n as f32).sqrt() };
+        for x in 0..n {
+            basis.push(alpha * (((2 * x + 1) as f32 * u as f32 * PI) / (2 * n) as f32).cos());
+        }
+    }
+    return basis;
+}
+
+/// Row-major indices of a `width` x `height` block in zig-zag order: along each anti-diagonal,
+/// alternating direction, starting from the top-left corner
+fn zigzag_order(width: usize, height: usize) -> Vec<usize> {
+    let mut order = Vec::with_capacity(width * height);
+    for diagonal in 0..(width + height - 1) {
+        let mut positions: Vec<(usize, usize)> = (0..height)
+            .filter(|&y| y <= diagonal && diagonal - y < width)
+            .map(|y| (diagonal - y, y))
+            .collect();
+
+        // Odd diagonals run down-left, even ones up-right
+        if diagonal % 2 == 0 {
+            positions.reverse();
+        }
+        order.extend(positions.iter().map(|&(x, y)| (y * width) + x));
+    }
+    return order;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zigzag_test() {
+        // The first entries of ITU T.81 Figure A.6
+        assert_eq!(zigzag_order(8, 8)[..10], [0, 1, 8, 16, 9, 2, 3, 10, 17, 24]);
+        assert_eq!(zigzag_order(4, 8).len(), 32);
+    }
+
+    #[test]
+    fn payload_test() {
+        let transform = Dct8x8::new(75);
+        let mut blocks = Vec::new();
+        for seed in 0..4_i64 {
+            let mut block = vec![0_i64; 64 + 16 + 16];
+            block[0] = 40 - (30 * seed);
+            block[1] = seed - 2;
+            block[63] = 7;
+            block[64] = -300 * seed;
+            block[95] = i64::from(seed == 2);
+            blocks.push(block);
+        }
+        let payload = transform.write_blocks(&blocks, 2).unwrap();
+        assert_eq!(transform.read_blocks(&payload, 2, 4).unwrap(), blocks);
+        assert!(transform.read_blocks(&payload[..payload.len() - 1], 2, 4).is_err());
+    }
+
+    #[test]
+    fn out_of_range_test() {
+        // Every coefficient is 12 bits signed, so a DC or AC level past 2047 cannot be decoded
+        let transform = Dct8x8::new(75);
+        let in_range = vec![0_i64; 64 + 16 + 16];
+        for (index, value) in [(0, 2048), (0, -2049), (64, 5000), (1, -3000), (80, i64::MAX)] {
+            let mut block = in_range.clone();
+            block[index] = value;
+            let payload = transform.write_blocks(&[in_range.clone(), block], 2).unwrap();
+            let result = transform.read_blocks(&payload, 2, 2);
+            assert!(matches!(result, Err(RpegError::FieldOverflow { .. })), "{} at {} gave {:?}", value, index, result);
+        }
+    }
+
+    #[test]
+    fn parameters_test() {
+        for sampling in ChromaSampling::ALL {
+            let transform = Dct8x8::with_sampling(30, sampling);
+            let rebuilt = Dct8x8::from_parameters(&transform.parameters()).unwrap();
+            assert_eq!((rebuilt.quality, rebuilt.sampling), (30, sampling));
+        }
+        assert!(matches!(Dct8x8::from_parameters(&[0, 2]), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(Dct8x8::from_parameters(&[101, 2]), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(Dct8x8::from_parameters(&[30]), Err(RpegError::Truncated)));
+        assert!(matches!(Dct8x8::from_parameters(&[30, 2, 0]), Err(RpegError::InvalidHeader(_))));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::compress_decompress::Ypbpr;
+
+// Documentation:
+// The filter follows the shape of the H.264 loop filter (ITU-T H.264 section 8.7): for each
+// line of pixels crossing a block edge, p1 p0 | q0 q1, the edge is only smoothed when the
+// step |p0 - q0| is small enough to be a quantization artifact rather than a real edge, and
+// only as far as the blocks on either side are smooth. Block smoothness is measured as the
+// mean absolute deviation of luma from the block's mean, which tracks the magnitude of the
+// block's AC coefficients.
+
+/// Settings for the deblocking filter run over decoded block-transform images
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Deblock {
+    /// Scales every threshold; 1.0 suits the default quality, larger values smooth more.
+    /// Zero or less turns the filter off
+    pub strength: f32,
+}
+
+impl Default for Deblock {
+    fn default() -> Self {
+        Deblock { strength: 1.0 }
+    }
+}
+
+impl Deblock {
+    /// Largest step across an edge (in luma, 0..=1) that is treated as an artifact
+    fn edge_threshold(&self) -> f32 {
+        0.06 * self.strength
+    }
+
+    /// Block activity above which a block is left alone as texture
+    fn activity_threshold(&self) -> f32 {
+        0.02 * self.strength
+    }
+
+    /// Largest change the filter may make to a pixel next to the edge
+    fn clip(&self) -> f32 {
+        0.025 * self.strength
+    }
+
+    /// Smooths the block edges of a row-major image in place
+    ///
+    /// # Arguments:
+    /// * `image`: the decoded image
+    /// * `width`: the width of the image
+    /// * `height`: the height of the image
+    /// * `block_size`: the side length of the blocks the image was coded in
+    pub fn apply(&self, image: &mut [Ypbpr], width: usize, height: usize, block_size: usize) {
+        if block_size < 2 || width < block_size || height < block_size {
+            return;
+        }
+        let activity = block_activity(image, width, height, block_size);
+        let blocks_across = width.div_ceil(block_size);
+
+        // Vertical edges, filtered along each row
+        for y in 0..height {
+            for x in (block_size..width).step_by(block_size) {
+                let left = activity[((y / block_size) * blocks_across) + (x / block_size) - 1];
+                let right = activity[((y / block_size) * blocks_across) + (x / block_size)];
+                let line = [(y * width) + x - 2, (y * width) + x - 1, (y * width) + x, (y * width) + x + 1];
+                self.filter_line(image, line, left, right);
+            }
+        }
+
+        // Horizontal edges, filtered down each column
+        for y in (block_size..height).step_by(block_size) {
+            for x in 0..width {
+                let above = activity[(((y / block_size) - 1) * blocks_across) + (x / block_size)];
+                let below = activity[((y / block_size) * blocks_across) + (x / block_size)];
+                let line = [((y - 2) * width) + x, ((y - 1) * width) + x, (y * width) + x, ((y + 1) * width) + x];
+                self.filter_line(image, line, above, below);
+            }
+        }
+    }
+
+    /// Filters the four pixels p1, p0, q0, q1 at `line` that straddle an edge between
+    /// blocks with activities `p_activity` and `q_activity`
+    fn filter_line(&self, image: &mut [Ypbpr], line: [usize; 4], p_activity: f32, q_activity: f32) {
+        let [p1, p0, q0, q1] = line;
+
+        // A strength of zero or less (or NaN) leaves nothing to filter, and would divide by zero below
+        let threshold = self.activity_threshold();
+        if threshold.is_nan() || threshold <= 0.0 {
+            return;
+        }
+        let roughest = p_activity.max(q_activity);
+        if roughest > threshold || (image[p0].y - image[q0].y).abs() > self.edge_threshold() {
+            return;
+        }
+
+        // Smoother neighbours let the filter move pixels further
+        let clip = self.clip() * (1.0 - (roughest / threshold));
+        let flat = roughest < threshold / 4.0;
+
+        let smooth = |values: [f32; 4]| {
+            let [p1, p0, q0, q1] = values;
+            let delta = (((q0 - p0) * 4.0 + (p1 - q1)) / 8.0).clamp(-clip, clip);
+            if flat {
+                [p1 + (delta / 2.0), p0 + delta, q0 - delta, q1 - (delta / 2.0)]
+            } else {
+                [p1, p0 + delta, q0 - delta, q1]
+            }
+        };
+
+        let y = smooth(line.map(|index| image[index].y));
+        let pb = smooth(line.map(|index| image[index].pb));
+        let pr = smooth(line.map(|index| image[index].pr));
+        for (i, &index) in [p1, p0, q0, q1].iter().enumerate() {
+            image[index] = Ypbpr { y: y[i], pb: pb[i], pr: pr[i] };
+        }
+    }
+}
+
+/// Mean absolute deviation of luma from its mean, for each block in row-major block order
+fn block_activity(image: &[Ypbpr], width: usize, height: usize, block_size: usize) -> Vec<f32> {
+    let mut activity = Vec::new();
+    for top in (0..height).step_by(block_size) {
+        for left in (0..width).step_by(block_size) {
+            let pixels: Vec<f32> = (top..(top + block_size).min(height))
+                .flat_map(|row| (left..(left + block_size).min(width)).map(move |col| (row * width) + col))
+                .map(|index| image[index].y)
+                .collect();
+            let mean = pixels.iter().sum::<f32>() / pixels.len() as f32;
+            activity.push(pixels.iter().map(|value| (value - mean).abs()).sum::<f32>() / pixels.len() as f32);
+        }
+    }
+    return activity;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two flat 2x2 blocks side by side with a small step in luma between them
+    fn step() -> Vec<Ypbpr> {
+        (0..8).map(|i| Ypbpr { y: if i % 4 < 2 { 0.50 } else { 0.52 }, pb: 0.0, pr: 0.0 }).collect()
+    }
+
+    #[test]
+    fn smooth_test() {
+        let mut image = step();
+        Deblock::default().apply(&mut image, 4, 2, 2);
+        assert!(image[1].y > 0.50 && image[2].y < 0.52);
+        assert!((image[1].y + image[2].y - 1.02).abs() < 1e-6);
+    }
+
+    #[test]
+    fn strength_test() {
+        for strength in [0.0, -1.0, f32::NAN] {
+            let mut image = step();
+            Deblock { strength }.apply(&mut image, 4, 2, 2);
+            assert!(image.iter().zip(step()).all(|(after, before)| after.y == before.y), "strength {}", strength);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::io;
+use bitpack::bitio::{BitReader, BitWriter};
+use bitpack::codes::{read_exp_golomb, read_signed_exp_golomb, unzigzag, write_exp_golomb, write_signed_exp_golomb, zigzag};
+use bitpack::huffman::HuffmanCode;
+use crate::error::RpegError;
+use crate::transform::{Coefficient, CoefficientKind};
+
+// Documentation:
+// The 2x2 transform's b, c and d cluster around zero, so the variable-length coding writes them
+// as zig-zag mapped Exp-Golomb codes (order 0: 1 bit for 0, 3 bits for +-1, 5 bits for +-2 or
+// +-3, ...) in place of fixed 5-bit fields. Unsigned values (a and the chroma indices) keep
+// their fixed widths, and counts (the skip runs) are plain Exp-Golomb codes. Blocks follow one
+// another with no padding, and the payload ends with zero bits up to a whole byte.
+//
+// The Huffman coding makes two passes: the first counts how often each value of b, c, d and
+// the chroma indices occurs, the second writes each as its canonical Huffman code. Each
+// coefficient name (b, c, d, avg_pb, avg_pr) has its own table, shared by every chroma sample
+// of a block, and the tables head the payload in that order. `a` keeps its fixed width, as it
+// takes too many values to be worth a table, unless prediction has turned it into a signed
+// difference. Signed values are zig-zag mapped into symbols.
+
+/// How a block transform's quantized coefficients are written into the payload
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Coding {
+    /// Every coefficient in its own fixed-width field of a word
+    #[default]
+    Fixed,
+    /// Signed coefficients as Exp-Golomb codes, unsigned ones in fixed-width fields
+    VariableLength,
+    /// Every coefficient through the adaptive binary arithmetic coder in `arithmetic`
+    Arithmetic,
+    /// Every coefficient but `a` as a code from a canonical Huffman table stored in the payload
+    Huffman,
+}
+
+impl Coding {
+    /// Every coding, in the order of their ids
+    pub const ALL: [Coding; 4] = [Coding::Fixed, Coding::VariableLength, Coding::Arithmetic, Coding::Huffman];
+
+    /// Value recorded in the stream for this coding
+    pub fn id(&self) -> u8 {
+        match self {
+            Coding::Fixed => 0,
+            Coding::VariableLength => 1,
+            Coding::Arithmetic => 2,
+            Coding::Huffman => 3,
+        }
+    }
+
+    /// Parses the value written by `id`
+    pub fn from_id(id: u8) -> Result<Self, RpegError> {
+        return Coding::ALL.get(id as usize).copied()
+            .ok_or(RpegError::InvalidHeader(format!("unknown coefficient coding {}", id)));
+    }
+
+    /// Looks up a coding by the name the command line uses for it
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "fixed" => Some(Coding::Fixed),
+            "vlc" => Some(Coding::VariableLength),
+            "arith" => Some(Coding::Arithmetic),
+            "huffman" => Some(Coding::Huffman),
+            _ => None,
+        }
+    }
+}
+
+/// Maps bitstream errors onto the codec's: running out of bits means the payload was cut short,
+/// and bits that decode to nothing valid mean it is corrupt
+pub(crate) fn stream_error(err: io::Error) -> RpegError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => RpegError::Truncated,
+        io::ErrorKind::InvalidData => RpegError::InvalidHeader(format!("corrupt payload: {}", err)),
+        _ => RpegError::Io(err),
+    }
+}
+
+/// Fails early if `payload` is too short to hold `count` blocks of at least `block_bits` bits
+/// each, so that a count from a corrupt header is never trusted
+fn check_count(payload: &[u8], count: usize, block_bits: u64) -> Result<(), RpegError> {
+    if count as u128 * block_bits as u128 > payload.len() as u128 * 8 {
+        return Err(RpegError::Truncated);
+    }
+    return Ok(());
+}
+
+/// Writes every block's coefficients with the variable-length coding
+///
+/// # Arguments:
+/// * `blocks`: the coefficients of each block
+/// * `layout`: describes each coefficient of a block, as the transform's `layout` returns
+pub fn write_variable(blocks: &[Vec<i64>], layout: &[Coefficient]) -> Result<Vec<u8>, RpegError> {
+    let mut writer = BitWriter::new(Vec::new());
+    for block in blocks {
+        if block.len() != layout.len() {
+            return Err(RpegError::DimensionMismatch { expected: layout.len(), actual: block.len() });
+        }
+        for (&value, coefficient) in block.iter().zip(layout) {
+            let unsigned = || u64::try_from(value).map_err(|_| RpegError::FieldOverflow { field: coefficient.name, value });
+            let written = match coefficient.kind {
+                CoefficientKind::Signed => write_signed_exp_golomb(&mut writer, value, 0),
+                CoefficientKind::Unsigned => writer.write_bits(unsigned()?, coefficient.bits),
+                CoefficientKind::Count => write_exp_golomb(&mut writer, unsigned()?, 0),
+            };
+            written.map_err(|err| match err.kind() {
+                io::ErrorKind::InvalidInput => RpegError::FieldOverflow { field: coefficient.name, value },
+                _ => RpegError::Io(err),
+            })?;
+        }
+    }
+    return writer.into_inner().map_err(stream_error);
+}
+
+/// Reads `count` blocks written by `write_variable` with the same `layout`
+pub fn read_variable(payload: &[u8], layout: &[Coefficient], count: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+    // Exp-Golomb codes take at least a bit and unsigned values their whole width
+    let block_bits = layout.iter()
+        .map(|coefficient| if coefficient.kind == CoefficientKind::Unsigned { coefficient.bits } else { 1 })
+        .sum();
+    check_count(payload, count, block_bits)?;
+    let mut reader = BitReader::new(payload);
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        let block = layout.iter()
+            .map(|coefficient| {
+                let value = match coefficient.kind {
+                    CoefficientKind::Signed => read_signed_exp_golomb(&mut reader, 0),
+                    CoefficientKind::Unsigned => reader.read_bits(coefficient.bits).map(|value| value as i64),
+                    CoefficientKind::Count => read_exp_golomb(&mut reader, 0).map(|value| value as i64),
+                }.map_err(stream_error)?;
+
+                // An Exp-Golomb code can stand for a value wider than the coefficient
+                return match coefficient.holds(value) {
+                    true => Ok(value),
+                    false => Err(RpegError::FieldOverflow { field: coefficient.name, value }),
+                };
+            })
+            .collect::<Result<Vec<_>, RpegError>>()?;
+        blocks.push(block);
+    }
+    return Ok(blocks);
+}
+
+/// The table each coefficient is coded with, or `None` for an unsigned `a`, and the number of tables
+fn huffman_tables(layout: &[Coefficient]) -> (Vec<Option<usize>>, usize) {
+    let mut names: Vec<&str> = Vec::new();
+    let tables = layout.iter().enumerate()
+        .map(|(i, coefficient)| {
+            if i == 0 && coefficient.kind == CoefficientKind::Unsigned {
+                return None;
+            }
+            let table = names.iter().position(|&name| name == coefficient.name).unwrap_or_else(|| {
+                names.push(coefficient.name);
+                names.len() - 1
+            });
+            return Some(table);
+        })
+        .collect();
+    return (tables, names.len());
+}
+
+/// The Huffman symbol for a coefficient's value, which must fit the coefficient's width if unsigned
+fn symbol(value: i64, coefficient: &Coefficient) -> Result<u64, RpegError> {
+    if coefficient.kind == CoefficientKind::Signed {
+        return Ok(zigzag(value));
+    }
+    if !coefficient.holds(value) {
+        return Err(RpegError::FieldOverflow { field: coefficient.name, value });
+    }
+    return Ok(value as u64);
+}
+
+/// Writes every block's coefficients with the two-pass Huffman coding
+///
+/// # Arguments:
+/// * `blocks`: the coefficients of each block
+/// * `layout`: describes each coefficient of a block, as the transform's `layout` returns
+pub fn write_huffman(blocks: &[Vec<i64>], layout: &[Coefficient]) -> Result<Vec<u8>, RpegError> {
+    let (tables, table_count) = huffman_tables(layout);
+    let mut frequencies = vec![BTreeMap::new(); table_count];
+    for block in blocks {
+        if block.len() != layout.len() {
+            return Err(RpegError::DimensionMismatch { expected: layout.len(), actual: block.len() });
+        }
+        for ((&value, coefficient), table) in block.iter().zip(layout).zip(&tables) {
+            if let Some(table) = table {
+                *frequencies[*table].entry(symbol(value, coefficient)?).or_insert(0_u64) += 1;
+            }
+        }
+    }
+    let codes: Vec<HuffmanCode> = frequencies.iter().map(HuffmanCode::from_frequencies).collect();
+
+    let mut writer = BitWriter::new(Vec::new());
+    for code in &codes {
+        code.write_table(&mut writer).map_err(stream_error)?;
+    }
+    for block in blocks {
+        for ((&value, coefficient), table) in block.iter().zip(layout).zip(&tables) {
+            let written = match table {
+                Some(table) => codes[*table].write_symbol(&mut writer, symbol(value, coefficient)?),
+                None => writer.write_bits(symbol(value, coefficient)?, coefficient.bits),
+            };
+            written.map_err(|err| match err.kind() {
+                io::ErrorKind::InvalidInput => RpegError::FieldOverflow { field: coefficient.name, value },
+                _ => RpegError::Io(err),
+            })?;
+        }
+    }
+    return writer.into_inner().map_err(stream_error);
+}
+
+/// Reads `count` blocks written by `write_huffman` with the same `layout`
+pub fn read_huffman(payload: &[u8], layout: &[Coefficient], count: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+    let (tables, table_count) = huffman_tables(layout);
+    let mut reader = BitReader::new(payload);
+    let codes = (0..table_count)
+        .map(|_| HuffmanCode::read_table(&mut reader))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(stream_error)?;
+
+    // Every code takes at least a bit, and a value outside the tables its whole width
+    let block_bits = layout.iter().zip(&tables).map(|(coefficient, table)| if table.is_some() { 1 } else { coefficient.bits }).sum();
+    check_count(payload, count, block_bits)?;
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        let block = layout.iter().zip(&tables)
+            .map(|(coefficient, table)| {
+                let symbol = match table {
+                    Some(table) => codes[*table].read_symbol(&mut reader),
+                    None => reader.read_bits(coefficient.bits),
+                }.map_err(stream_error)?;
+                if coefficient.kind == CoefficientKind::Signed {
+                    return Ok(unzigzag(symbol));
+                }
+
+                // A table can hold any symbol, so a corrupt one can give a value wider than the coefficient
+                return match coefficient.holds(symbol as i64) {
+                    true => Ok(symbol as i64),
+                    false => Err(RpegError::FieldOverflow { field: coefficient.name, value: symbol as i64 }),
+                };
+            })
+            .collect::<Result<Vec<_>, RpegError>>()?;
+        blocks.push(block);
+    }
+    return Ok(blocks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Vec<Coefficient> {
+        vec![
+            Coefficient { name: "a", kind: CoefficientKind::Unsigned, bits: 9 },
+            Coefficient { name: "b", kind: CoefficientKind::Signed, bits: 5 },
+            Coefficient { name: "b", kind: CoefficientKind::Signed, bits: 5 },
+            Coefficient { name: "index", kind: CoefficientKind::Unsigned, bits: 4 },
+        ]
+    }
+
+    fn blocks() -> Vec<Vec<i64>> {
+        (0..50_i64).map(|i| vec![(i * 37) % 512, (i % 5) - 2, -(i % 3), i % 16]).collect()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let blocks = blocks();
+        let variable = write_variable(&blocks, &layout()).unwrap();
+        assert_eq!(read_variable(&variable, &layout(), blocks.len()).unwrap(), blocks);
+        let huffman = write_huffman(&blocks, &layout()).unwrap();
+        assert_eq!(read_huffman(&huffman, &layout(), blocks.len()).unwrap(), blocks);
+
+        // Blocks that cannot fit in the payload are refused before any are read
+        assert!(matches!(read_variable(&variable, &layout(), usize::MAX), Err(RpegError::Truncated)));
+        assert!(matches!(read_huffman(&huffman, &layout(), usize::MAX), Err(RpegError::Truncated)));
+    }
+
+    #[test]
+    fn huffman_range_test() {
+        // Written with room for a wider index than the reader's layout allows
+        let mut wide_layout = layout();
+        wide_layout[3].bits = 64;
+        let mut blocks = blocks();
+        blocks[7][3] = 16;
+        let payload = write_huffman(&blocks, &wide_layout).unwrap();
+        assert!(matches!(read_huffman(&payload, &layout(), blocks.len()), Err(RpegError::FieldOverflow { field: "index", value: 16 })));
+
+        assert!(matches!(write_huffman(&blocks, &layout()), Err(RpegError::FieldOverflow { field: "index", value: 16 })));
+        assert!(matches!(write_variable(&blocks[..1], &layout()[..3]), Err(RpegError::DimensionMismatch { .. })));
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+use std::io;
+
+/// Errors that can be produced while encoding or decoding an rpeg image
+#[derive(Debug)]
+pub enum RpegError {
+    /// Reading or writing the underlying file or stream failed
+    Io(io::Error),
+    /// The input does not start with a header we know how to read
+    InvalidHeader(String),
+    /// A count of pixels, blocks or words does not agree with the image dimensions
+    DimensionMismatch { expected: usize, actual: usize },
+    /// A quantized value does not fit in the bit field reserved for it
+    FieldOverflow { field: &'static str, value: i64 },
+    /// The input ended before all of the data described by its header was read
+    Truncated,
+    /// The header is recognized, but written by a format version we cannot read
+    UnsupportedVersion(u32),
+    /// The payload does not match the CRC32 recorded in its header
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The requested encoder options cannot be represented in the chosen output format
+    IncompatibleOptions(String),
+}
+
+impl fmt::Display for RpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpegError::Io(err) => write!(f, "i/o error: {}", err),
+            RpegError::InvalidHeader(reason) => write!(f, "invalid header: {}", reason),
+            RpegError::DimensionMismatch { expected, actual } => {
+                write!(f, "dimension mismatch: expected {} but found {}", expected, actual)
+            }
+            RpegError::FieldOverflow { field, value } => {
+                write!(f, "value {} does not fit in field `{}`", value, field)
+            }
+            RpegError::Truncated => write!(f, "input is truncated"),
+            RpegError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            RpegError::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum mismatch: header says {:08x} but payload is {:08x}", expected, actual)
+            }
+            RpegError::IncompatibleOptions(reason) => write!(f, "incompatible options: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RpegError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpegError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RpegError {
+    fn from(err: io::Error) -> Self {
+        RpegError::Io(err)
+    }
+}
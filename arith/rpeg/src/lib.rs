@@ -1,3 +1,16 @@
+pub mod arithmetic;
+pub mod chroma;
 pub mod codec;
 pub mod compress_decompress;
-pub mod transform;
\ No newline at end of file
+pub mod container;
+pub mod dct;
+pub mod deblock;
+pub mod entropy;
+pub mod error;
+pub mod lossless;
+pub mod ppm;
+pub mod prediction;
+pub mod rle;
+pub mod skip;
+pub mod transform;
+pub mod wavelet;
\ No newline at end of file
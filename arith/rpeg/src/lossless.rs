@@ -0,0 +1,314 @@
+use csc411_image::Rgb;
+use crate::arithmetic::{read_arithmetic, write_arithmetic};
+use crate::container::take_array;
+use crate::entropy::{read_huffman, read_variable, write_huffman, write_variable, Coding};
+use crate::error::RpegError;
+use crate::prediction::{predict, predicted_layout, unpredict};
+use crate::transform::{check_block, Coefficient, CoefficientKind};
+use crate::wavelet::{lift_forward, lift_inverse, WaveletFilter};
+
+// Documentation:
+// The other modes work in f32 component video and round on the way in and out, so they cannot
+// give back exactly the pixels they were given. The lossless mode stays in integers throughout:
+//
+// * Color: the reversible YCoCg-R transform,
+//       Co = R - B,  t = B + floor(Co / 2),  Cg = G - t,  Y = t + floor(Cg / 2)
+//   which is undone by t = Y - floor(Cg / 2), G = Cg + t, B = t - floor(Co / 2), R = B + Co.
+//   Co and Cg lie in -den..=den, so den is added to both to keep every plane non-negative.
+// * Space: the S-transform (the integer Haar lifting of `wavelet`) along the rows and then the
+//   columns of each 2x2 block, which gives LL, HL, LH and HH for each of Y, Co and Cg.
+// * Entropy: the LL values are predicted from the neighbouring blocks as in `prediction`, and
+//   every value is written with one of the variable-length codings.
+//
+// Each step maps integers onto integers and is undone exactly, so decoding gives back the
+// (padded) input bit for bit.
+
+/// Names of each block's coefficients: LL, HL, LH and HH of Y, Co and Cg in turn
+const NAMES: [&str; 12] = [
+    "y_ll", "y_hl", "y_lh", "y_hh",
+    "co_ll", "co_hl", "co_lh", "co_hh",
+    "cg_ll", "cg_hl", "cg_lh", "cg_hh",
+];
+
+/// Lossless coding of the whole image, selected instead of a block transform
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lossless {
+    /// How the predicted coefficients are written into the payload; never `Fixed`
+    pub coding: Coding,
+}
+
+impl Lossless {
+    /// Id recorded in the header in place of a block transform's id
+    pub const ID: u8 = 3;
+    pub const NAME: &'static str = "lossless";
+
+    /// Builds the lossless mode with the given coding. Fixed words cannot hold the predicted
+    /// values, so `Fixed` picks the arithmetic coder
+    pub fn new(coding: Coding) -> Self {
+        match coding {
+            Coding::Fixed => Lossless { coding: Coding::Arithmetic },
+            coding => Lossless { coding },
+        }
+    }
+
+    /// Looks up the lossless mode by name
+    pub fn by_name(name: &str, coding: Coding) -> Option<Self> {
+        match name {
+            Lossless::NAME => Some(Lossless::new(coding)),
+            _ => None,
+        }
+    }
+
+    /// Serializes the settings the decoder needs
+    pub fn parameters(&self) -> Vec<u8> {
+        vec![self.coding.id()]
+    }
+
+    /// Parses the bytes written by `parameters`
+    pub fn from_parameters(parameters: &[u8]) -> Result<Self, RpegError> {
+        let mut rest = parameters;
+        let [coding] = take_array::<1>(&mut rest)?;
+        return match Coding::from_id(coding)? {
+            Coding::Fixed => Err(RpegError::InvalidHeader("the lossless mode cannot use fixed words".to_string())),
+            coding => Ok(Lossless { coding }),
+        };
+    }
+
+    /// Transforms and codes a row-major image whose width and height are even
+    ///
+    /// # Arguments:
+    /// * `image`: the pixels to compress
+    /// * `width`: the width of the image
+    /// * `height`: the height of the image
+    /// * `denominator`: the largest value a pixel's channels can take
+    pub fn encode(&self, image: &[Rgb], width: usize, height: usize, denominator: u16) -> Result<Vec<u8>, RpegError> {
+        check_dimensions(image.len(), width, height)?;
+        // The block averages can hide a channel above the denominator, which would then
+        // decode to a different pixel
+        for pixel in image {
+            if let Some(sample) = [pixel.red, pixel.green, pixel.blue].into_iter().find(|&sample| sample > denominator) {
+                return Err(RpegError::FieldOverflow { field: "sample", value: sample as i64 });
+            }
+        }
+        let offset = denominator as i64;
+        let layout = layout(denominator);
+
+        let mut blocks = Vec::with_capacity((width / 2) * (height / 2));
+        for i in (0..height).step_by(2) {
+            for j in (0..width).step_by(2) {
+                let corners = [(i, j), (i, j + 1), (i + 1, j), (i + 1, j + 1)]
+                    .map(|(row, column)| to_ycocg(&image[(row * width) + column], offset));
+                let mut block = Vec::with_capacity(NAMES.len());
+                for channel in 0..3 {
+                    block.extend(forward_block(corners.map(|pixel| pixel[channel]))?);
+                }
+
+                check_block(&block, &layout)?;
+                blocks.push(block);
+            }
+        }
+
+        let predicted = predict(&blocks, &layout, width / 2)?;
+        let layout = predicted_layout(&layout);
+        return match self.coding {
+            Coding::VariableLength => write_variable(&predicted, &layout),
+            Coding::Arithmetic => write_arithmetic(&predicted, &layout),
+            Coding::Huffman => write_huffman(&predicted, &layout),
+            Coding::Fixed => Err(RpegError::IncompatibleOptions("the lossless mode cannot use fixed words".to_string())),
+        };
+    }
+
+    /// Rebuilds the exact pixels `encode` was given
+    ///
+    /// # Arguments:
+    /// * `payload`: the coded blocks
+    /// * `width`: the width of the image
+    /// * `height`: the height of the image
+    /// * `denominator`: the largest value a pixel's channels can take
+    pub fn decode(&self, payload: &[u8], width: usize, height: usize, denominator: u16) -> Result<Vec<Rgb>, RpegError> {
+        check_dimensions(width * height, width, height)
+            .map_err(|_| RpegError::InvalidHeader(format!("dimensions {}x{} are not whole 2x2 blocks", width, height)))?;
+        let offset = denominator as i64;
+
+        let layout = layout(denominator);
+        let count = (width / 2) * (height / 2);
+        let mut blocks = match self.coding {
+            Coding::VariableLength => read_variable(payload, &predicted_layout(&layout), count)?,
+            Coding::Arithmetic => read_arithmetic(payload, &predicted_layout(&layout), count)?,
+            Coding::Huffman => read_huffman(payload, &predicted_layout(&layout), count)?,
+            Coding::Fixed => return Err(RpegError::InvalidHeader("the lossless mode cannot use fixed words".to_string())),
+        };
+        unpredict(&mut blocks, &layout, width / 2)?;
+        for block in &blocks {
+            check_block(block, &layout)?;
+        }
+
+        let mut image = vec![Rgb { red: 0, green: 0, blue: 0 }; width * height];
+        let mut block_index = 0;
+        for i in (0..height).step_by(2) {
+            for j in (0..width).step_by(2) {
+                let block = &blocks[block_index];
+                let mut channels = [[0; 4]; 3];
+                for (channel, samples) in channels.iter_mut().enumerate() {
+                    *samples = inverse_block(&block[channel * 4..(channel + 1) * 4])?;
+                }
+                for (corner, (row, column)) in [(i, j), (i, j + 1), (i + 1, j), (i + 1, j + 1)].into_iter().enumerate() {
+                    let ycocg = channels.map(|samples| samples[corner]);
+                    image[(row * width) + column] = from_ycocg(ycocg, offset)?;
+                }
+                block_index += 1;
+            }
+        }
+        return Ok(image);
+    }
+}
+
+/// Checks that `pixel_count` pixels make an image of whole 2x2 blocks
+fn check_dimensions(pixel_count: usize, width: usize, height: usize) -> Result<(), RpegError> {
+    if pixel_count != width * height {
+        return Err(RpegError::DimensionMismatch { expected: width * height, actual: pixel_count });
+    }
+    if !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+        return Err(RpegError::IncompatibleOptions(format!("dimensions {}x{} are not whole 2x2 blocks", width, height)));
+    }
+    return Ok(());
+}
+
+/// Describes each coefficient of a block for pixels up to `denominator`
+fn layout(denominator: u16) -> Vec<Coefficient> {
+    // Y reaches den and the offset Co and Cg reach 2 den; a row difference doubles the range
+    // and a column difference of row differences doubles it again
+    let bits = |max: u64| (64 - max.leading_zeros()) as u64;
+    let den = denominator as u64;
+    return NAMES.iter().enumerate()
+        .map(|(i, &name)| {
+            let max = if i < 4 { den } else { 2 * den };
+            match i % 4 {
+                0 => Coefficient { name, kind: CoefficientKind::Unsigned, bits: bits(max) },
+                3 => Coefficient { name, kind: CoefficientKind::Signed, bits: bits(2 * max) + 1 },
+                _ => Coefficient { name, kind: CoefficientKind::Signed, bits: bits(max) + 1 },
+            }
+        })
+        .collect();
+}
+
+/// Turns a pixel into Y, Co + `offset` and Cg + `offset`
+fn to_ycocg(pixel: &Rgb, offset: i64) -> [i64; 3] {
+    let (r, g, b) = (pixel.red as i64, pixel.green as i64, pixel.blue as i64);
+    let co = r - b;
+    let t = b + (co >> 1);
+    let cg = g - t;
+    let y = t + (cg >> 1);
+    return [y, co + offset, cg + offset];
+}
+
+/// Reverses `to_ycocg`, failing if the result is not a valid pixel: the offset is the
+/// denominator, so every channel must lie in 0..=offset
+fn from_ycocg([y, co, cg]: [i64; 3], offset: i64) -> Result<Rgb, RpegError> {
+    let (co, cg) = (co - offset, cg - offset);
+    let t = y - (cg >> 1);
+    let g = cg + t;
+    let b = t - (co >> 1);
+    let r = b + co;
+    let channel = |value: i64| u16::try_from(value).ok()
+        .filter(|&channel| channel as i64 <= offset)
+        .ok_or(RpegError::InvalidHeader(format!("lossless payload decodes to a channel of {}", value)));
+    return Ok(Rgb { red: channel(r)?, green: channel(g)?, blue: channel(b)? });
+}
+
+/// The S-transform of one channel of a 2x2 block, given and returned in row-major order:
+/// [x00, x01, x10, x11] becomes [LL, HL, LH, HH]
+fn forward_block(samples: [i64; 4]) -> Result<[i64; 4], RpegError> {
+    // Every value fits an i32: the channels are at most 2 * 65535 and each difference doubles that
+    let mut top = [samples[0] as i32, samples[1] as i32];
+    let mut bottom = [samples[2] as i32, samples[3] as i32];
+    lift_forward(&mut top, WaveletFilter::Haar)?;
+    lift_forward(&mut bottom, WaveletFilter::Haar)?;
+    let mut low = [top[0], bottom[0]];
+    let mut high = [top[1], bottom[1]];
+    lift_forward(&mut low, WaveletFilter::Haar)?;
+    lift_forward(&mut high, WaveletFilter::Haar)?;
+    return Ok([low[0], high[0], low[1], high[1]].map(|value| value as i64));
+}
+
+/// Reverses `forward_block`
+fn inverse_block(coefficients: &[i64]) -> Result<[i64; 4], RpegError> {
+    let [ll, hl, lh, hh] = [0, 1, 2, 3].map(|i| coefficients[i] as i32);
+    let mut low = [ll, lh];
+    let mut high = [hl, hh];
+    lift_inverse(&mut low, WaveletFilter::Haar)?;
+    lift_inverse(&mut high, WaveletFilter::Haar)?;
+    let mut top = [low[0], high[0]];
+    let mut bottom = [low[1], high[1]];
+    lift_inverse(&mut top, WaveletFilter::Haar)?;
+    lift_inverse(&mut bottom, WaveletFilter::Haar)?;
+    return Ok([top[0], top[1], bottom[0], bottom[1]].map(|value| value as i64));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pixels that run through the whole range, with the corners at the extremes
+    fn image(width: usize, height: usize, denominator: u16) -> Vec<Rgb> {
+        let den = denominator as usize;
+        return (0..width * height)
+            .map(|i| match i % 5 {
+                0 => Rgb { red: 0, green: denominator, blue: 0 },
+                1 => Rgb { red: denominator, green: 0, blue: denominator },
+                _ => Rgb { red: ((i * 7) % (den + 1)) as u16, green: ((i * 13) % (den + 1)) as u16, blue: ((i * i) % (den + 1)) as u16 },
+            })
+            .collect();
+    }
+
+    fn assert_same(a: &[Rgb], b: &[Rgb]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert_eq!((x.red, x.green, x.blue), (y.red, y.green, y.blue));
+        }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        for denominator in [1, 255, 65535] {
+            for coding in [Coding::VariableLength, Coding::Arithmetic, Coding::Huffman] {
+                let lossless = Lossless::new(coding);
+                let original = image(10, 6, denominator);
+                let payload = lossless.encode(&original, 10, 6, denominator).unwrap();
+                assert_same(&lossless.decode(&payload, 10, 6, denominator).unwrap(), &original);
+            }
+        }
+    }
+
+    #[test]
+    fn coding_test() {
+        assert_eq!(Lossless::new(Coding::Fixed).coding, Coding::Arithmetic);
+        for coding in [Coding::VariableLength, Coding::Arithmetic, Coding::Huffman] {
+            let lossless = Lossless::new(coding);
+            assert_eq!(Lossless::from_parameters(&lossless.parameters()).unwrap(), lossless);
+        }
+        let fixed = Lossless { coding: Coding::Fixed };
+        assert!(matches!(Lossless::from_parameters(&fixed.parameters()), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(fixed.encode(&image(2, 2, 255), 2, 2, 255), Err(RpegError::IncompatibleOptions(_))));
+    }
+
+    #[test]
+    fn invalid_test() {
+        let lossless = Lossless::new(Coding::Huffman);
+        assert!(matches!(lossless.encode(&image(3, 2, 255), 3, 2, 255), Err(RpegError::IncompatibleOptions(_))));
+        assert!(matches!(lossless.encode(&image(2, 2, 255), 4, 2, 255), Err(RpegError::DimensionMismatch { .. })));
+
+        // A channel above the denominator is refused even when its block stays in range
+        let mut pixels = image(2, 2, 255);
+        pixels[0].red = 1000;
+        assert!(matches!(lossless.encode(&pixels, 2, 2, 255), Err(RpegError::FieldOverflow { .. })));
+
+        // A block that decodes to channels outside 0..=den is refused
+        assert!(matches!(from_ycocg([0, 0, 0], 255), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(from_ycocg([255, 255 + 255, 255], 255), Err(RpegError::InvalidHeader(_))));
+        for pixel in image(4, 4, 255) {
+            let decoded = from_ycocg(to_ycocg(&pixel, 255), 255).unwrap();
+            assert_eq!((decoded.red, decoded.green, decoded.blue), (pixel.red, pixel.green, pixel.blue));
+        }
+    }
+}
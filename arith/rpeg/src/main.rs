@@ -1,16 +1,151 @@
 use std::env;
-use rpeg::codec::{compress, decompress};
+use std::process;
+use rpeg::chroma::{ChromaSampling, ChromaUpsampling};
+use rpeg::codec::{compress, decompress, DecodeOptions, EncodeOptions};
+use rpeg::compress_decompress::{Padding, WordLayout};
+use rpeg::container::ContainerFormat;
+use rpeg::deblock::Deblock;
+use rpeg::entropy::Coding;
+use rpeg::lossless::Lossless;
+use rpeg::prediction::Prediction;
+use rpeg::transform::{by_name, Depth, Haar2x2};
+use rpeg::wavelet::Wavelet;
+use rpeg::error::RpegError;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let argnum = args.len();
-    assert!(argnum == 2 || argnum == 3);
-    let filename = args.iter().nth(2).unwrap();
-    match args[1].as_str() {
-        "-c" => compress(Some(filename)),
-        "-d" => decompress(Some(filename)),
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        usage();
+    }
+
+    // Collect any options that follow the mode flag, leaving at most one filename
+    let mut options = EncodeOptions::default();
+    let mut decode_options = DecodeOptions::default();
+    let mut filename = None;
+    let mut transform = None;
+    let mut levels = 3;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-q" | "--quality" => options.quality = parse_value(arg, rest.next()),
+            "-t" | "--transform" => transform = Some(parse_value::<String>(arg, rest.next())),
+            "--levels" => levels = parse_value(arg, rest.next()),
+            "--chroma" => options.chroma = match rest.next().and_then(|mode| ChromaSampling::by_name(mode)) {
+                Some(sampling) => sampling,
+                None => usage(),
+            },
+            "--upsample" => decode_options.upsampling = match rest.next().map(|mode| mode.as_str()) {
+                Some("replicate") => ChromaUpsampling::Replicate,
+                Some("bilinear") => ChromaUpsampling::Bilinear,
+                _ => usage(),
+            },
+            "--deblock" => decode_options.deblock = match parse_value::<f32>(arg, rest.next()) {
+                strength if strength.is_finite() && strength > 0.0 => Some(Deblock { strength }),
+                _ => {
+                    eprintln!("rpeg: the deblocking strength must be a positive number");
+                    usage();
+                }
+            },
+            "--depth" => options.depth = match rest.next().map(|bits| bits.as_str()) {
+                Some("8") => Depth::Standard,
+                Some("16") => Depth::Deep,
+                _ => usage(),
+            },
+            "--coding" => options.coding = match rest.next().and_then(|name| Coding::by_name(name)) {
+                Some(coding) => coding,
+                None => usage(),
+            },
+            "--predict" => options.prediction = match rest.next().and_then(|name| Prediction::by_name(name)) {
+                Some(prediction) => prediction,
+                None => usage(),
+            },
+            "--skip-runs" => options.skip_runs = true,
+            "--layout" => options.layout = Some(WordLayout::from_widths(&parse_widths(rest.next()))),
+            "--legacy" => options.container = ContainerFormat::Legacy,
+            "--pad" => options.padding = match rest.next().map(|mode| mode.as_str()) {
+                Some("replicate") => Padding::Replicate,
+                Some("mirror") => Padding::Mirror,
+                Some("zero") => Padding::Zero,
+                Some("trim") => Padding::Trim,
+                _ => usage(),
+            },
+            _ if filename.is_none() && !arg.starts_with('-') => filename = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+
+    // The transform is built last so that it sees the final quality and chroma settings; the
+    // 2x2 transform is left to the options, which also apply the layout and coding flags. The
+    // lossless mode takes the coding flag as its own, with the arithmetic coder by default
+    if let Some(name) = transform.filter(|name| name != Haar2x2::NAME) {
+        options.wavelet = match Wavelet::by_name(&name, levels, options.quality, options.chroma) {
+            Ok(wavelet) => wavelet,
+            Err(err) => {
+                eprintln!("rpeg: {}", err);
+                process::exit(exit_code(&err));
+            }
+        };
+        options.lossless = Lossless::by_name(&name, options.coding);
+        options.transform = match by_name(&name, options.quality, options.chroma, options.depth) {
+            Some(transform) => Some(transform),
+            None if options.wavelet.is_some() || options.lossless.is_some() => None,
+            None => {
+                eprintln!("rpeg: unknown transform {}", name);
+                usage();
+            }
+        };
+    }
+
+    let result = match args[0].as_str() {
+        "-c" => compress(filename, &options),
+        "-d" => decompress(filename, &decode_options),
+        _ => usage(),
+    };
+
+    if let Err(err) = result {
+        eprintln!("rpeg: {}", err);
+        process::exit(exit_code(&err));
+    }
+}
+
+/// Parses the value given to `flag`, exiting with the usage message when it is missing or malformed
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> T {
+    match value.map(|value| value.parse::<T>()) {
+        Some(Ok(parsed)) => parsed,
+        _ => {
+            eprintln!("rpeg: bad or missing value for {}", flag);
+            usage();
+        }
+    }
+}
+
+/// Parses a comma-separated list of field widths for `--layout`, such as "9,5,5,5,4,4"
+fn parse_widths(value: Option<&String>) -> Vec<u64> {
+    let widths = value.map(|list| list.split(',').map(|width| width.trim().parse::<u64>()).collect::<Result<Vec<_>, _>>());
+    match widths {
+        Some(Ok(widths)) if !widths.is_empty() => widths,
         _ => {
-            eprintln!("Usage: cargo run -- -d [filename]\ncargo run -- -c [filename]")
+            eprintln!("rpeg: bad or missing value for --layout");
+            usage();
         }
     }
+}
+
+/// Maps each kind of failure to its own process exit code (1 is reserved for usage errors)
+fn exit_code(err: &RpegError) -> i32 {
+    match err {
+        RpegError::Io(_) => 2,
+        RpegError::InvalidHeader(_) => 3,
+        RpegError::UnsupportedVersion(_) => 4,
+        RpegError::Truncated => 5,
+        RpegError::DimensionMismatch { .. } => 6,
+        RpegError::FieldOverflow { .. } => 7,
+        RpegError::ChecksumMismatch { .. } => 8,
+        RpegError::IncompatibleOptions(_) => 9,
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: cargo run -- -d [--upsample replicate|bilinear] [--deblock strength] [filename]\ncargo run -- -c [-q quality] [-t haar|dct|wavelet|cdf53|lossless] [--levels n] [--chroma 444|422|420] [--depth 8|16] [--layout widths] [--coding fixed|vlc|arith|huffman] [--predict none|med] [--skip-runs] [--legacy] [--pad replicate|mirror|zero|trim] [filename]");
+    process::exit(1);
 }
\ No newline at end of file
@@ -0,0 +1,195 @@
+use csc411_image::{Rgb, RgbImage};
+use crate::error::RpegError;
+
+// Documentation:
+// Netpbm PPM: https://netpbm.sourceforge.net/doc/ppm.html
+//
+// A PPM starts with the magic number P6 (binary) or P3 (plain), then the width, the height and
+// the maxval as decimal text separated by whitespace, where a '#' starts a comment that runs to
+// the end of its line. A binary raster follows a single whitespace character after the maxval,
+// with each sample in one byte when the maxval is below 256 and in two big-endian bytes
+// otherwise; a plain raster is decimal text like the header. The maxval is any value from 1 to
+// 65535 and becomes the image's denominator, so 16-bit images keep their precision and an image
+// decoded from an rpeg file keeps the maxval it was compressed with.
+
+/// Largest maxval a PPM can have
+const MAX_MAXVAL: u64 = 65535;
+
+/// Parses a binary (P6) or plain (P3) PPM of any maxval
+///
+/// # Arguments:
+/// * `bytes`: the complete contents of the image file
+pub fn read_ppm(bytes: &[u8]) -> Result<RgbImage, RpegError> {
+    let plain = match bytes.get(..2) {
+        Some(b"P6") => false,
+        Some(b"P3") => true,
+        _ => return Err(RpegError::InvalidHeader("not a PPM image (expected P3 or P6)".to_string())),
+    };
+    let mut rest = &bytes[2..];
+
+    let width = take_number(&mut rest)?;
+    let height = take_number(&mut rest)?;
+    let maxval = take_number(&mut rest)?;
+    if maxval == 0 || maxval > MAX_MAXVAL {
+        return Err(RpegError::InvalidHeader(format!("ppm maxval {} is not between 1 and {}", maxval, MAX_MAXVAL)));
+    }
+    let (width, height) = match (u32::try_from(width), u32::try_from(height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(RpegError::InvalidHeader(format!("ppm dimensions {}x{} are too large", width, height))),
+    };
+    let count = (width as usize).checked_mul(height as usize).and_then(|pixels| pixels.checked_mul(3))
+        .ok_or(RpegError::InvalidHeader(format!("ppm dimensions {}x{} are too large", width, height)))?;
+
+    let samples = if plain {
+        let mut samples = Vec::new();
+        for _ in 0..count {
+            samples.push(take_number(&mut rest)?);
+        }
+        samples
+    } else {
+        // Exactly one whitespace character separates the maxval from the raster
+        match rest.split_first() {
+            Some((byte, after)) if byte.is_ascii_whitespace() => rest = after,
+            Some(_) => return Err(RpegError::InvalidHeader("ppm maxval is not followed by whitespace".to_string())),
+            None => return Err(RpegError::Truncated),
+        }
+        let sample_bytes = if maxval < 256 { 1 } else { 2 };
+        let raster = rest.get(..count.saturating_mul(sample_bytes)).ok_or(RpegError::Truncated)?;
+        raster.chunks_exact(sample_bytes)
+            .map(|sample| sample.iter().fold(0_u64, |value, &byte| (value << 8) | byte as u64))
+            .collect()
+    };
+
+    if let Some(&sample) = samples.iter().find(|&&sample| sample > maxval) {
+        return Err(RpegError::InvalidHeader(format!("ppm sample {} is above the maxval {}", sample, maxval)));
+    }
+    let pixels = samples.chunks_exact(3)
+        .map(|rgb| Rgb { red: rgb[0] as u16, green: rgb[1] as u16, blue: rgb[2] as u16 })
+        .collect();
+    return Ok(RgbImage { pixels, width, height, denominator: maxval as u16 });
+}
+
+/// Serializes an image as a binary PPM whose maxval is the image's denominator
+///
+/// # Arguments:
+/// * `image`: the image to write
+pub fn write_ppm(image: &RgbImage) -> Result<Vec<u8>, RpegError> {
+    let expected = image.width as usize * image.height as usize;
+    if image.pixels.len() != expected {
+        return Err(RpegError::DimensionMismatch { expected, actual: image.pixels.len() });
+    }
+    if image.denominator == 0 {
+        return Err(RpegError::IncompatibleOptions("a ppm cannot have a maxval of 0".to_string()));
+    }
+
+    let header = format!("P6\n{} {}\n{}\n", image.width, image.height, image.denominator);
+    let sample_bytes = if image.denominator < 256 { 1 } else { 2 };
+    let mut output = Vec::with_capacity(header.len() + (expected * 3 * sample_bytes));
+    output.extend_from_slice(header.as_bytes());
+    for pixel in &image.pixels {
+        for sample in [pixel.red, pixel.green, pixel.blue] {
+            if sample > image.denominator {
+                return Err(RpegError::FieldOverflow { field: "sample", value: sample as i64 });
+            }
+            output.extend_from_slice(&sample.to_be_bytes()[2 - sample_bytes..]);
+        }
+    }
+    return Ok(output);
+}
+
+/// Removes the whitespace, comments and decimal number at the front of `input`
+fn take_number(input: &mut &[u8]) -> Result<u64, RpegError> {
+    loop {
+        match input.first() {
+            Some(byte) if byte.is_ascii_whitespace() => *input = &input[1..],
+            Some(b'#') => {
+                let line_end = input.iter().position(|&byte| byte == b'\n').unwrap_or(input.len());
+                *input = &input[line_end..];
+            }
+            Some(_) => break,
+            None => return Err(RpegError::Truncated),
+        }
+    }
+
+    let digits = input.iter().take_while(|byte| byte.is_ascii_digit()).count();
+    if digits == 0 {
+        return Err(RpegError::InvalidHeader(format!("expected a number in the ppm, found {:?}", input[0] as char)));
+    }
+    let (number, rest) = input.split_at(digits);
+    *input = rest;
+
+    // Every byte is an ASCII digit, so only a value too large for a u64 can fail to parse
+    return std::str::from_utf8(number).ok()
+        .and_then(|number| number.parse::<u64>().ok())
+        .ok_or(RpegError::InvalidHeader("number in the ppm is too large".to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(denominator: u16) -> RgbImage {
+        let pixels = (0..6_u32)
+            .map(|i| Rgb { red: (i * denominator as u32 / 5) as u16, green: denominator.saturating_sub(i as u16), blue: denominator })
+            .collect();
+        RgbImage { pixels, width: 3, height: 2, denominator }
+    }
+
+    fn assert_same(a: &RgbImage, b: &RgbImage) {
+        assert_eq!((a.width, a.height, a.denominator), (b.width, b.height, b.denominator));
+        for (x, y) in a.pixels.iter().zip(&b.pixels) {
+            assert_eq!((x.red, x.green, x.blue), (y.red, y.green, y.blue));
+        }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        for denominator in [1, 100, 255, 256, 1023, 65535] {
+            let original = image(denominator);
+            let bytes = write_ppm(&original).unwrap();
+            let sample_bytes = if denominator < 256 { 1 } else { 2 };
+            assert_eq!(bytes.len(), format!("P6\n3 2\n{}\n", denominator).len() + (18 * sample_bytes));
+            assert_same(&read_ppm(&bytes).unwrap(), &original);
+        }
+    }
+
+    #[test]
+    fn wide_samples_test() {
+        // Two bytes per sample, most significant first
+        let bytes = b"P6 1 1 65535\n\x12\x34\xff\xff\x00\x01";
+        let image = read_ppm(bytes).unwrap();
+        assert_eq!((image.pixels[0].red, image.pixels[0].green, image.pixels[0].blue), (0x1234, 0xffff, 1));
+        assert_eq!(write_ppm(&image).unwrap(), b"P6\n1 1\n65535\n\x12\x34\xff\xff\x00\x01");
+    }
+
+    #[test]
+    fn plain_test() {
+        let bytes = b"P3\n# a comment\n2 1 # another\n100\n0 50 100\n 7\n8 9\n";
+        let image = read_ppm(bytes).unwrap();
+        assert_eq!((image.width, image.height, image.denominator), (2, 1, 100));
+        assert_eq!((image.pixels[1].red, image.pixels[1].green, image.pixels[1].blue), (7, 8, 9));
+    }
+
+    #[test]
+    fn invalid_test() {
+        let invalid = |bytes: &[u8]| matches!(read_ppm(bytes), Err(RpegError::InvalidHeader(_)));
+        assert!(invalid(b"P5 1 1 255\n\0"));
+        assert!(invalid(b"P6 1 1 0\n\0\0\0"));
+        assert!(invalid(b"P6 1 1 65536\n\0\0\0\0\0\0"));
+        assert!(invalid(b"P6 x 1 255\n\0\0\0"));
+        assert!(invalid(b"P6 1 1 255x\0\0\0"));
+        assert!(invalid(b"P3 1 1 100 0 101 0"));
+        assert!(invalid(b"P6 99999999999 1 255\n"));
+
+        assert!(matches!(read_ppm(b"P6 1 1"), Err(RpegError::Truncated)));
+        assert!(matches!(read_ppm(b"P6 2 1 255\n\0\0\0\0\0"), Err(RpegError::Truncated)));
+        assert!(matches!(read_ppm(b"P6 65535 65535 65535\n\0\0"), Err(RpegError::Truncated)));
+        assert!(matches!(read_ppm(b"P3 1 1 255 0 0"), Err(RpegError::Truncated)));
+
+        let mut image = image(100);
+        image.pixels[0].red = 101;
+        assert!(matches!(write_ppm(&image), Err(RpegError::FieldOverflow { .. })));
+        image.pixels.pop();
+        assert!(matches!(write_ppm(&image), Err(RpegError::DimensionMismatch { .. })));
+    }
+}
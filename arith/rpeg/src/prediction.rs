@@ -0,0 +1,200 @@
+use crate::error::RpegError;
+use crate::transform::{Coefficient, CoefficientKind};
+
+// Documentation:
+// Neighbouring blocks have much the same mean luma and chroma, so with prediction on, the
+// unsigned coefficients of the 2x2 transform (a, avg_pb and avg_pr) are stored as differences
+// from a guess made from the blocks to the left (W), above (N) and above-left (NW). The guess is
+// the median edge detector of LOCO-I and JPEG-LS:
+//
+//   min(W, N)     if NW >= max(W, N)
+//   max(W, N)     if NW <= min(W, N)
+//   W + N - NW    otherwise
+//
+// which follows a horizontal or vertical edge when there is one and the plane through the three
+// neighbours when there is not. Blocks on the top row are guessed from W, blocks in the left
+// column from N, and the first block from 0. The decoder rebuilds the blocks in the same order,
+// so every guess is made from values it already has.
+//
+// Differences can be negative and need a bit more than the values, so prediction goes with the
+// variable-length codings rather than fixed words.
+
+/// Whether coefficients are stored as they are or as differences from their neighbours
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Prediction {
+    /// Every coefficient as it is
+    #[default]
+    None,
+    /// a and the chroma indices as differences from the median edge detector's guess
+    Med,
+}
+
+impl Prediction {
+    /// Every prediction, in the order of their ids
+    pub const ALL: [Prediction; 2] = [Prediction::None, Prediction::Med];
+
+    /// Value recorded in the stream for this prediction
+    pub fn id(&self) -> u8 {
+        match self {
+            Prediction::None => 0,
+            Prediction::Med => 1,
+        }
+    }
+
+    /// Parses the value written by `id`
+    pub fn from_id(id: u8) -> Result<Self, RpegError> {
+        return Prediction::ALL.get(id as usize).copied()
+            .ok_or(RpegError::InvalidHeader(format!("unknown prediction {}", id)));
+    }
+
+    /// Looks up a prediction by the name the command line uses for it
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Prediction::None),
+            "med" => Some(Prediction::Med),
+            _ => None,
+        }
+    }
+}
+
+/// The median edge detector's guess from the left, top and top-left neighbours
+fn median_edge(w: i64, n: i64, nw: i64) -> i64 {
+    if nw >= w.max(n) {
+        return w.min(n);
+    }
+    if nw <= w.min(n) {
+        return w.max(n);
+    }
+    return w.wrapping_add(n).wrapping_sub(nw);
+}
+
+/// The guess for coefficient `i` of block `index`, from the blocks before it
+///
+/// # Arguments:
+/// * `blocks`: the blocks in row-major order; only those before `index` are looked at
+/// * `index`: the block being guessed
+/// * `columns`: number of blocks in each row of the image
+/// * `i`: the coefficient being guessed
+fn guess(blocks: &[Vec<i64>], index: usize, columns: usize, i: usize) -> i64 {
+    match (index / columns, index % columns) {
+        (0, 0) => 0,
+        (0, _) => blocks[index - 1][i],
+        (_, 0) => blocks[index - columns][i],
+        _ => median_edge(blocks[index - 1][i], blocks[index - columns][i], blocks[index - columns - 1][i]),
+    }
+}
+
+/// Describes the blocks `predict` returns: the predicted coefficients become signed and one
+/// bit wider
+pub fn predicted_layout(layout: &[Coefficient]) -> Vec<Coefficient> {
+    return layout.iter()
+        .map(|coefficient| match coefficient.kind {
+            CoefficientKind::Unsigned => Coefficient { kind: CoefficientKind::Signed, bits: (coefficient.bits + 1).min(64), ..coefficient.clone() },
+            _ => coefficient.clone(),
+        })
+        .collect();
+}
+
+/// Replaces the unsigned coefficients of every block with their differences from the guess
+///
+/// # Arguments:
+/// * `blocks`: the coefficients of each block, in row-major order
+/// * `layout`: describes each coefficient of a block, as the transform's `layout` returns
+/// * `columns`: number of blocks in each row of the image
+pub fn predict(blocks: &[Vec<i64>], layout: &[Coefficient], columns: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+    let columns = columns.max(1);
+    let mut predicted = Vec::with_capacity(blocks.len());
+    for (index, block) in blocks.iter().enumerate() {
+        if block.len() != layout.len() {
+            return Err(RpegError::DimensionMismatch { expected: layout.len(), actual: block.len() });
+        }
+        let differences = block.iter().zip(layout).enumerate()
+            .map(|(i, (&value, coefficient))| match coefficient.kind {
+                CoefficientKind::Unsigned => value.wrapping_sub(guess(blocks, index, columns, i)),
+                _ => value,
+            })
+            .collect();
+        predicted.push(differences);
+    }
+    return Ok(predicted);
+}
+
+/// Reverses `predict` in place, given the same `layout` and `columns`. Fails if a rebuilt value
+/// does not fit its coefficient, since later guesses are made from it
+pub fn unpredict(blocks: &mut [Vec<i64>], layout: &[Coefficient], columns: usize) -> Result<(), RpegError> {
+    let columns = columns.max(1);
+    for index in 0..blocks.len() {
+        if blocks[index].len() != layout.len() {
+            return Err(RpegError::DimensionMismatch { expected: layout.len(), actual: blocks[index].len() });
+        }
+        for (i, coefficient) in layout.iter().enumerate() {
+            if coefficient.kind == CoefficientKind::Unsigned {
+                let value = blocks[index][i].wrapping_add(guess(blocks, index, columns, i));
+                if !coefficient.holds(value) {
+                    return Err(RpegError::FieldOverflow { field: coefficient.name, value });
+                }
+                blocks[index][i] = value;
+            }
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Vec<Coefficient> {
+        vec![Coefficient { name: "a", kind: CoefficientKind::Unsigned, bits: 9 }, Coefficient { name: "b", kind: CoefficientKind::Signed, bits: 5 }]
+    }
+
+    #[test]
+    fn median_edge_test() {
+        // An edge on either side is followed, and a smooth patch is continued as a plane
+        assert_eq!(median_edge(10, 20, 25), 10);
+        assert_eq!(median_edge(10, 20, 5), 20);
+        assert_eq!(median_edge(10, 20, 12), 18);
+        assert_eq!(median_edge(7, 7, 7), 7);
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let blocks: Vec<Vec<i64>> = (0..12_i64).map(|i| vec![(i * 41) % 512, (i % 7) - 3]).collect();
+        for columns in [0, 1, 4, 12] {
+            let predicted = predict(&blocks, &layout(), columns).unwrap();
+
+            // Signed values are left alone and the first block is guessed from 0
+            assert_eq!(predicted[0], blocks[0]);
+            assert!(predicted.iter().zip(&blocks).all(|(after, before)| after[1] == before[1]));
+
+            let mut rebuilt = predicted.clone();
+            unpredict(&mut rebuilt, &layout(), columns).unwrap();
+            assert_eq!(rebuilt, blocks);
+        }
+
+        // The top row is guessed from the left and the left column from above
+        let predicted = predict(&blocks, &layout(), 4).unwrap();
+        assert_eq!(predicted[1][0], blocks[1][0] - blocks[0][0]);
+        assert_eq!(predicted[4][0], blocks[4][0] - blocks[0][0]);
+    }
+
+    #[test]
+    fn layout_test() {
+        let predicted = predicted_layout(&layout());
+        assert_eq!(predicted[0], Coefficient { name: "a", kind: CoefficientKind::Signed, bits: 10 });
+        assert_eq!(predicted[1], layout()[1]);
+        let wide = predicted_layout(&[Coefficient { name: "wide", kind: CoefficientKind::Unsigned, bits: 64 }]);
+        assert_eq!(wide[0].bits, 64);
+    }
+
+    #[test]
+    fn overflow_test() {
+        // A difference that takes a value out of its field is refused, not passed on to later guesses
+        let mut blocks = vec![vec![500, 0], vec![100, 0]];
+        assert!(matches!(unpredict(&mut blocks, &layout(), 2), Err(RpegError::FieldOverflow { field: "a", value: 600 })));
+        let mut blocks = vec![vec![-1, 0]];
+        assert!(matches!(unpredict(&mut blocks, &layout(), 1), Err(RpegError::FieldOverflow { field: "a", value: -1 })));
+        let mut blocks = vec![vec![0]];
+        assert!(matches!(unpredict(&mut blocks, &layout(), 1), Err(RpegError::DimensionMismatch { .. })));
+    }
+}
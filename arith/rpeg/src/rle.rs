@@ -0,0 +1,67 @@
+use std::io;
+use bitpack::bitio::{BitReader, BitWriter};
+use bitpack::codes::{read_leb128, unzigzag, write_leb128, zigzag};
+use crate::entropy::stream_error;
+use crate::error::RpegError;
+
+// Documentation:
+// Coefficients from the frequency transforms are mostly zero once quantized, so they are stored
+// as (run, level) pairs in the style of JPEG: the number of zeros skipped, then the next nonzero
+// value. A run byte of END_OF_BLOCK means every remaining coefficient is zero.
+// Runs are single bytes; levels are zig-zag mapped and written as LEB128 varints.
+
+const END_OF_BLOCK: u8 = 0xFF;
+
+/// Writes `coefficients` as (run, level) pairs followed by an end-of-block marker
+///
+/// # Arguments:
+/// * `coefficients`: one block's coefficients, ordered so that zeros cluster at the end
+/// * `writer`: where the encoded bytes are written
+pub fn write_run_length<W: io::Write>(coefficients: &[i64], writer: &mut BitWriter<W>) -> Result<(), RpegError> {
+    let mut run: usize = 0;
+    for &coefficient in coefficients {
+        if coefficient == 0 {
+            run += 1;
+            continue;
+        }
+
+        // Runs longer than a byte can describe are split with explicit zero levels
+        while run >= END_OF_BLOCK as usize {
+            write_pair(writer, END_OF_BLOCK - 1, 0)?;
+            run -= END_OF_BLOCK as usize;
+        }
+        write_pair(writer, run as u8, coefficient)?;
+        run = 0;
+    }
+    return writer.write_bits(END_OF_BLOCK as u64, 8).map_err(stream_error);
+}
+
+/// Reads one block of `count` coefficients written by `write_run_length`
+pub fn read_run_length<R: io::Read>(reader: &mut BitReader<R>, count: usize) -> Result<Vec<i64>, RpegError> {
+    let mut coefficients = Vec::new();
+    loop {
+        let run = reader.read_bits(8).map_err(stream_error)? as u8;
+        if run == END_OF_BLOCK {
+            break;
+        }
+
+        let level = unzigzag(read_leb128(reader).map_err(stream_error)?);
+        let skipped = run as usize + if level == 0 { 1 } else { 0 };
+        if coefficients.len() + run as usize + 1 > count {
+            return Err(RpegError::DimensionMismatch { expected: count, actual: coefficients.len() + run as usize + 1 });
+        }
+        coefficients.resize(coefficients.len() + skipped, 0);
+        if level != 0 {
+            coefficients.push(level);
+        }
+    }
+
+    coefficients.resize(count, 0);
+    return Ok(coefficients);
+}
+
+/// Writes a run byte and a zig-zag mapped LEB128 level
+fn write_pair<W: io::Write>(writer: &mut BitWriter<W>, run: u8, level: i64) -> Result<(), RpegError> {
+    writer.write_bits(run as u64, 8).map_err(stream_error)?;
+    return write_leb128(writer, zigzag(level)).map_err(stream_error);
+}
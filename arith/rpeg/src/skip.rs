@@ -0,0 +1,128 @@
+use crate::error::RpegError;
+use crate::transform::{Coefficient, CoefficientKind};
+
+// Documentation:
+// Flat regions give runs of whole blocks with no detail: b = c = d = 0 and the same a and
+// chroma as the block before (or, once predicted, every value 0). With skip runs on, such a
+// block is left out and the block before it carries a count of how many were skipped after it,
+// as an extra "run" coefficient that goes through the same coding as the rest. Most counts are
+// 0, so the run is a `Count`, which every variable-length coding writes in a bit or so however
+// large the image. The first block is always kept.
+
+/// The count appended to every kept block; `expand_runs` checks it against the image's blocks
+const RUN: Coefficient = Coefficient { name: "run", kind: CoefficientKind::Count, bits: 64 };
+
+/// Whether `block` repeats the unsigned coefficients of `previous` and has every signed one 0
+fn repeats(block: &[i64], previous: &[i64], layout: &[Coefficient]) -> bool {
+    return block.iter().zip(previous).zip(layout)
+        .all(|((&value, &before), coefficient)| value == if coefficient.kind == CoefficientKind::Signed { 0 } else { before });
+}
+
+/// The block a skipped block stands for: `previous` with every signed coefficient 0
+fn repeated(previous: &[i64], layout: &[Coefficient]) -> Vec<i64> {
+    return previous.iter().zip(layout)
+        .map(|(&before, coefficient)| if coefficient.kind == CoefficientKind::Signed { 0 } else { before })
+        .collect();
+}
+
+/// Describes the blocks `skip_runs` returns: `layout` followed by the run count
+pub fn run_layout(layout: &[Coefficient]) -> Vec<Coefficient> {
+    let mut with_run = layout.to_vec();
+    with_run.push(RUN);
+    return with_run;
+}
+
+/// Leaves out every block that repeats the block before it with no detail, and appends to each
+/// kept block the number left out after it
+///
+/// # Arguments:
+/// * `blocks`: the coefficients of each block
+/// * `layout`: describes each coefficient of a block
+pub fn skip_runs(blocks: &[Vec<i64>], layout: &[Coefficient]) -> Result<Vec<Vec<i64>>, RpegError> {
+    let mut kept: Vec<Vec<i64>> = Vec::new();
+    for (index, block) in blocks.iter().enumerate() {
+        if block.len() != layout.len() {
+            return Err(RpegError::DimensionMismatch { expected: layout.len(), actual: block.len() });
+        }
+        match kept.last_mut() {
+            Some(last) if repeats(block, &blocks[index - 1], layout) => last[layout.len()] += 1,
+            _ => {
+                let mut with_run = block.clone();
+                with_run.push(0);
+                kept.push(with_run);
+            }
+        }
+    }
+    return Ok(kept);
+}
+
+/// Reverses `skip_runs`, checking that the runs add up to `count` blocks
+pub fn expand_runs(kept: &[Vec<i64>], layout: &[Coefficient], count: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+    let mut blocks = Vec::new();
+    for with_run in kept {
+        let (block, run) = with_run.split_at(layout.len());
+        blocks.push(block.to_vec());
+        let remaining = count.saturating_sub(blocks.len());
+        let run = usize::try_from(run[0]).ok()
+            .filter(|&run| run <= remaining)
+            .ok_or(RpegError::InvalidHeader(format!("a run of {} blocks overruns the image", run[0])))?;
+        for _ in 0..run {
+            blocks.push(repeated(block, layout));
+        }
+    }
+    if blocks.len() != count {
+        return Err(RpegError::DimensionMismatch { expected: count, actual: blocks.len() });
+    }
+    return Ok(blocks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arithmetic::{read_arithmetic, write_arithmetic};
+    use crate::entropy::{read_huffman, read_variable, write_huffman, write_variable};
+
+    fn layout() -> Vec<Coefficient> {
+        vec![Coefficient { name: "a", kind: CoefficientKind::Unsigned, bits: 9 }, Coefficient { name: "b", kind: CoefficientKind::Signed, bits: 5 }]
+    }
+
+    #[test]
+    fn round_trip_test() {
+        // Blocks 1, 2 and 5 repeat the one before with no detail; block 4 has detail, so it is kept
+        let blocks = vec![vec![7, 0], vec![7, 0], vec![7, 0], vec![9, 0], vec![9, 2], vec![9, 0]];
+        let kept = skip_runs(&blocks, &layout()).unwrap();
+        assert_eq!(kept, vec![vec![7, 0, 2], vec![9, 0, 0], vec![9, 2, 1]]);
+        assert_eq!(expand_runs(&kept, &layout(), blocks.len()).unwrap(), blocks);
+
+        // A skipped block stands for its predecessor with the detail cleared
+        assert_eq!(expand_runs(&[vec![9, 2, 1]], &layout(), 2).unwrap(), vec![vec![9, 2], vec![9, 0]]);
+        assert!(skip_runs(&[], &layout()).unwrap().is_empty());
+        assert!(matches!(skip_runs(&[vec![1]], &layout()), Err(RpegError::DimensionMismatch { .. })));
+    }
+
+    #[test]
+    fn expand_error_test() {
+        assert!(matches!(expand_runs(&[vec![7, 0, 3]], &layout(), 3), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(expand_runs(&[vec![7, 0, -1]], &layout(), 3), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(expand_runs(&[vec![7, 0, 1]], &layout(), 3), Err(RpegError::DimensionMismatch { .. })));
+    }
+
+    #[test]
+    fn coding_test() {
+        // Runs are unsigned counts, and every coding keeps the common count of 0 short
+        let run_layout = run_layout(&layout());
+        assert_eq!(run_layout[2], Coefficient { name: "run", kind: CoefficientKind::Count, bits: 64 });
+        let kept: Vec<Vec<i64>> = (0..200).map(|i| vec![i % 512, 0, if i == 100 { 1 << 40 } else { 0 }]).collect();
+        let variable = write_variable(&kept, &run_layout).unwrap();
+        assert!(variable.len() < 200 * 11 / 8 + 16, "{} bytes", variable.len());
+        assert_eq!(read_variable(&variable, &run_layout, kept.len()).unwrap(), kept);
+        assert_eq!(read_arithmetic(&write_arithmetic(&kept, &run_layout).unwrap(), &run_layout, kept.len()).unwrap(), kept);
+        assert_eq!(read_huffman(&write_huffman(&kept, &run_layout).unwrap(), &run_layout, kept.len()).unwrap(), kept);
+
+        // A count is never negative
+        let negative = vec![vec![0, 0, -1]];
+        assert!(matches!(write_variable(&negative, &run_layout), Err(RpegError::FieldOverflow { field: "run", .. })));
+        assert!(matches!(write_arithmetic(&negative, &run_layout), Err(RpegError::FieldOverflow { field: "run", .. })));
+        assert!(matches!(write_huffman(&negative, &run_layout), Err(RpegError::FieldOverflow { field: "run", .. })));
+    }
+}
@@ -1,12 +1,588 @@
-use csc411_image::{RgbImage, Rgb};
+use std::fmt::Debug;
+use std::sync::Arc;
+use bitpack::bitio::{BitReader, BitWriter};
+use bitpack::codes::{read_leb128, write_leb128};
 use csc411_arith::{index_of_chroma, chroma_of_index};
 use crate::compress_decompress::Ypbpr;
 use crate::compress_decompress::PixelBlockValues;
+use crate::compress_decompress::{pack_words, unpack_words, FieldLayout, WordLayout};
+use crate::chroma::ChromaSampling;
+use crate::container::take_array;
+use crate::dct::Dct8x8;
+use crate::arithmetic::{read_arithmetic, write_arithmetic};
+use crate::entropy::{read_huffman, read_variable, stream_error, write_huffman, write_variable, Coding};
+use crate::error::RpegError;
+use crate::prediction::{predict, predicted_layout, unpredict, Prediction};
+use crate::skip::{expand_runs, run_layout, skip_runs};
+
+// -----------------------------------------------------------------------------------
+//                              BLOCK TRANSFORMS
+// -----------------------------------------------------------------------------------
+
+/// What values a coefficient takes, which decides how the variable-length codings write it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoefficientKind {
+    /// 0 up to the largest value of its bits, spread over that range
+    Unsigned,
+    /// Either sign, most often near 0
+    Signed,
+    /// 0 or more, most often near 0, such as the length of a run
+    Count,
+}
+
+/// Describes one of the quantized coefficients a transform produces for every block
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coefficient {
+    /// Name used in error messages
+    pub name: &'static str,
+    /// What values the coefficient takes
+    pub kind: CoefficientKind,
+    /// Number of bits needed to hold any value the coefficient can take
+    pub bits: u64,
+}
+
+impl Coefficient {
+    /// Whether `value` lies in the range `bits` gives the coefficient. A 64-bit unsigned
+    /// coefficient holds any word, whatever sign its top bit gives the i64
+    pub fn holds(&self, value: i64) -> bool {
+        match (self.kind, self.bits) {
+            (_, 0) => value == 0,
+            (CoefficientKind::Count, 64..) => value >= 0,
+            (_, 64..) => true,
+            (CoefficientKind::Signed, bits) => value >> (bits - 1) == 0 || value >> (bits - 1) == -1,
+            (_, bits) => value >= 0 && value >> bits == 0,
+        }
+    }
+}
+
+/// Checks that every coefficient of a decoded block lies in the range `layout` gives it, so
+/// that a corrupt payload cannot reach the inverse transform with values it never produces
+pub fn check_block(block: &[i64], layout: &[Coefficient]) -> Result<(), RpegError> {
+    if block.len() != layout.len() {
+        return Err(RpegError::DimensionMismatch { expected: layout.len(), actual: block.len() });
+    }
+    for (&value, coefficient) in block.iter().zip(layout) {
+        if !coefficient.holds(value) {
+            return Err(RpegError::FieldOverflow { field: coefficient.name, value });
+        }
+    }
+    return Ok(());
+}
+
+/// A reversible transform over square blocks of component video pixels.
+///
+/// The codec cuts the (padded) image into `block_size` x `block_size` blocks in row-major
+/// order, runs each one through `forward` and hands the results to `write_blocks` along with
+/// the number of blocks in each row. Decoding
+/// runs the same steps backwards, so a transform owns both its math and its payload format.
+pub trait BlockTransform: Debug + Send + Sync {
+    /// Identifier recorded in the stream so the decoder can rebuild the same transform
+    fn id(&self) -> u8;
+
+    /// Name used to pick the transform at runtime
+    fn name(&self) -> &'static str;
+
+    /// Side length of the square blocks this transform works on
+    fn block_size(&self) -> usize;
+
+    /// How far the chroma channels are reduced inside each block
+    fn sampling(&self) -> ChromaSampling;
+
+    /// Describes each coefficient of a block, in the order `forward` produces them
+    fn layout(&self) -> Vec<Coefficient>;
+
+    /// Serializes the settings the decoder needs to rebuild this transform
+    fn parameters(&self) -> Vec<u8>;
+
+    /// Turns one block of pixels (in row-major order) into quantized coefficients
+    fn forward(&self, pixels: &[Ypbpr]) -> Result<Vec<i64>, RpegError>;
+
+    /// Turns one block's coefficients back into pixels (in row-major order)
+    fn inverse(&self, coefficients: &[i64]) -> Result<Vec<Ypbpr>, RpegError>;
+
+    /// Serializes the coefficients of every block, in rows of `columns`, into the stream payload
+    fn write_blocks(&self, blocks: &[Vec<i64>], columns: usize) -> Result<Vec<u8>, RpegError>;
+
+    /// Parses `count` blocks of coefficients, in rows of `columns`, back out of the stream payload
+    fn read_blocks(&self, payload: &[u8], columns: usize, count: usize) -> Result<Vec<Vec<i64>>, RpegError>;
+}
+
+/// Rebuilds the transform a stream was encoded with from the id and parameters in its header
+///
+/// # Arguments:
+/// * `id`: the value the transform's `id` returned at encode time
+/// * `parameters`: the bytes the transform's `parameters` returned at encode time
+pub fn from_parameters(id: u8, parameters: &[u8]) -> Result<Arc<dyn BlockTransform>, RpegError> {
+    match id {
+        Haar2x2::ID => Ok(Arc::new(Haar2x2::from_parameters(parameters)?)),
+        Dct8x8::ID => Ok(Arc::new(Dct8x8::from_parameters(parameters)?)),
+        _ => Err(RpegError::InvalidHeader(format!("unknown transform {}", id))),
+    }
+}
+
+/// Looks up a transform by name, configured for the given quality, chroma subsampling and depth
+///
+/// # Arguments:
+/// * `name`: the transform's `name`
+/// * `quality`: quality from 1 to 100
+/// * `sampling`: how far the chroma channels are reduced
+/// * `depth`: the word depth, for the transforms that pack fixed-size words
+pub fn by_name(name: &str, quality: u8, sampling: ChromaSampling, depth: Depth) -> Option<Arc<dyn BlockTransform>> {
+    match name {
+        Haar2x2::NAME => Some(Arc::new(Haar2x2::with_sampling(QuantParams::for_depth(quality, depth), sampling))),
+        Dct8x8::NAME => Some(Arc::new(Dct8x8::with_sampling(quality, sampling))),
+        _ => None,
+    }
+}
+
+// -----------------------------------------------------------------------------------
+//                              2x2 HAAR TRANSFORM
+// -----------------------------------------------------------------------------------
+
+/// How the 2x2 transform quantizes its values, and which word layout it uses by default
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Depth {
+    /// `index_of_chroma` chroma in the assignment's 32-bit layout, for 8-bit images
+    #[default]
+    Standard,
+    /// 9-bit linear chroma in the 64-bit layout, for 16-bit images or higher fidelity
+    Deep,
+}
+
+impl Depth {
+    /// Value recorded in the stream for this depth
+    pub fn id(&self) -> u8 {
+        match self {
+            Depth::Standard => 0,
+            Depth::Deep => 1,
+        }
+    }
+
+    /// Parses the value written by `id`
+    pub fn from_id(id: u8) -> Result<Self, RpegError> {
+        match id {
+            0 => Ok(Depth::Standard),
+            1 => Ok(Depth::Deep),
+            _ => Err(RpegError::InvalidHeader(format!("unknown word depth {}", id))),
+        }
+    }
+
+    /// Bits needed to hold any chroma index at this depth
+    pub fn chroma_bits(&self) -> u64 {
+        match self {
+            Depth::Standard => 4,
+            Depth::Deep => 9,
+        }
+    }
+
+    /// The word layout used at this depth unless a custom one is given
+    pub fn layout(&self, chroma_count: usize) -> WordLayout {
+        match self {
+            Depth::Standard => WordLayout::standard(chroma_count),
+            Depth::Deep => WordLayout::wide(chroma_count),
+        }
+    }
+}
+
+/// Settings for the quantizer that turns a block's a, b, c, d and chroma values into integers
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantParams {
+    /// Multiplier applied to `a` (which lies in 0..=1) before rounding
+    pub a_scale: f32,
+    /// `b`, `c` and `d` are clamped to -bcd_clamp..=bcd_clamp before scaling
+    pub bcd_clamp: f32,
+    /// Multiplier applied to `b`, `c` and `d` before rounding
+    pub bcd_scale: f32,
+    /// Chroma indices are snapped to the middle of buckets of this many indices
+    pub chroma_step: usize,
+    /// Whether chroma uses `index_of_chroma` (standard) or linear levels (deep)
+    pub depth: Depth,
+}
+
+impl QuantParams {
+    /// The parameters used by the original assignment, which fill the 9/5/5/5/4/4 word exactly
+    pub fn legacy() -> Self {
+        QuantParams {
+            a_scale: 511.0,
+            bcd_clamp: 0.3,
+            bcd_scale: 50.0,
+            chroma_step: 1,
+            depth: Depth::Standard,
+        }
+    }
+
+    /// The deep counterpart of `legacy`, which fills the 16/10/10/10/9/9 word
+    pub fn deep() -> Self {
+        QuantParams {
+            a_scale: 65535.0,
+            bcd_clamp: 0.3,
+            bcd_scale: 1700.0,
+            chroma_step: 1,
+            depth: Depth::Deep,
+        }
+    }
+
+    /// Scales the legacy parameters down for a quality between 1 (coarsest) and 100 (legacy)
+    ///
+    /// # Arguments:
+    /// * `quality`: the requested quality, clamped to 1..=100
+    pub fn from_quality(quality: u8) -> Self {
+        QuantParams::for_depth(quality, Depth::Standard)
+    }
+
+    /// Scales the full-precision parameters for `depth` down for a quality between 1 and 100
+    ///
+    /// # Arguments:
+    /// * `quality`: the requested quality, clamped to 1..=100
+    /// * `depth`: the word depth the parameters are for
+    pub fn for_depth(quality: u8, depth: Depth) -> Self {
+        let full = match depth {
+            Depth::Standard => QuantParams::legacy(),
+            Depth::Deep => QuantParams::deep(),
+        };
+        return full.scaled(quality);
+    }
+
+    /// Picks parameters that fill the fields of a custom `layout`, scaled down for a quality
+    /// between 1 and 100
+    ///
+    /// # Arguments:
+    /// * `quality`: the requested quality, clamped to 1..=100
+    /// * `depth`: how chroma is quantized
+    /// * `layout`: the word layout the values are packed into
+    pub fn for_layout(quality: u8, depth: Depth, layout: &WordLayout) -> Self {
+        let bcd_clamp = QuantParams::legacy().bcd_clamp;
+        let full = QuantParams {
+            a_scale: field_max(layout.a_bits()) as f32,
+            bcd_clamp,
+            bcd_scale: field_max(layout.bcd_bits().saturating_sub(1)) as f32 / bcd_clamp,
+            chroma_step: 1,
+            depth,
+        };
+        return full.scaled(quality);
+    }
+
+    /// Scales full-precision parameters down for `quality`
+    fn scaled(&self, quality: u8) -> Self {
+        let quality = quality.clamp(1, 100);
+        let fraction = quality as f32 / 100.0;
+
+        // Fewer luma levels at lower qualities, and coarser chroma below the halfway mark.
+        // Chroma buckets cover the same share of the range at either depth
+        let chroma_levels = 1 << self.depth.chroma_bits();
+        let chroma_step = match quality {
+            50..=100 => 1,
+            25..=49 => chroma_levels / 8,
+            _ => chroma_levels / 4,
+        };
+
+        QuantParams {
+            a_scale: (self.a_scale * fraction).round().max(1.0),
+            bcd_clamp: self.bcd_clamp,
+            bcd_scale: self.bcd_scale * fraction,
+            chroma_step,
+            depth: self.depth,
+        }
+    }
+
+    /// Checks that every quantized value these parameters can produce fits in its field of `layout`
+    pub fn validate(&self, layout: &WordLayout) -> Result<(), RpegError> {
+        if !(self.a_scale > 0.0 && self.a_scale.round() <= field_max(layout.a_bits()) as f32) {
+            return Err(RpegError::FieldOverflow { field: "a", value: self.a_scale.round() as i64 });
+        }
+        let bcd_max = (self.bcd_clamp * self.bcd_scale).round();
+        if !(self.bcd_clamp > 0.0 && self.bcd_scale > 0.0 && bcd_max <= field_max(layout.bcd_bits().saturating_sub(1)) as f32) {
+            return Err(RpegError::FieldOverflow { field: "b", value: bcd_max as i64 });
+        }
+        if layout.chroma_bits() < self.depth.chroma_bits() {
+            return Err(RpegError::FieldOverflow { field: "avg_pb", value: (1 << self.depth.chroma_bits()) - 1 });
+        }
+        if self.chroma_step == 0 || self.chroma_step > (1 << self.depth.chroma_bits()).min(255) {
+            return Err(RpegError::FieldOverflow { field: "avg_pb", value: self.chroma_step as i64 });
+        }
+        return Ok(());
+    }
+}
+
+/// Largest unsigned value a field `bits` wide can hold
+fn field_max(bits: u64) -> u64 {
+    return 1_u64.checked_shl(bits as u32).map_or(u64::MAX, |limit| limit - 1);
+}
+
+/// The transform from the original assignment: each 2x2 block becomes a, b, c, d and
+/// the block's chroma, packed into one word (32 bits for standard 4:2:0, as the assignment
+/// had it, 64 bits for deep 4:2:0, or whatever a custom layout describes)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Haar2x2 {
+    pub params: QuantParams,
+    pub sampling: ChromaSampling,
+    pub layout: WordLayout,
+    /// How the coefficients are written into the payload
+    pub coding: Coding,
+    /// Whether a and the chroma indices are stored as differences from neighbouring blocks
+    pub prediction: Prediction,
+    /// Whether runs of blocks that repeat the block before with no detail are left out
+    pub skip_runs: bool,
+}
+
+impl Haar2x2 {
+    pub const ID: u8 = 0;
+    pub const NAME: &'static str = "haar";
+
+    /// Builds the transform with the assignment's 4:2:0 chroma
+    pub fn new(params: QuantParams) -> Self {
+        Haar2x2::with_sampling(params, ChromaSampling::Yuv420)
+    }
+
+    /// Builds the transform with the default word layout for `params.depth`
+    pub fn with_sampling(params: QuantParams, sampling: ChromaSampling) -> Self {
+        let layout = params.depth.layout(sampling.block_samples(2));
+        Haar2x2 { params, sampling, layout, coding: Coding::Fixed, prediction: Prediction::None, skip_runs: false }
+    }
+
+    /// Builds the transform with a custom word layout, checking that `params` fit it
+    pub fn with_layout(params: QuantParams, sampling: ChromaSampling, layout: WordLayout) -> Result<Self, RpegError> {
+        let transform = Haar2x2 { params, sampling, layout, coding: Coding::Fixed, prediction: Prediction::None, skip_runs: false };
+        transform.validate()?;
+        return Ok(transform);
+    }
+
+    /// Checks that the layout has a field for every value and that every value fits its field
+    pub fn validate(&self) -> Result<(), RpegError> {
+        self.layout.validate(self.sampling.block_samples(2))?;
+        return self.params.validate(&self.layout);
+    }
+
+    /// Parses the bytes written by `parameters`
+    pub fn from_parameters(parameters: &[u8]) -> Result<Self, RpegError> {
+        let mut rest = parameters;
+
+        let [word_bits, field_count] = take_array::<2>(&mut rest)?;
+        let mut fields = Vec::new();
+        for _ in 0..field_count {
+            let [width, lsb] = take_array::<2>(&mut rest)?;
+            fields.push(FieldLayout { width: width as u64, lsb: lsb as u64 });
+        }
+        let layout = WordLayout { word_bits: word_bits as u64, fields };
+
+        let a_scale = f32::from_be_bytes(take_array(&mut rest)?);
+        let bcd_clamp = f32::from_be_bytes(take_array(&mut rest)?);
+        let bcd_scale = f32::from_be_bytes(take_array(&mut rest)?);
+        let [chroma_step, sampling, depth, coding, prediction, skip_runs] = take_array::<6>(&mut rest)?;
+        if !rest.is_empty() {
+            return Err(RpegError::InvalidHeader(format!("{} unexpected bytes after the 2x2 parameters", rest.len())));
+        }
+        let params = QuantParams { a_scale, bcd_clamp, bcd_scale, chroma_step: chroma_step as usize, depth: Depth::from_id(depth)? };
+        let sampling = ChromaSampling::from_id(sampling)?;
+        let coding = Coding::from_id(coding)?;
+        let prediction = Prediction::from_id(prediction)?;
+        let skip_runs = match skip_runs {
+            0 => false,
+            1 => true,
+            flag => return Err(RpegError::InvalidHeader(format!("unknown skip runs flag {}", flag))),
+        };
+
+        // The word layout is recorded in full, so it only has to be one we can unpack
+        let transform = Haar2x2::with_layout(params, sampling, layout)
+            .map_err(|err| RpegError::InvalidHeader(format!("unusable 2x2 parameters ({})", err)))?;
+        return Ok(Haar2x2 { coding, prediction, skip_runs, ..transform });
+    }
+
+    /// Describes the coefficients as the coding sees them, after any prediction
+    fn coded_layout(&self) -> Vec<Coefficient> {
+        match self.prediction {
+            Prediction::None => self.layout(),
+            Prediction::Med => predicted_layout(&self.layout()),
+        }
+    }
+
+    /// Packs each block into one word of the layout
+    fn write_words(&self, blocks: &[Vec<i64>]) -> Result<Vec<u8>, RpegError> {
+        let values = blocks.iter()
+            .map(|coefficients| PixelBlockValues::from_coefficients(coefficients))
+            .collect::<Result<Vec<_>, _>>()?;
+        return pack_words(&values, &self.layout);
+    }
+
+    /// Unpacks `count` words of the layout back into blocks
+    fn read_words(&self, payload: &[u8], count: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+        let word_bytes = (self.layout.word_bits / 8) as usize;
+        if !payload.len().is_multiple_of(word_bytes) {
+            return Err(RpegError::Truncated);
+        }
+        if payload.len() / word_bytes != count {
+            return Err(RpegError::DimensionMismatch { expected: count, actual: payload.len() / word_bytes });
+        }
+        return Ok(unpack_words(payload, &self.layout)?.iter().map(|block| block.to_coefficients()).collect());
+    }
+}
+
+impl BlockTransform for Haar2x2 {
+    fn id(&self) -> u8 {
+        Haar2x2::ID
+    }
+
+    fn name(&self) -> &'static str {
+        Haar2x2::NAME
+    }
+
+    fn block_size(&self) -> usize {
+        2
+    }
+
+    fn sampling(&self) -> ChromaSampling {
+        self.sampling
+    }
+
+    fn layout(&self) -> Vec<Coefficient> {
+        let count = self.layout.chroma_count();
+        let mut names = vec!["a", "b", "c", "d"];
+        names.extend(std::iter::repeat_n("avg_pb", count));
+        names.extend(std::iter::repeat_n("avg_pr", count));
+        return self.layout.fields.iter().zip(names).enumerate()
+            .map(|(i, (field, name))| Coefficient {
+                name,
+                kind: if (1..4).contains(&i) { CoefficientKind::Signed } else { CoefficientKind::Unsigned },
+                bits: field.width,
+            })
+            .collect();
+    }
+
+    fn parameters(&self) -> Vec<u8> {
+        let mut parameters = vec![self.layout.word_bits as u8, self.layout.fields.len() as u8];
+        for field in &self.layout.fields {
+            parameters.push(field.width as u8);
+            parameters.push(field.lsb as u8);
+        }
+        parameters.extend_from_slice(&self.params.a_scale.to_be_bytes());
+        parameters.extend_from_slice(&self.params.bcd_clamp.to_be_bytes());
+        parameters.extend_from_slice(&self.params.bcd_scale.to_be_bytes());
+        parameters.push(self.params.chroma_step as u8);
+        parameters.push(self.sampling.id());
+        parameters.push(self.params.depth.id());
+        parameters.push(self.coding.id());
+        parameters.push(self.prediction.id());
+        parameters.push(self.skip_runs as u8);
+        return parameters;
+    }
+
+    fn forward(&self, pixels: &[Ypbpr]) -> Result<Vec<i64>, RpegError> {
+        let block = discrete_cosine_transfer(pixels.to_vec(), &self.params, self.sampling)?;
+        return Ok(block.to_coefficients());
+    }
+
+    fn inverse(&self, coefficients: &[i64]) -> Result<Vec<Ypbpr>, RpegError> {
+        let block = PixelBlockValues::from_coefficients(coefficients)?;
+        return inverse_discrete_cosine_transfer(&block, &self.params, self.sampling);
+    }
+
+    fn write_blocks(&self, blocks: &[Vec<i64>], columns: usize) -> Result<Vec<u8>, RpegError> {
+        let predicted;
+        let blocks = match self.prediction {
+            Prediction::None => blocks,
+            Prediction::Med => {
+                predicted = predict(blocks, &self.layout(), columns)?;
+                predicted.as_slice()
+            }
+        };
+
+        // With skip runs, the payload opens with the number of blocks kept
+        let mut payload = Vec::new();
+        let mut layout = self.coded_layout();
+        let kept;
+        let blocks = match self.skip_runs {
+            false => blocks,
+            true => {
+                kept = skip_runs(blocks, &layout)?;
+                layout = run_layout(&layout);
+                write_leb128(&mut BitWriter::new(&mut payload), kept.len() as u64).map_err(stream_error)?;
+                kept.as_slice()
+            }
+        };
+        payload.extend(match self.coding {
+            Coding::VariableLength => write_variable(blocks, &layout)?,
+            Coding::Arithmetic => write_arithmetic(blocks, &layout)?,
+            Coding::Huffman => write_huffman(blocks, &layout)?,
+            Coding::Fixed => self.write_words(blocks)?,
+        });
+        return Ok(payload);
+    }
+
+    fn read_blocks(&self, payload: &[u8], columns: usize, count: usize) -> Result<Vec<Vec<i64>>, RpegError> {
+        let mut rest = payload;
+        let layout = self.coded_layout();
+        let (stored, stored_layout) = match self.skip_runs {
+            false => (count, layout.clone()),
+            true => {
+                let kept = read_leb128(&mut BitReader::new(&mut rest)).map_err(stream_error)?;
+                if kept > count as u64 {
+                    return Err(RpegError::DimensionMismatch { expected: count, actual: kept as usize });
+                }
+                (kept as usize, run_layout(&layout))
+            }
+        };
+        let mut blocks = match self.coding {
+            Coding::VariableLength => read_variable(rest, &stored_layout, stored)?,
+            Coding::Arithmetic => read_arithmetic(rest, &stored_layout, stored)?,
+            Coding::Huffman => read_huffman(rest, &stored_layout, stored)?,
+            Coding::Fixed => self.read_words(rest, stored)?,
+        };
+        if self.skip_runs {
+            blocks = expand_runs(&blocks, &layout, count)?;
+        }
+        if self.prediction == Prediction::Med {
+            unpredict(&mut blocks, &self.layout(), columns)?;
+        }
+
+        // The variable-length codings can hold values wider than the fields they stand for
+        let layout = self.layout();
+        for block in &blocks {
+            check_block(block, &layout)?;
+        }
+        return Ok(blocks);
+    }
+}
+
+impl PixelBlockValues {
+    /// Lists the block's values in the order of `Haar2x2::layout`
+    pub fn to_coefficients(&self) -> Vec<i64> {
+        let mut coefficients = vec![self.a as i64, self.b as i64, self.c as i64, self.d as i64];
+        coefficients.extend(self.avg_pb.iter().map(|&index| index as i64));
+        coefficients.extend(self.avg_pr.iter().map(|&index| index as i64));
+        return coefficients;
+    }
+
+    /// Rebuilds a block from values in the order of `Haar2x2::layout`
+    pub fn from_coefficients(coefficients: &[i64]) -> Result<Self, RpegError> {
+        if coefficients.len() < 6 || !coefficients.len().is_multiple_of(2) {
+            return Err(RpegError::DimensionMismatch { expected: 6, actual: coefficients.len() });
+        }
+        let (luma, chroma) = coefficients.split_at(4);
+        let (pb, pr) = chroma.split_at(chroma.len() / 2);
+        let indices = |values: &[i64], field: &'static str| values.iter()
+            .map(|&value| usize::try_from(value).map_err(|_| RpegError::FieldOverflow { field, value }))
+            .collect::<Result<Vec<_>, _>>();
+
+        return Ok(PixelBlockValues {
+            a: luma[0] as f32,
+            b: luma[1] as f32,
+            c: luma[2] as f32,
+            d: luma[3] as f32,
+            avg_pb: indices(pb, "avg_pb")?,
+            avg_pr: indices(pr, "avg_pr")?,
+        });
+    }
+}
 
 // Function takes in a vector filled with 4 pixels of component video (ypbpr) type
 // then it will convert this block into our custom struct type PixelBlockValues
-pub fn discrete_cosine_transfer(pixels: Vec<Ypbpr>) -> PixelBlockValues {
-    
+// using the quantizer settings in `params`, keeping as many chroma samples as `sampling` asks for
+pub fn discrete_cosine_transfer(pixels: Vec<Ypbpr>, params: &QuantParams, sampling: ChromaSampling) -> Result<PixelBlockValues, RpegError> {
+
+    if pixels.len() != 4 {
+        return Err(RpegError::DimensionMismatch { expected: 4, actual: pixels.len() });
+    }
+
     // Use this for simplification of division for averages
     let pixel_total: f32 = pixels.len() as f32;
 
@@ -27,25 +603,52 @@ pub fn discrete_cosine_transfer(pixels: Vec<Ypbpr>) -> PixelBlockValues {
     let mut d = (pixels[3].y - pixels[2].y - pixels[1].y + pixels[0].y) / pixel_total;
 
 
-    // For b, c, d, we clamp it to be between the floating point range of -0.3 and 0.3
-    a = (a * (511 as f32)).round();
-    b = (b.clamp(-0.3,0.3) * (50 as f32)).round();
-    c = (c.clamp(-0.3,0.3) * (50 as f32)).round();
-    d = (d.clamp(-0.3,0.3) * (50 as f32)).round();
+    // For b, c, d, we clamp it to be between the floating point range of -bcd_clamp and bcd_clamp
+    let clamp = params.bcd_clamp;
+    a = (a * params.a_scale).round();
+    b = (b.clamp(-clamp, clamp) * params.bcd_scale).round();
+    c = (c.clamp(-clamp, clamp) * params.bcd_scale).round();
+    d = (d.clamp(-clamp, clamp) * params.bcd_scale).round();
 
-    // Calculate average pb
-    let avg_pb = (pixels[0].pb + pixels[1].pb + pixels[2].pb + pixels[3].pb) / pixel_total;
-    let avg_pb = index_of_chroma(avg_pb as f32);
+    // Calculate average pb and pr over each area that shares a chroma sample
+    let avg_pb = sampling.downsample(&pixels, 2, 2, |pixel| pixel.pb).into_iter()
+        .map(|value| quantize_chroma(value, params))
+        .collect();
+    let avg_pr = sampling.downsample(&pixels, 2, 2, |pixel| pixel.pr).into_iter()
+        .map(|value| quantize_chroma(value, params))
+        .collect();
 
-    // Calculate average pr
-    let avg_pr = (pixels[0].pr + pixels[1].pr + pixels[2].pr + pixels[3].pr) / pixel_total;
-    let avg_pr = index_of_chroma(avg_pr as f32);
+    return Ok(PixelBlockValues {a, b, c, d, avg_pb, avg_pr});
 
-    return PixelBlockValues {a, b, c, d, avg_pb, avg_pr};
+}
 
+/// Looks up the chroma index for `value` (in the `index_of_chroma` table for standard depth,
+/// or on an even scale over -0.5..=0.5 for deep), then snaps it to the middle of its bucket
+/// of `chroma_step` indices
+fn quantize_chroma(value: f32, params: &QuantParams) -> usize {
+    let max = (1 << params.depth.chroma_bits()) - 1;
+    let index = match params.depth {
+        Depth::Standard => index_of_chroma(value),
+        Depth::Deep => ((value + 0.5).clamp(0.0, 1.0) * max as f32).round() as usize,
+    };
+    let step = params.chroma_step;
+    return ((index / step) * step + step / 2).min(max);
+}
+
+/// Reverses `quantize_chroma`, failing for an index past the end of the depth's scale
+fn dequantize_chroma(index: usize, depth: Depth) -> Result<f32, RpegError> {
+    let max = (1 << depth.chroma_bits()) - 1;
+    if index > max {
+        return Err(RpegError::FieldOverflow { field: "chroma index", value: index as i64 });
+    }
+    return Ok(match depth {
+        Depth::Standard => chroma_of_index(index),
+        Depth::Deep => (index as f32 / max as f32) - 0.5,
+    });
 }
 
-pub fn inverse_discrete_cosine_transfer(pixel: &PixelBlockValues) -> Vec<Ypbpr> {
+// Function reverses discrete_cosine_transfer, using the same `params` and `sampling` the block was quantized with
+pub fn inverse_discrete_cosine_transfer(pixel: &PixelBlockValues, params: &QuantParams, sampling: ChromaSampling) -> Result<Vec<Ypbpr>, RpegError> {
 
     let mut pixels = Vec::new();
 
@@ -57,10 +660,11 @@ pub fn inverse_discrete_cosine_transfer(pixel: &PixelBlockValues) -> Vec<Ypbpr>
     // Y3 = a + b − c − d
     // Y4 = a + b + c + d
     // We must first get the a, b, c, and d values:
-    let a: f32 = (pixel.a as f32 / (511 as f32)).clamp(0.0,1.0);
-    let b: f32 = (pixel.b as f32 / (50 as f32)).clamp(-0.3,0.3);
-    let c: f32 = (pixel.c as f32 / (50 as f32)).clamp(-0.3,0.3);
-    let d: f32 = (pixel.d as f32 / (50 as f32)).clamp(-0.3,0.3);
+    let clamp = params.bcd_clamp;
+    let a: f32 = (pixel.a / params.a_scale).clamp(0.0,1.0);
+    let b: f32 = (pixel.b / params.bcd_scale).clamp(-clamp, clamp);
+    let c: f32 = (pixel.c / params.bcd_scale).clamp(-clamp, clamp);
+    let d: f32 = (pixel.d / params.bcd_scale).clamp(-clamp, clamp);
 
     // Then we calculate as follows: 
     let mut y_vec = Vec::new();
@@ -69,12 +673,119 @@ pub fn inverse_discrete_cosine_transfer(pixel: &PixelBlockValues) -> Vec<Ypbpr>
     y_vec.push(a + b - c - d);
     y_vec.push(a + b + c + d);
 
-    let pb = chroma_of_index(pixel.avg_pb as usize);
-    let pr = chroma_of_index(pixel.avg_pr as usize);
-
+    // Each chroma sample is copied to every pixel it was averaged from
+    let (chroma_width, _) = sampling.plane_size(2, 2);
+    let chroma_count = sampling.block_samples(2);
+    if pixel.avg_pb.len() < chroma_count || pixel.avg_pr.len() < chroma_count {
+        return Err(RpegError::DimensionMismatch { expected: chroma_count, actual: pixel.avg_pb.len().min(pixel.avg_pr.len()) });
+    }
     for i in 0..y_vec.len() {
+        let chroma_index = sampling.sample_index(i % 2, i / 2, chroma_width);
+        let pb = dequantize_chroma(pixel.avg_pb[chroma_index], params.depth)?;
+        let pr = dequantize_chroma(pixel.avg_pr[chroma_index], params.depth)?;
         pixels.push(Ypbpr {y: y_vec[i] as f32, pb: pb as f32, pr: pr as f32});
     }
     
-    return pixels;
-}
\ No newline at end of file
+    return Ok(pixels);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x2 block of gently varying luma with chroma straight from the `index_of_chroma` table
+    fn block(seed: usize) -> Vec<Ypbpr> {
+        (0..4)
+            .map(|i| Ypbpr { y: 0.25 + (0.01 * ((seed * 7 + i * 3) % 11) as f32), pb: 0.1, pr: -0.2 })
+            .collect()
+    }
+
+    #[test]
+    fn inverse_test() {
+        for (params, tolerance) in [(QuantParams::legacy(), 0.03), (QuantParams::deep(), 0.002)] {
+            let transform = Haar2x2::new(params);
+            for seed in 0..20 {
+                let pixels = block(seed);
+                let decoded = transform.inverse(&transform.forward(&pixels).unwrap()).unwrap();
+                for (before, after) in pixels.iter().zip(&decoded) {
+                    assert!((before.y - after.y).abs() < tolerance, "{:?} became {:?}", before, after);
+                    assert!((before.pb - after.pb).abs() < tolerance, "{:?} became {:?}", before, after);
+                    assert!((before.pr - after.pr).abs() < tolerance, "{:?} became {:?}", before, after);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parameters_test() {
+        for transform in [Haar2x2::new(QuantParams::legacy()), Haar2x2::new(QuantParams::deep()), Haar2x2::new(QuantParams::from_quality(30))] {
+            let rebuilt = from_parameters(Haar2x2::ID, &transform.parameters()).unwrap();
+            assert_eq!(rebuilt.parameters(), transform.parameters());
+            assert_eq!(Haar2x2::from_parameters(&transform.parameters()).unwrap(), transform);
+        }
+        assert!(matches!(from_parameters(9, &[]), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(Haar2x2::from_parameters(&[32, 6]), Err(RpegError::Truncated)));
+
+        // Every setting must be recorded, and nothing after them
+        let parameters = Haar2x2::new(QuantParams::legacy()).parameters();
+        assert!(matches!(Haar2x2::from_parameters(&parameters[..parameters.len() - 1]), Err(RpegError::Truncated)));
+        assert!(matches!(Haar2x2::from_parameters(&[parameters.as_slice(), &[0]].concat()), Err(RpegError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn words_test() {
+        let transform = Haar2x2::new(QuantParams::legacy());
+        let blocks: Vec<Vec<i64>> = (0..6).map(|seed| transform.forward(&block(seed)).unwrap()).collect();
+        let payload = transform.write_blocks(&blocks, 3).unwrap();
+        assert_eq!(payload.len(), 6 * 4);
+        assert_eq!(transform.read_blocks(&payload, 3, 6).unwrap(), blocks);
+
+        assert!(matches!(transform.read_blocks(&payload[..23], 3, 6), Err(RpegError::Truncated)));
+        assert!(matches!(transform.read_blocks(&payload, 3, 5), Err(RpegError::DimensionMismatch { .. })));
+    }
+
+    #[test]
+    fn overflow_test() {
+        let transform = Haar2x2::new(QuantParams::legacy());
+        let mut coefficients = transform.forward(&block(0)).unwrap();
+        coefficients[1] = 16;
+        assert!(matches!(transform.write_blocks(&[coefficients], 1), Err(RpegError::FieldOverflow { field: "b", .. })));
+    }
+
+    #[test]
+    fn range_test() {
+        let mut transform = Haar2x2::new(QuantParams::legacy());
+        for coding in [Coding::VariableLength, Coding::Arithmetic, Coding::Huffman] {
+            transform.coding = coding;
+            let blocks: Vec<Vec<i64>> = (0..4).map(|seed| transform.forward(&block(seed)).unwrap()).collect();
+            assert_eq!(transform.read_blocks(&transform.write_blocks(&blocks, 2).unwrap(), 2, 4).unwrap(), blocks);
+
+            // These codings write a b of any size, but the field it stands for cannot hold it
+            let mut wide = blocks.clone();
+            wide[3][1] = 1000;
+            let payload = match coding {
+                Coding::VariableLength => write_variable(&wide, &transform.layout()),
+                Coding::Arithmetic => write_arithmetic(&wide, &transform.layout()),
+                _ => write_huffman(&wide, &transform.layout()),
+            }.unwrap();
+            assert!(matches!(transform.read_blocks(&payload, 2, 4), Err(RpegError::FieldOverflow { field: "b", value: 1000 })));
+        }
+
+        // A Huffman table can hold a chroma index past the end of the chroma table
+        let mut wide = (0..4).map(|seed| transform.forward(&block(seed)).unwrap()).collect::<Vec<_>>();
+        wide[2][4] = 16;
+        let wide_layout: Vec<Coefficient> = transform.layout().into_iter()
+            .map(|coefficient| match coefficient.name {
+                "avg_pb" => Coefficient { bits: 64, ..coefficient },
+                _ => coefficient,
+            })
+            .collect();
+        let payload = write_huffman(&wide, &wide_layout).unwrap();
+        assert!(matches!(transform.read_blocks(&payload, 2, 4), Err(RpegError::FieldOverflow { field: "avg_pb", value: 16 })));
+
+        // A wider custom field can hold it too, and the inverse refuses it rather than panicking
+        let mut coefficients = transform.forward(&block(0)).unwrap();
+        coefficients[4] = 16;
+        assert!(matches!(transform.inverse(&coefficients), Err(RpegError::FieldOverflow { field: "chroma index", value: 16 })));
+        assert!(matches!(transform.inverse(&coefficients[..5]), Err(RpegError::DimensionMismatch { .. })));
+    }
+}
@@ -0,0 +1,497 @@
+use bitpack::bitio::{BitReader, BitWriter};
+use bitpack::codes::{read_leb128, write_leb128};
+use crate::chroma::ChromaSampling;
+use crate::compress_decompress::Ypbpr;
+use crate::container::take_array;
+use crate::error::RpegError;
+use crate::entropy::stream_error;
+use crate::rle::{write_run_length, read_run_length};
+
+// Documentation:
+// Both filters are written as integer lifting steps, so the transform itself is exactly
+// reversible and only the quantizer loses information:
+//     Haar (S-transform):  d = x[2n+1] - x[2n]
+//                          s = x[2n] + floor(d / 2)
+//     CDF 5/3 (LeGall):    d = x[2n+1] - floor((x[2n] + x[2n+2]) / 2)
+//                          s = x[2n] + floor((d[n-1] + d[n] + 2) / 4)
+// with the signal mirrored at its ends. Each level splits the current low band into
+// LL, HL, LH and HH subbands in place (Mallat layout), and the payload stores subbands from
+// the coarsest LL to the finest HH, so the coarse levels can be decoded on their own.
+
+/// Samples are stored as integers in units of 1/SAMPLE_SCALE
+const SAMPLE_SCALE: f32 = 1024.0;
+
+/// Largest number of decomposition levels a stream may ask for
+pub const MAX_LEVELS: u8 = 15;
+
+/// The lifting filter applied at every level
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaveletFilter {
+    Haar,
+    Cdf53,
+}
+
+/// Multi-level wavelet coding of the whole image, selected instead of a block transform
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wavelet {
+    pub filter: WaveletFilter,
+    /// Number of times the low band is split again
+    pub levels: u8,
+    /// Quality from 1 (coarsest) to 100 (finest quantizer step)
+    pub quality: u8,
+    /// How far the chroma planes are reduced before they are transformed
+    pub sampling: ChromaSampling,
+}
+
+impl Wavelet {
+    /// Id recorded in the header in place of a block transform's id
+    pub const ID: u8 = 2;
+
+    /// Builds a wavelet mode, failing if `levels` is not 1 to MAX_LEVELS or `quality` not 1 to 100
+    pub fn new(filter: WaveletFilter, levels: u8, quality: u8, sampling: ChromaSampling) -> Result<Self, RpegError> {
+        if levels == 0 || levels > MAX_LEVELS {
+            return Err(RpegError::IncompatibleOptions(format!("the wavelet modes take 1 to {} levels, not {}", MAX_LEVELS, levels)));
+        }
+        if quality == 0 || quality > 100 {
+            return Err(RpegError::IncompatibleOptions(format!("the wavelet modes take a quality from 1 to 100, not {}", quality)));
+        }
+        return Ok(Wavelet { filter, levels, quality, sampling });
+    }
+
+    /// Looks up a wavelet mode by name ("wavelet" for Haar, "cdf53" for CDF 5/3), giving `None`
+    /// for any other name and an error for settings `new` rejects
+    pub fn by_name(name: &str, levels: u8, quality: u8, sampling: ChromaSampling) -> Result<Option<Self>, RpegError> {
+        return match name {
+            "wavelet" => Wavelet::new(WaveletFilter::Haar, levels, quality, sampling).map(Some),
+            "cdf53" => Wavelet::new(WaveletFilter::Cdf53, levels, quality, sampling).map(Some),
+            _ => Ok(None),
+        };
+    }
+
+    /// Serializes the settings the decoder needs
+    pub fn parameters(&self) -> Vec<u8> {
+        let filter = match self.filter {
+            WaveletFilter::Haar => 0,
+            WaveletFilter::Cdf53 => 1,
+        };
+        vec![filter, self.levels, self.quality, self.sampling.id()]
+    }
+
+    /// Parses the bytes written by `parameters`
+    pub fn from_parameters(parameters: &[u8]) -> Result<Self, RpegError> {
+        let mut rest = parameters;
+        let [filter, levels, quality, sampling] = take_array::<4>(&mut rest)?;
+        let sampling = ChromaSampling::from_id(sampling)?;
+        let filter = match filter {
+            0 => WaveletFilter::Haar,
+            1 => WaveletFilter::Cdf53,
+            _ => return Err(RpegError::InvalidHeader(format!("unknown wavelet filter {}", filter))),
+        };
+        return Wavelet::new(filter, levels, quality, sampling)
+            .map_err(|_| RpegError::InvalidHeader("wavelet parameters are out of range".to_string()));
+    }
+
+    /// Transforms, quantizes and packs a row-major component video image
+    ///
+    /// # Arguments:
+    /// * `image`: the pixels to compress
+    /// * `width`: the width of the image
+    /// * `height`: the height of the image
+    pub fn encode(&self, image: &[Ypbpr], width: usize, height: usize) -> Result<Vec<u8>, RpegError> {
+        if image.len() != width * height {
+            return Err(RpegError::DimensionMismatch { expected: width * height, actual: image.len() });
+        }
+
+        // Chroma is reduced before it is transformed, as in the block modes
+        let (chroma_width, chroma_height) = self.sampling.plane_size(width, height);
+        let planes = [
+            (to_samples(image.iter().map(|pixel| pixel.y)), width, height),
+            (to_samples(self.sampling.downsample(image, width, height, |pixel| pixel.pb)), chroma_width, chroma_height),
+            (to_samples(self.sampling.downsample(image, width, height, |pixel| pixel.pr)), chroma_width, chroma_height),
+        ];
+
+        let mut payload = Vec::new();
+        for (mut samples, plane_width, plane_height) in planes {
+            self.forward_2d(&mut samples, plane_width, plane_height)?;
+            for band in subbands(plane_width, plane_height, self.levels) {
+                let step = self.step(band.level);
+                let coefficients: Vec<i64> = band.indices(plane_width)
+                    .map(|index| (samples[index] as f32 / step).round() as i64)
+                    .collect();
+
+                // Each subband is length-prefixed so a reader can skip the finer levels
+                let mut coded = BitWriter::new(Vec::new());
+                write_run_length(&coefficients, &mut coded)?;
+                let coded = coded.into_inner().map_err(stream_error)?;
+                write_leb128(&mut BitWriter::new(&mut payload), coded.len() as u64).map_err(stream_error)?;
+                payload.extend_from_slice(&coded);
+            }
+        }
+        return Ok(payload);
+    }
+
+    /// Unpacks, dequantizes and inverse transforms a payload written by `encode`
+    ///
+    /// # Arguments:
+    /// * `payload`: the packed subbands
+    /// * `width`: the width of the image
+    /// * `height`: the height of the image
+    pub fn decode(&self, payload: &[u8], width: usize, height: usize) -> Result<Vec<Ypbpr>, RpegError> {
+        let (chroma_width, chroma_height) = self.sampling.plane_size(width, height);
+        let mut rest = payload;
+
+        let mut channels = Vec::new();
+        for (plane_width, plane_height) in [(width, height), (chroma_width, chroma_height), (chroma_width, chroma_height)] {
+            let mut samples = reserve(plane_width, plane_height)?;
+            samples.resize(plane_width * plane_height, 0_i32);
+            for band in subbands(plane_width, plane_height, self.levels) {
+                let length = read_leb128(&mut BitReader::new(&mut rest)).map_err(stream_error)?;
+                let length = usize::try_from(length).ok().filter(|&length| length <= rest.len()).ok_or(RpegError::Truncated)?;
+                let (coded, after) = rest.split_at(length);
+                rest = after;
+
+                // A coefficient the encoder could not have produced would overflow the lifting
+                let step = self.step(band.level);
+                let limit = self.limit(band.level) + step as f64;
+                let coefficients = read_run_length(&mut BitReader::new(coded), band.width * band.height)?;
+                for (index, coefficient) in band.indices(plane_width).zip(coefficients) {
+                    if (coefficient as f64 * step as f64).abs() > limit {
+                        return Err(RpegError::FieldOverflow { field: "wavelet coefficient", value: coefficient });
+                    }
+                    samples[index] = (coefficient as f32 * step).round() as i32;
+                }
+            }
+            self.inverse_2d(&mut samples, plane_width, plane_height)?;
+            channels.push(samples);
+        }
+
+        // Chroma samples are replicated back over the pixels they were averaged from
+        let mut image = reserve(width, height)?;
+        for y in 0..height {
+            for x in 0..width {
+                let chroma_index = self.sampling.sample_index(x, y, chroma_width);
+                image.push(Ypbpr {
+                    y: channels[0][(y * width) + x] as f32 / SAMPLE_SCALE,
+                    pb: channels[1][chroma_index] as f32 / SAMPLE_SCALE,
+                    pr: channels[2][chroma_index] as f32 / SAMPLE_SCALE,
+                });
+            }
+        }
+        return Ok(image);
+    }
+
+    /// Quantizer step for subbands produced at `level` (1 is the finest). Coarser levels hold
+    /// averages over larger areas, so their steps halve per level to keep the error per pixel even
+    fn step(&self, level: usize) -> f32 {
+        let quality = self.quality as f32;
+        let base = if quality < 50.0 { 5000.0 / quality } else { 200.0 - (2.0 * quality) } * 0.16;
+        return (base / (1 << (level - 1)) as f32).max(1.0);
+    }
+
+    /// Largest magnitude the encoder can give a coefficient produced at `level`. Samples start
+    /// within twice SAMPLE_SCALE, leaving room for rounding; a detail coefficient is at most
+    /// four times the low band it came from. The Haar low band stays within its inputs, while
+    /// the CDF 5/3 low-pass filter can grow it by 1.5 in each direction, 2.25 a level
+    fn limit(&self, level: usize) -> f64 {
+        let input = 2.0 * SAMPLE_SCALE as f64;
+        return match self.filter {
+            WaveletFilter::Haar => 4.0 * input,
+            WaveletFilter::Cdf53 => 4.0 * input * 2.25_f64.powi(level as i32 - 1),
+        };
+    }
+
+    /// Applies `levels` levels of the 2D transform to a row-major plane in place
+    fn forward_2d(&self, samples: &mut [i32], width: usize, height: usize) -> Result<(), RpegError> {
+        let (mut level_width, mut level_height) = (width, height);
+        for _ in 0..self.levels {
+            transform_rows(samples, width, level_width, level_height, |line| lift_forward(line, self.filter))?;
+            transform_columns(samples, width, level_width, level_height, |line| lift_forward(line, self.filter))?;
+            level_width = level_width.div_ceil(2);
+            level_height = level_height.div_ceil(2);
+        }
+        return Ok(());
+    }
+
+    /// Undoes `forward_2d` in place
+    fn inverse_2d(&self, samples: &mut [i32], width: usize, height: usize) -> Result<(), RpegError> {
+        let mut sizes = vec![(width, height)];
+        for _ in 1..self.levels {
+            let (level_width, level_height) = sizes[sizes.len() - 1];
+            sizes.push((level_width.div_ceil(2), level_height.div_ceil(2)));
+        }
+        for (level_width, level_height) in sizes.into_iter().rev() {
+            transform_columns(samples, width, level_width, level_height, |line| lift_inverse(line, self.filter))?;
+            transform_rows(samples, width, level_width, level_height, |line| lift_inverse(line, self.filter))?;
+        }
+        return Ok(());
+    }
+}
+
+// -----------------------------------------------------------------------------------
+//                              SUBBANDS
+// -----------------------------------------------------------------------------------
+
+/// A rectangle of coefficients that all came out of the same filter at the same level
+struct Subband {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    level: usize,
+}
+
+impl Subband {
+    /// Row-major indices of the subband's coefficients within a plane `plane_width` wide
+    fn indices(&self, plane_width: usize) -> impl Iterator<Item = usize> + '_ {
+        (self.y..self.y + self.height)
+            .flat_map(move |row| (self.x..self.x + self.width).map(move |col| (row * plane_width) + col))
+    }
+}
+
+/// Lists the subbands of a `width` x `height` plane after `levels` levels, coarsest first
+fn subbands(width: usize, height: usize, levels: u8) -> Vec<Subband> {
+    let mut details = Vec::new();
+    let (mut level_width, mut level_height) = (width, height);
+    for level in 1..=levels as usize {
+        let (low_width, low_height) = (level_width.div_ceil(2), level_height.div_ceil(2));
+        let (high_width, high_height) = (level_width - low_width, level_height - low_height);
+
+        // Finest level first here; reversed below so the output runs coarse to fine
+        details.push(Subband { x: 0, y: low_height, width: low_width, height: high_height, level });
+        details.push(Subband { x: low_width, y: 0, width: high_width, height: low_height, level });
+        details.push(Subband { x: low_width, y: low_height, width: high_width, height: high_height, level });
+        level_width = low_width;
+        level_height = low_height;
+    }
+
+    let mut bands = vec![Subband { x: 0, y: 0, width: level_width, height: level_height, level: levels as usize }];
+    bands.extend(details.into_iter().rev());
+    return bands;
+}
+
+// -----------------------------------------------------------------------------------
+//                              LIFTING
+// -----------------------------------------------------------------------------------
+
+/// Runs `lift` over the first `level_width` samples of each of the first `level_height` rows
+fn transform_rows(samples: &mut [i32], width: usize, level_width: usize, level_height: usize,
+                  lift: impl Fn(&mut [i32]) -> Result<(), RpegError>) -> Result<(), RpegError> {
+    for row in 0..level_height {
+        lift(&mut samples[(row * width)..(row * width) + level_width])?;
+    }
+    return Ok(());
+}
+
+/// Runs `lift` over the first `level_height` samples of each of the first `level_width` columns
+fn transform_columns(samples: &mut [i32], width: usize, level_width: usize, level_height: usize,
+                     lift: impl Fn(&mut [i32]) -> Result<(), RpegError>) -> Result<(), RpegError> {
+    let mut line = vec![0; level_height];
+    for col in 0..level_width {
+        for row in 0..level_height {
+            line[row] = samples[(row * width) + col];
+        }
+        lift(&mut line)?;
+        for row in 0..level_height {
+            samples[(row * width) + col] = line[row];
+        }
+    }
+    return Ok(());
+}
+
+/// One level of the 1D forward transform: the low band ends up in the first half of `line`
+/// (rounded up) and the high band in the rest. The steps run in i64, so a sample that does
+/// not fit back in an i32 is an error rather than an overflow
+pub fn lift_forward(line: &mut [i32], filter: WaveletFilter) -> Result<(), RpegError> {
+    let n = line.len();
+    if n < 2 {
+        return Ok(());
+    }
+    let (low_count, high_count) = (n.div_ceil(2), n / 2);
+    let input: Vec<i64> = line.iter().map(|&sample| sample as i64).collect();
+    let even = |i: usize| input[2 * i.min(low_count - 1)];
+
+    let mut high = vec![0; high_count];
+    let mut low = vec![0; low_count];
+    match filter {
+        WaveletFilter::Haar => {
+            for i in 0..high_count {
+                high[i] = input[(2 * i) + 1] - input[2 * i];
+            }
+            for i in 0..low_count {
+                low[i] = if i < high_count { input[2 * i] + (high[i] >> 1) } else { input[2 * i] };
+            }
+        }
+        WaveletFilter::Cdf53 => {
+            // Past the last even sample the signal is mirrored, which repeats its neighbour
+            for i in 0..high_count {
+                high[i] = input[(2 * i) + 1] - ((even(i) + even(i + 1)) >> 1);
+            }
+            for i in 0..low_count {
+                let left = high[i.saturating_sub(1).min(high_count - 1)];
+                let right = high[i.min(high_count - 1)];
+                low[i] = input[2 * i] + ((left + right + 2) >> 2);
+            }
+        }
+    }
+
+    low.extend(high);
+    return store(line, &low);
+}
+
+/// Undoes `lift_forward`
+pub fn lift_inverse(line: &mut [i32], filter: WaveletFilter) -> Result<(), RpegError> {
+    let n = line.len();
+    if n < 2 {
+        return Ok(());
+    }
+    let (low_count, high_count) = (n.div_ceil(2), n / 2);
+    let low: Vec<i64> = line[..low_count].iter().map(|&sample| sample as i64).collect();
+    let high: Vec<i64> = line[low_count..].iter().map(|&sample| sample as i64).collect();
+
+    let mut output = vec![0; n];
+    match filter {
+        WaveletFilter::Haar => {
+            for i in 0..low_count {
+                output[2 * i] = if i < high_count { low[i] - (high[i] >> 1) } else { low[i] };
+            }
+            for i in 0..high_count {
+                output[(2 * i) + 1] = high[i] + output[2 * i];
+            }
+        }
+        WaveletFilter::Cdf53 => {
+            for i in 0..low_count {
+                let left = high[i.saturating_sub(1).min(high_count - 1)];
+                let right = high[i.min(high_count - 1)];
+                output[2 * i] = low[i] - ((left + right + 2) >> 2);
+            }
+            for i in 0..high_count {
+                let right = output[2 * (i + 1).min(low_count - 1)];
+                output[(2 * i) + 1] = high[i] + ((output[2 * i] + right) >> 1);
+            }
+        }
+    }
+
+    return store(line, &output);
+}
+
+/// Copies the results of a lifting step back into `line`, failing on one too large for an i32
+fn store(line: &mut [i32], values: &[i64]) -> Result<(), RpegError> {
+    for (sample, &value) in line.iter_mut().zip(values) {
+        *sample = i32::try_from(value).map_err(|_| RpegError::FieldOverflow { field: "wavelet sample", value })?;
+    }
+    return Ok(());
+}
+
+// -----------------------------------------------------------------------------------
+//                              HELPERS
+// -----------------------------------------------------------------------------------
+
+fn to_samples(values: impl IntoIterator<Item = f32>) -> Vec<i32> {
+    values.into_iter().map(|value| (value * SAMPLE_SCALE).round() as i32).collect()
+}
+
+/// An empty vector with room for a `width` x `height` plane. A run-length payload can describe
+/// a huge plane in a few bytes, so a corrupt header fails here rather than aborting the process
+fn reserve<T>(width: usize, height: usize) -> Result<Vec<T>, RpegError> {
+    let mut values = Vec::new();
+    return width.checked_mul(height)
+        .and_then(|count| values.try_reserve_exact(count).ok())
+        .map(|_| values)
+        .ok_or(RpegError::InvalidHeader(format!("dimensions {}x{} are too large to decode", width, height)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifting_test() {
+        for filter in [WaveletFilter::Haar, WaveletFilter::Cdf53] {
+            for length in 0..12 {
+                let original: Vec<i32> = (0..length).map(|i| ((i * 37) % 23) - 11).collect();
+                let mut line = original.clone();
+                lift_forward(&mut line, filter).unwrap();
+                lift_inverse(&mut line, filter).unwrap();
+                assert_eq!(line, original, "{:?} over {} samples", filter, length);
+            }
+        }
+
+        // A constant signal has no detail
+        let mut line = vec![5; 8];
+        lift_forward(&mut line, WaveletFilter::Cdf53).unwrap();
+        assert_eq!(line, [5, 5, 5, 5, 0, 0, 0, 0]);
+
+        // A step whose result leaves the i32 range fails instead of overflowing
+        let mut line = vec![i32::MIN, i32::MAX];
+        assert!(matches!(lift_forward(&mut line, WaveletFilter::Haar), Err(RpegError::FieldOverflow { .. })));
+        let mut line = vec![i32::MAX, i32::MAX];
+        assert!(matches!(lift_inverse(&mut line, WaveletFilter::Cdf53), Err(RpegError::FieldOverflow { .. })));
+    }
+
+    #[test]
+    fn subbands_test() {
+        // Every coefficient of the plane lands in exactly one subband
+        for (width, height, levels) in [(8, 8, 3), (7, 5, 2), (1, 9, 4)] {
+            let mut seen = vec![0; width * height];
+            for band in subbands(width, height, levels) {
+                band.indices(width).for_each(|index| seen[index] += 1);
+            }
+            assert!(seen.iter().all(|&count| count == 1), "{}x{} at {} levels", width, height, levels);
+        }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let (width, height) = (13, 9);
+        let image: Vec<Ypbpr> = (0..width * height)
+            .map(|i| Ypbpr { y: 0.3 + (0.002 * (i % width) as f32), pb: 0.05, pr: -0.1 + (0.001 * (i / width) as f32) })
+            .collect();
+        for filter in [WaveletFilter::Haar, WaveletFilter::Cdf53] {
+            let wavelet = Wavelet::new(filter, 3, 90, ChromaSampling::Yuv420).unwrap();
+            let decoded = wavelet.decode(&wavelet.encode(&image, width, height).unwrap(), width, height).unwrap();
+            for (before, after) in image.iter().zip(&decoded) {
+                assert!((before.y - after.y).abs() < 0.02, "{:?} became {:?}", before, after);
+                assert!((before.pb - after.pb).abs() < 0.02, "{:?} became {:?}", before, after);
+                assert!((before.pr - after.pr).abs() < 0.02, "{:?} became {:?}", before, after);
+            }
+
+            let rebuilt = Wavelet::from_parameters(&wavelet.parameters()).unwrap();
+            assert_eq!(rebuilt, wavelet);
+        }
+    }
+
+    #[test]
+    fn corrupt_coefficient_test() {
+        // A 2x2 image at one level has twelve one-coefficient subbands; give each a value far
+        // beyond anything the encoder can produce
+        for filter in [WaveletFilter::Haar, WaveletFilter::Cdf53] {
+            let wavelet = Wavelet::new(filter, 1, 90, ChromaSampling::Yuv444).unwrap();
+            let mut payload = Vec::new();
+            for _ in 0..12 {
+                let mut coded = BitWriter::new(Vec::new());
+                write_run_length(&[i64::MAX / 2], &mut coded).unwrap();
+                let coded = coded.into_inner().unwrap();
+                write_leb128(&mut BitWriter::new(&mut payload), coded.len() as u64).unwrap();
+                payload.extend_from_slice(&coded);
+            }
+            assert!(matches!(wavelet.decode(&payload, 2, 2), Err(RpegError::FieldOverflow { .. })), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn parameters_test() {
+        assert!(matches!(Wavelet::from_parameters(&[2, 3, 90, 2]), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(Wavelet::from_parameters(&[0, 0, 90, 2]), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(Wavelet::from_parameters(&[0, MAX_LEVELS + 1, 90, 2]), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(Wavelet::from_parameters(&[0, 3, 101, 2]), Err(RpegError::InvalidHeader(_))));
+        assert!(matches!(Wavelet::from_parameters(&[0, 3, 90]), Err(RpegError::Truncated)));
+
+        // The constructor refuses the same settings rather than quietly changing them
+        for (levels, quality) in [(0, 90), (MAX_LEVELS + 1, 90), (40, 90), (3, 0), (3, 101)] {
+            let wavelet = Wavelet::new(WaveletFilter::Haar, levels, quality, ChromaSampling::Yuv420);
+            assert!(matches!(wavelet, Err(RpegError::IncompatibleOptions(_))), "{} levels at quality {}", levels, quality);
+        }
+        assert!(matches!(Wavelet::by_name("cdf53", 40, 90, ChromaSampling::Yuv420), Err(RpegError::IncompatibleOptions(_))));
+        assert!(matches!(Wavelet::by_name("dct", 40, 90, ChromaSampling::Yuv420), Ok(None)));
+    }
+}